@@ -21,17 +21,17 @@ fn main() {
         SubCommand::Get { key } => {
             let obj = client.get(key).unwrap();
             let buf: Vec<u8> = obj.into();
-            print!("{}", String::from_utf8(buf).unwrap());
+            print!("{}", String::from_utf8_lossy(&buf));
         }
         SubCommand::Set { key, value } => {
             let obj = client.set(key, value).unwrap();
             let buf: Vec<u8> = obj.into();
-            print!("{}", String::from_utf8(buf).unwrap());
+            print!("{}", String::from_utf8_lossy(&buf));
         }
         SubCommand::Remove { key } => {
             let obj = client.remove(key).unwrap();
             let buf: Vec<u8> = obj.into();
-            print!("{}", String::from_utf8(buf).unwrap());
+            print!("{}", String::from_utf8_lossy(&buf));
         }
     };
 }
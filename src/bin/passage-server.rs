@@ -25,6 +25,15 @@ struct Opts {
 
     #[clap(short, long, default_value = "12345")]
     port: u32,
+
+    #[clap(long, default_value = "536870912")]
+    proto_max_bulk_len: usize,
+
+    #[clap(long, default_value = "33554432")]
+    pubsub_max_output_len: usize,
+
+    #[clap(long, default_value = "16")]
+    databases: usize,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -38,6 +47,8 @@ fn main() -> Result<(), Box<dyn Error>> {
         backlog: 128,
         port: opts.port,
         read_only: opts.read_only,
+        proto_max_bulk_len: opts.proto_max_bulk_len,
+        pubsub_max_output_len: opts.pubsub_max_output_len,
         only_v6: false,
         reuse_address: true,
         reuse_port: true,
@@ -45,7 +56,8 @@ fn main() -> Result<(), Box<dyn Error>> {
         cluster_password: opts.cluster_password,
         cluster_nodes: opts.cluster_nodes,
         cluster_connect_timeout: 1000,
+        databases: opts.databases,
     };
-    let wal = Arc::new(Wal::new(&opts.log_file, opts.fsync).unwrap());
-    Server::new(options, wal).run()
+    let wal = Arc::new(Wal::new(&opts.log_file, opts.fsync, opts.proto_max_bulk_len).unwrap());
+    Server::new(options, wal)?.run()
 }
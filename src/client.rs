@@ -1,11 +1,17 @@
+use crate::command::{
+    Command, End, KeyValue, ScanOptions, SetOp, SetOptions, XAddId, XClaimOptions, XGroup,
+    XPendingRange, XReadOptions, ZAddOptions, ZRangeOptions,
+};
 use crate::object;
-use crate::object::{parse, Object};
-use crate::server::MESSAGE_MAX_SIZE;
+use crate::object::{read_object, Object};
+use crate::server::PROTO_MAX_BULK_LEN;
+use crate::stream::StreamId;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use socket2::{Domain, Socket, Type};
 use std::error::Error;
 use std::io;
 use std::io::prelude::*;
-use std::io::Cursor;
 use std::net::SocketAddr;
 
 type Result<T> = std::result::Result<T, ClientError>;
@@ -41,52 +47,529 @@ impl From<object::Error> for ClientError {
 
 pub struct Client {
     conn: Socket,
+    buf: Vec<u8>,
+    proto_max_bulk_len: usize,
 }
 
 impl Client {
     pub fn new(addr: &str) -> Self {
+        Client::with_proto_max_bulk_len(addr, PROTO_MAX_BULK_LEN)
+    }
+
+    pub fn with_proto_max_bulk_len(addr: &str, proto_max_bulk_len: usize) -> Self {
         let addr: SocketAddr = addr.parse().unwrap();
         let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
         socket.connect(&addr.into()).unwrap();
-        Self { conn: socket }
+        Self {
+            conn: socket,
+            buf: Vec::new(),
+            proto_max_bulk_len,
+        }
     }
 
-    pub fn get(&mut self, key: String) -> Result<Object> {
-        let msg = format!("*2\r\n+get\r\n+{}\r\n", key);
-        self.conn.write(msg.as_bytes())?;
+    fn request(&mut self, cmd: Command) -> Result<Object> {
+        let buf: Vec<u8> = cmd.to_object().into();
+        self.conn.write_all(&buf)?;
+        self.read_reply()
+    }
 
-        let mut buf = [0; MESSAGE_MAX_SIZE];
-        let len = self.conn.read(&mut buf)?;
+    fn read_reply(&mut self) -> Result<Object> {
+        let obj = read_object(&mut self.conn, &mut self.buf, self.proto_max_bulk_len)?;
+        Ok(obj)
+    }
 
-        let mut cursor = Cursor::new(&buf[0..len]);
-        let obj = parse(&mut cursor)?;
+    /// Sends any command that serializes to a RESP array and deserializes the
+    /// reply, so that callers can derive the encoding of their own types.
+    pub fn call<C, R>(&mut self, cmd: &C) -> Result<R>
+    where
+        C: Serialize + ?Sized,
+        R: DeserializeOwned,
+    {
+        let buf = object::to_bytes(cmd)?;
+        self.conn.write_all(&buf)?;
 
-        Ok(obj)
+        let obj = self.read_reply()?;
+        Ok(object::from_object(&obj)?)
+    }
+
+    pub fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Object> {
+        self.request(Command::Get(key.into()))
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<Object> {
-        let msg = format!("*3\r\n+set\r\n+{}\r\n+{}\r\n", key, value);
-        self.conn.write(msg.as_bytes())?;
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<Object> {
+        self.set_with_options(key, value, SetOptions::default())
+    }
 
-        let mut buf = [0; MESSAGE_MAX_SIZE];
-        let len = self.conn.read(&mut buf)?;
+    pub fn set_with_options(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        options: SetOptions,
+    ) -> Result<Object> {
+        self.request(Command::Set(key.into(), value.into(), options))
+    }
 
-        let mut cursor = Cursor::new(&buf[0..len]);
-        let obj = parse(&mut cursor)?;
+    pub fn mget<K: Into<Vec<u8>>>(&mut self, keys: impl IntoIterator<Item = K>) -> Result<Object> {
+        self.request(Command::MGet(keys.into_iter().map(Into::into).collect()))
+    }
 
-        Ok(obj)
+    pub fn mset<K, V>(&mut self, pairs: impl IntoIterator<Item = (K, V)>) -> Result<Object>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.request(Command::MSet(into_pairs(pairs)))
     }
 
-    pub fn remove(&mut self, key: String) -> Result<Object> {
-        let msg = format!("*2\r\n+remove\r\n+{}\r\n", key);
-        self.conn.write(msg.as_bytes())?;
+    pub fn msetnx<K, V>(&mut self, pairs: impl IntoIterator<Item = (K, V)>) -> Result<Object>
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.request(Command::MSetNx(into_pairs(pairs)))
+    }
 
-        let mut buf = [0; MESSAGE_MAX_SIZE];
-        let len = self.conn.read(&mut buf)?;
+    pub fn incr(&mut self, key: impl Into<Vec<u8>>) -> Result<Object> {
+        self.incrby(key, 1)
+    }
 
-        let mut cursor = Cursor::new(&buf[0..len]);
-        let obj = parse(&mut cursor)?;
+    pub fn decr(&mut self, key: impl Into<Vec<u8>>) -> Result<Object> {
+        self.incrby(key, -1)
+    }
 
-        Ok(obj)
+    pub fn incrby(&mut self, key: impl Into<Vec<u8>>, increment: i64) -> Result<Object> {
+        self.request(Command::IncrBy(key.into(), increment))
+    }
+
+    pub fn decrby(&mut self, key: impl Into<Vec<u8>>, decrement: i64) -> Result<Object> {
+        match decrement.checked_neg() {
+            Some(increment) => self.incrby(key, increment),
+            None => Ok(Object::Error("ERR decrement would overflow".to_string())),
+        }
+    }
+
+    pub fn incrbyfloat(&mut self, key: impl Into<Vec<u8>>, increment: f64) -> Result<Object> {
+        self.request(Command::IncrByFloat(key.into(), increment))
+    }
+
+    pub fn exists<K: Into<Vec<u8>>>(
+        &mut self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<Object> {
+        self.request(Command::Exists(keys.into_iter().map(Into::into).collect()))
+    }
+
+    pub fn del<K: Into<Vec<u8>>>(&mut self, keys: impl IntoIterator<Item = K>) -> Result<Object> {
+        self.request(Command::Del(keys.into_iter().map(Into::into).collect()))
+    }
+
+    pub fn key_type(&mut self, key: impl Into<Vec<u8>>) -> Result<Object> {
+        self.request(Command::Type(key.into()))
+    }
+
+    pub fn rename(
+        &mut self,
+        source: impl Into<Vec<u8>>,
+        destination: impl Into<Vec<u8>>,
+    ) -> Result<Object> {
+        self.request(Command::Rename(source.into(), destination.into()))
+    }
+
+    pub fn renamenx(
+        &mut self,
+        source: impl Into<Vec<u8>>,
+        destination: impl Into<Vec<u8>>,
+    ) -> Result<Object> {
+        self.request(Command::RenameNx(source.into(), destination.into()))
+    }
+
+    pub fn copy(
+        &mut self,
+        source: impl Into<Vec<u8>>,
+        destination: impl Into<Vec<u8>>,
+        replace: bool,
+    ) -> Result<Object> {
+        self.request(Command::Copy(source.into(), destination.into(), replace))
+    }
+
+    pub fn dbsize(&mut self) -> Result<Object> {
+        self.request(Command::DbSize)
+    }
+
+    pub fn flushall(&mut self) -> Result<Object> {
+        self.request(Command::FlushAll)
+    }
+
+    pub fn flushdb(&mut self) -> Result<Object> {
+        self.request(Command::FlushDb)
+    }
+
+    pub fn select(&mut self, index: usize) -> Result<Object> {
+        self.request(Command::Select(index))
+    }
+
+    pub fn swapdb(&mut self, first: usize, second: usize) -> Result<Object> {
+        self.request(Command::SwapDb(first, second))
+    }
+
+    pub fn lpush<V: Into<Vec<u8>>>(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        values: impl IntoIterator<Item = V>,
+    ) -> Result<Object> {
+        let values = values.into_iter().map(Into::into).collect();
+        self.request(Command::Push(End::Left, key.into(), values))
+    }
+
+    pub fn rpush<V: Into<Vec<u8>>>(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        values: impl IntoIterator<Item = V>,
+    ) -> Result<Object> {
+        let values = values.into_iter().map(Into::into).collect();
+        self.request(Command::Push(End::Right, key.into(), values))
+    }
+
+    pub fn lpop(&mut self, key: impl Into<Vec<u8>>, count: Option<usize>) -> Result<Object> {
+        self.request(Command::Pop(End::Left, key.into(), count))
+    }
+
+    pub fn rpop(&mut self, key: impl Into<Vec<u8>>, count: Option<usize>) -> Result<Object> {
+        self.request(Command::Pop(End::Right, key.into(), count))
+    }
+
+    pub fn lrange(&mut self, key: impl Into<Vec<u8>>, start: i64, stop: i64) -> Result<Object> {
+        self.request(Command::LRange(key.into(), start, stop))
+    }
+
+    pub fn llen(&mut self, key: impl Into<Vec<u8>>) -> Result<Object> {
+        self.request(Command::LLen(key.into()))
+    }
+
+    pub fn ltrim(&mut self, key: impl Into<Vec<u8>>, start: i64, stop: i64) -> Result<Object> {
+        self.request(Command::LTrim(key.into(), start, stop))
+    }
+
+    pub fn lmove(
+        &mut self,
+        source: impl Into<Vec<u8>>,
+        destination: impl Into<Vec<u8>>,
+        from: End,
+        to: End,
+    ) -> Result<Object> {
+        self.request(Command::LMove(source.into(), destination.into(), from, to))
+    }
+
+    /// Pops from the first non-empty list, waiting up to `timeout` seconds for
+    /// one of the keys to receive a value. A timeout of 0 waits forever.
+    pub fn blpop<K: Into<Vec<u8>>>(
+        &mut self,
+        keys: impl IntoIterator<Item = K>,
+        timeout: f64,
+    ) -> Result<Object> {
+        let keys = keys.into_iter().map(Into::into).collect();
+        self.request(Command::BPop(End::Left, keys, timeout))
+    }
+
+    pub fn brpop<K: Into<Vec<u8>>>(
+        &mut self,
+        keys: impl IntoIterator<Item = K>,
+        timeout: f64,
+    ) -> Result<Object> {
+        let keys = keys.into_iter().map(Into::into).collect();
+        self.request(Command::BPop(End::Right, keys, timeout))
+    }
+
+    pub fn blmove(
+        &mut self,
+        source: impl Into<Vec<u8>>,
+        destination: impl Into<Vec<u8>>,
+        from: End,
+        to: End,
+        timeout: f64,
+    ) -> Result<Object> {
+        let cmd = Command::BLMove(source.into(), destination.into(), from, to, timeout);
+        self.request(cmd)
+    }
+
+    pub fn hset<F, V>(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        pairs: impl IntoIterator<Item = (F, V)>,
+    ) -> Result<Object>
+    where
+        F: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.request(Command::HSet(key.into(), into_pairs(pairs)))
+    }
+
+    pub fn hget(&mut self, key: impl Into<Vec<u8>>, field: impl Into<Vec<u8>>) -> Result<Object> {
+        self.request(Command::HGet(key.into(), field.into()))
     }
+
+    pub fn hmget<F: Into<Vec<u8>>>(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        fields: impl IntoIterator<Item = F>,
+    ) -> Result<Object> {
+        let fields = fields.into_iter().map(Into::into).collect();
+        self.request(Command::HMGet(key.into(), fields))
+    }
+
+    pub fn hdel<F: Into<Vec<u8>>>(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        fields: impl IntoIterator<Item = F>,
+    ) -> Result<Object> {
+        let fields = fields.into_iter().map(Into::into).collect();
+        self.request(Command::HDel(key.into(), fields))
+    }
+
+    pub fn hgetall(&mut self, key: impl Into<Vec<u8>>) -> Result<Object> {
+        self.request(Command::HGetAll(key.into()))
+    }
+
+    pub fn hincrby(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        field: impl Into<Vec<u8>>,
+        increment: i64,
+    ) -> Result<Object> {
+        self.request(Command::HIncrBy(key.into(), field.into(), increment))
+    }
+
+    pub fn hscan(&mut self, key: impl Into<Vec<u8>>, options: ScanOptions) -> Result<Object> {
+        self.request(Command::HScan(key.into(), options))
+    }
+
+    pub fn sadd<M: Into<Vec<u8>>>(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        members: impl IntoIterator<Item = M>,
+    ) -> Result<Object> {
+        let members = members.into_iter().map(Into::into).collect();
+        self.request(Command::SAdd(key.into(), members))
+    }
+
+    pub fn srem<M: Into<Vec<u8>>>(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        members: impl IntoIterator<Item = M>,
+    ) -> Result<Object> {
+        let members = members.into_iter().map(Into::into).collect();
+        self.request(Command::SRem(key.into(), members))
+    }
+
+    pub fn sismember(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        member: impl Into<Vec<u8>>,
+    ) -> Result<Object> {
+        self.request(Command::SIsMember(key.into(), member.into()))
+    }
+
+    pub fn smembers(&mut self, key: impl Into<Vec<u8>>) -> Result<Object> {
+        self.request(Command::SMembers(key.into()))
+    }
+
+    /// Sends `SINTER`, `SUNION` or `SDIFF`, depending on `op`.
+    pub fn set_op<K: Into<Vec<u8>>>(
+        &mut self,
+        op: SetOp,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<Object> {
+        self.request(Command::SetOp(
+            op,
+            keys.into_iter().map(Into::into).collect(),
+        ))
+    }
+
+    /// Sends `SINTERSTORE`, `SUNIONSTORE` or `SDIFFSTORE`, depending on `op`.
+    pub fn set_op_store<K: Into<Vec<u8>>>(
+        &mut self,
+        op: SetOp,
+        destination: impl Into<Vec<u8>>,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<Object> {
+        let keys = keys.into_iter().map(Into::into).collect();
+        self.request(Command::SetOpStore(op, destination.into(), keys))
+    }
+
+    pub fn zadd<M: Into<Vec<u8>>>(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        options: ZAddOptions,
+        members: impl IntoIterator<Item = (f64, M)>,
+    ) -> Result<Object> {
+        let members = members
+            .into_iter()
+            .map(|(score, member)| (score, member.into()))
+            .collect();
+        self.request(Command::ZAdd(key.into(), options, members))
+    }
+
+    pub fn zscore(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        member: impl Into<Vec<u8>>,
+    ) -> Result<Object> {
+        self.request(Command::ZScore(key.into(), member.into()))
+    }
+
+    pub fn zrank(&mut self, key: impl Into<Vec<u8>>, member: impl Into<Vec<u8>>) -> Result<Object> {
+        self.request(Command::ZRank(key.into(), member.into()))
+    }
+
+    pub fn zrange(&mut self, key: impl Into<Vec<u8>>, options: ZRangeOptions) -> Result<Object> {
+        self.request(Command::ZRange(key.into(), options))
+    }
+
+    pub fn zrem<M: Into<Vec<u8>>>(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        members: impl IntoIterator<Item = M>,
+    ) -> Result<Object> {
+        let members = members.into_iter().map(Into::into).collect();
+        self.request(Command::ZRem(key.into(), members))
+    }
+
+    pub fn zpopmin(&mut self, key: impl Into<Vec<u8>>, count: Option<usize>) -> Result<Object> {
+        self.request(Command::ZPopMin(key.into(), count))
+    }
+
+    pub fn xadd<F, V>(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        id: XAddId,
+        pairs: impl IntoIterator<Item = (F, V)>,
+    ) -> Result<Object>
+    where
+        F: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        self.request(Command::XAdd(key.into(), id, into_pairs(pairs)))
+    }
+
+    pub fn xrange(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+    ) -> Result<Object> {
+        self.request(Command::XRange(key.into(), start, end, count))
+    }
+
+    pub fn xread(&mut self, options: XReadOptions) -> Result<Object> {
+        self.request(Command::XRead(options))
+    }
+
+    pub fn xreadgroup(
+        &mut self,
+        group: impl Into<Vec<u8>>,
+        consumer: impl Into<Vec<u8>>,
+        options: XReadOptions,
+    ) -> Result<Object> {
+        self.request(Command::XReadGroup(group.into(), consumer.into(), options))
+    }
+
+    pub fn xgroup(&mut self, xgroup: XGroup) -> Result<Object> {
+        self.request(Command::XGroup(xgroup))
+    }
+
+    pub fn xack(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        group: impl Into<Vec<u8>>,
+        ids: impl IntoIterator<Item = StreamId>,
+    ) -> Result<Object> {
+        let ids = ids.into_iter().collect();
+        self.request(Command::XAck(key.into(), group.into(), ids))
+    }
+
+    pub fn xpending(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        group: impl Into<Vec<u8>>,
+        range: Option<XPendingRange>,
+    ) -> Result<Object> {
+        self.request(Command::XPending(key.into(), group.into(), range))
+    }
+
+    pub fn xclaim(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        group: impl Into<Vec<u8>>,
+        consumer: impl Into<Vec<u8>>,
+        min_idle: i64,
+        ids: impl IntoIterator<Item = StreamId>,
+        options: XClaimOptions,
+    ) -> Result<Object> {
+        let ids = ids.into_iter().collect();
+        let cmd = Command::XClaim(
+            key.into(),
+            group.into(),
+            consumer.into(),
+            min_idle,
+            ids,
+            options,
+        );
+        self.request(cmd)
+    }
+
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<Object> {
+        self.request(Command::Remove(key.into()))
+    }
+
+    pub fn getv(&mut self, key: impl Into<Vec<u8>>) -> Result<Object> {
+        self.request(Command::GetV(key.into()))
+    }
+
+    /// Sets a key if it still has the version `getv` gave, or if it does not
+    /// exist and the version is 0.
+    pub fn cas(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        version: u64,
+        value: impl Into<Vec<u8>>,
+    ) -> Result<Object> {
+        self.request(Command::Cas(key.into(), version, value.into()))
+    }
+
+    pub fn cad(&mut self, key: impl Into<Vec<u8>>, version: u64) -> Result<Object> {
+        self.request(Command::Cad(key.into(), version))
+    }
+
+    /// Starts a transaction. Requests reply with `QUEUED` until `exec`.
+    pub fn multi(&mut self) -> Result<Object> {
+        self.request(Command::Multi)
+    }
+
+    pub fn exec(&mut self) -> Result<Object> {
+        self.request(Command::Exec)
+    }
+
+    pub fn discard(&mut self) -> Result<Object> {
+        self.request(Command::Discard)
+    }
+
+    pub fn watch<K: Into<Vec<u8>>>(&mut self, keys: impl IntoIterator<Item = K>) -> Result<Object> {
+        self.request(Command::Watch(keys.into_iter().map(Into::into).collect()))
+    }
+
+    pub fn unwatch(&mut self) -> Result<Object> {
+        self.request(Command::Unwatch)
+    }
+}
+
+fn into_pairs<K, V>(pairs: impl IntoIterator<Item = (K, V)>) -> Vec<KeyValue>
+where
+    K: Into<Vec<u8>>,
+    V: Into<Vec<u8>>,
+{
+    pairs
+        .into_iter()
+        .map(|(key, value)| (key.into(), value.into()))
+        .collect()
 }
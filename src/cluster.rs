@@ -1,6 +1,6 @@
 use crate::command::NetCommand;
-use crate::object::Object;
-use crate::server::{ServerOptions, MESSAGE_MAX_SIZE};
+use crate::object::{read_object, Object};
+use crate::server::ServerOptions;
 use log::{debug, error, trace};
 use socket2::{Domain, Socket, Type};
 use std::error::Error;
@@ -11,7 +11,7 @@ use std::time::Duration;
 
 // TODO: Make nodes asynchronous
 pub struct Cluster {
-    _opt: ServerOptions,
+    opt: ServerOptions,
     nodes: Vec<Socket>,
 }
 
@@ -31,27 +31,34 @@ impl Cluster {
                 let cmd = NetCommand::Leader(opt.cluster_password.clone());
                 let obj: Object = cmd.into();
                 let buf: Vec<u8> = obj.into();
-                node_socket.write(&buf)?;
+                node_socket.write_all(&buf)?;
 
                 nodes.push(node_socket);
             }
         }
         trace!("Connected to all cluster nodes");
-        Ok(Cluster { _opt: opt, nodes })
+        Ok(Cluster { opt, nodes })
     }
 
-    pub fn relay(&mut self, out_buf: &[u8]) {
-        let mut in_buf = [0u8; MESSAGE_MAX_SIZE];
+    /// Sends encoded commands to every node and reads as many replies from
+    /// each as there are commands.
+    pub fn relay(&mut self, out_buf: &[u8], replies: usize) {
+        let mut in_buf = Vec::new();
         for node in self.nodes.iter_mut() {
             match node.write_all(out_buf) {
                 Ok(_) => {
                     // TODO: Error handling
-                    let size = node.read(&mut in_buf).unwrap();
-                    if size == 0 {
-                        panic!("Cluster node response was size 0");
+                    in_buf.clear();
+                    for _ in 0..replies {
+                        if let Err(err) =
+                            read_object(node, &mut in_buf, self.opt.proto_max_bulk_len)
+                        {
+                            panic!("Cluster node response could not be read: {}", err);
+                        }
                     }
                 }
                 Err(error) => {
+                    #[allow(clippy::match_single_binding)]
                     match error.kind() {
                         // TODO: Reconnect
                         // io::ErrorKind::ConnectionRefused => todo!(),
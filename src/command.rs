@@ -1,19 +1,1895 @@
-use crate::object::Object;
+use crate::object::{self, format_double, Object, ObjectRef};
+use crate::stream::StreamId;
+use serde::{Deserialize, Serialize};
 use std::convert::TryFrom;
 
-#[derive(Debug)]
+/// A key and the value to store under it.
+pub type KeyValue = (Vec<u8>, Vec<u8>);
+
+#[derive(Debug, Clone)]
 pub enum Command {
-    Get(String),
-    Set(String, String),
-    Remove(String),
+    Get(Vec<u8>),
+    Set(Vec<u8>, Vec<u8>, SetOptions),
+    Remove(Vec<u8>),
+    /// Gets a string with its version.
+    GetV(Vec<u8>),
+    /// Sets a string if the key's version is the given one, where 0 means
+    /// that the key does not exist.
+    Cas(Vec<u8>, u64, Vec<u8>),
+    /// Deletes a key if its version is the given one.
+    Cad(Vec<u8>, u64),
+    MGet(Vec<Vec<u8>>),
+    MSet(Vec<KeyValue>),
+    MSetNx(Vec<KeyValue>),
+    IncrBy(Vec<u8>, i64),
+    IncrByFloat(Vec<u8>, f64),
+    Expire(Vec<u8>, Expiry),
+    Ttl(Vec<u8>),
+    PTtl(Vec<u8>),
+    Persist(Vec<u8>),
+    Exists(Vec<Vec<u8>>),
+    Del(Vec<Vec<u8>>),
+    Type(Vec<u8>),
+    Rename(Vec<u8>, Vec<u8>),
+    RenameNx(Vec<u8>, Vec<u8>),
+    Copy(Vec<u8>, Vec<u8>, bool),
+    DbSize,
+    /// Empties every database.
+    FlushAll,
+    /// Empties the selected database.
+    FlushDb,
+    /// Selects the database that the connection's commands apply to.
+    Select(usize),
+    SwapDb(usize, usize),
+    Scan(ScanOptions),
+    Keys(Vec<u8>),
+    Push(End, Vec<u8>, Vec<Vec<u8>>),
+    Pop(End, Vec<u8>, Option<usize>),
+    LRange(Vec<u8>, i64, i64),
+    LLen(Vec<u8>),
+    LTrim(Vec<u8>, i64, i64),
+    LMove(Vec<u8>, Vec<u8>, End, End),
+    /// Pops from the first non-empty list, waiting up to a timeout in seconds,
+    /// where 0 waits forever.
+    BPop(End, Vec<Vec<u8>>, f64),
+    BLMove(Vec<u8>, Vec<u8>, End, End, f64),
+    HSet(Vec<u8>, Vec<KeyValue>),
+    HGet(Vec<u8>, Vec<u8>),
+    HMGet(Vec<u8>, Vec<Vec<u8>>),
+    HDel(Vec<u8>, Vec<Vec<u8>>),
+    HGetAll(Vec<u8>),
+    HIncrBy(Vec<u8>, Vec<u8>, i64),
+    HScan(Vec<u8>, ScanOptions),
+    SAdd(Vec<u8>, Vec<Vec<u8>>),
+    SRem(Vec<u8>, Vec<Vec<u8>>),
+    SIsMember(Vec<u8>, Vec<u8>),
+    SMembers(Vec<u8>),
+    SetOp(SetOp, Vec<Vec<u8>>),
+    /// Stores the result of a set operation on the keys in a destination key.
+    SetOpStore(SetOp, Vec<u8>, Vec<Vec<u8>>),
+    ZAdd(Vec<u8>, ZAddOptions, Vec<(f64, Vec<u8>)>),
+    ZScore(Vec<u8>, Vec<u8>),
+    ZRank(Vec<u8>, Vec<u8>),
+    ZRange(Vec<u8>, ZRangeOptions),
+    ZRem(Vec<u8>, Vec<Vec<u8>>),
+    ZPopMin(Vec<u8>, Option<usize>),
+    XAdd(Vec<u8>, XAddId, Vec<KeyValue>),
+    /// Reads the entries between two IDs, both included, up to a count.
+    XRange(Vec<u8>, StreamId, StreamId, Option<usize>),
+    XRead(XReadOptions),
+    /// Reads streams as a consumer of a group: the group and consumer names,
+    /// then the same options as `XREAD`.
+    XReadGroup(Vec<u8>, Vec<u8>, XReadOptions),
+    XGroup(XGroup),
+    XAck(Vec<u8>, Vec<u8>, Vec<StreamId>),
+    XPending(Vec<u8>, Vec<u8>, Option<XPendingRange>),
+    /// Gives pending entries to a consumer: the key, group and consumer, the
+    /// minimum idle time in milliseconds and the IDs of the entries.
+    XClaim(Vec<u8>, Vec<u8>, Vec<u8>, i64, Vec<StreamId>, XClaimOptions),
+    Hello(Option<i64>),
+    Command(Introspection),
+    Ping(Option<Vec<u8>>),
+    Echo(Vec<u8>),
+    Subscribe(Vec<Vec<u8>>),
+    /// Unsubscribes from the channels, or from all of them if there are none.
+    Unsubscribe(Vec<Vec<u8>>),
+    PSubscribe(Vec<Vec<u8>>),
+    PUnsubscribe(Vec<Vec<u8>>),
+    Publish(Vec<u8>, Vec<u8>),
+    /// Starts queueing commands for `EXEC` to run as a transaction.
+    Multi,
+    Exec,
+    Discard,
+    /// Makes the next `EXEC` fail if one of the keys changes before it.
+    Watch(Vec<Vec<u8>>),
+    Unwatch,
+}
+
+/// The modifiers of `SET`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SetOptions {
+    pub condition: Option<Condition>,
+    pub get: bool,
+    pub expiry: Option<Expiry>,
+}
+
+/// Whether a write requires the key to be absent (`NX`) or present (`XX`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Nx,
+    Xx,
+}
+
+/// When a key expires. Relative times are validated to fit in milliseconds
+/// when parsed. `EXPIRE` and its variants take the same forms, except for
+/// `KeepTtl`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    Ex(i64),
+    Px(i64),
+    ExAt(i64),
+    PxAt(i64),
+    KeepTtl,
+}
+
+impl Expiry {
+    /// The unix time in milliseconds at which the key expires, or `None` for
+    /// `KEEPTTL` and deadlines that do not fit in an `i64`.
+    pub fn deadline(self, now: i64) -> Option<i64> {
+        match self {
+            Expiry::Ex(seconds) => now.checked_add(seconds.checked_mul(1000)?),
+            Expiry::Px(millis) => now.checked_add(millis),
+            Expiry::ExAt(seconds) => seconds.checked_mul(1000),
+            Expiry::PxAt(millis) => Some(millis),
+            Expiry::KeepTtl => None,
+        }
+    }
+
+    fn to_args(self) -> Vec<Vec<u8>> {
+        let (name, time): (&[u8], _) = match self {
+            Expiry::Ex(seconds) => (b"EX", seconds),
+            Expiry::Px(millis) => (b"PX", millis),
+            Expiry::ExAt(seconds) => (b"EXAT", seconds),
+            Expiry::PxAt(millis) => (b"PXAT", millis),
+            Expiry::KeepTtl => return vec![b"KEEPTTL".to_vec()],
+        };
+        vec![name.to_vec(), time.to_string().into_bytes()]
+    }
+}
+
+/// The arguments of `SCAN` and the commands that scan a single value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanOptions {
+    pub cursor: u64,
+    pub pattern: Option<Vec<u8>>,
+    pub count: usize,
+}
+
+impl ScanOptions {
+    fn to_args(&self) -> Vec<Vec<u8>> {
+        let mut args = vec![self.cursor.to_string().into_bytes()];
+        if let Some(pattern) = &self.pattern {
+            args.extend([b"MATCH".to_vec(), pattern.clone()]);
+        }
+        args.extend([b"COUNT".to_vec(), self.count.to_string().into_bytes()]);
+        args
+    }
+}
+
+/// Which end of a list a command works on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    Left,
+    Right,
+}
+
+impl End {
+    fn name(self) -> &'static [u8] {
+        match self {
+            End::Left => b"LEFT",
+            End::Right => b"RIGHT",
+        }
+    }
+}
+
+/// How `SINTER`, `SUNION`, `SDIFF` and their `STORE` variants combine sets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+/// The modifiers of `ZADD`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ZAddOptions {
+    pub condition: Option<Condition>,
+    pub comparison: Option<Comparison>,
+    /// Whether to reply with the number of changed members, not just the
+    /// number of added ones.
+    pub ch: bool,
+    pub incr: bool,
+}
+
+/// Whether `ZADD` only updates scores that it increases (`GT`) or decreases
+/// (`LT`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Gt,
+    Lt,
+}
+
+/// The arguments of `ZRANGE`, which `ZRANGEBYSCORE` is a special case of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZRangeOptions {
+    pub by: ZRangeBy,
+    pub rev: bool,
+    /// The offset and count of `LIMIT`, where a negative count means all.
+    pub limit: Option<(i64, i64)>,
+    pub with_scores: bool,
+}
+
+/// What a `ZRANGE` is over. Score and lex ranges are kept as minimum and
+/// maximum, even though `REV` takes them the other way around.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZRangeBy {
+    Index(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+impl ZRangeOptions {
+    fn to_args(&self) -> Vec<Vec<u8>> {
+        let (min, max, by): (_, _, &[u8]) = match &self.by {
+            ZRangeBy::Index(start, stop) => (
+                start.to_string().into_bytes(),
+                stop.to_string().into_bytes(),
+                b"",
+            ),
+            ZRangeBy::Score(min, max) => (min.to_arg(), max.to_arg(), b"BYSCORE"),
+            ZRangeBy::Lex(min, max) => (min.to_arg(), max.to_arg(), b"BYLEX"),
+        };
+        let mut args = match self.rev && !by.is_empty() {
+            true => vec![max, min],
+            false => vec![min, max],
+        };
+        if !by.is_empty() {
+            args.push(by.to_vec());
+        }
+        if self.rev {
+            args.push(b"REV".to_vec());
+        }
+        if let Some((offset, count)) = self.limit {
+            args.extend([
+                b"LIMIT".to_vec(),
+                offset.to_string().into_bytes(),
+                count.to_string().into_bytes(),
+            ]);
+        }
+        if self.with_scores {
+            args.push(b"WITHSCORES".to_vec());
+        }
+        args
+    }
+}
+
+/// A score range bound, written as `(score` to exclude the score itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreBound {
+    pub score: f64,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    /// Whether `score` is below the range that this bound is the minimum of.
+    pub fn below_min(self, score: f64) -> bool {
+        score < self.score || (self.exclusive && score == self.score)
+    }
+
+    /// Whether `score` is above the range that this bound is the maximum of.
+    pub fn above_max(self, score: f64) -> bool {
+        score > self.score || (self.exclusive && score == self.score)
+    }
+
+    fn to_arg(self) -> Vec<u8> {
+        let score = format_double(self.score);
+        match self.exclusive {
+            true => format!("({}", score).into_bytes(),
+            false => score.into_bytes(),
+        }
+    }
+}
+
+/// A lexicographical range bound: `-` and `+` for the lowest and highest
+/// member, `[member` to include and `(member` to exclude a member.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexBound {
+    Lowest,
+    Highest,
+    Inclusive(Vec<u8>),
+    Exclusive(Vec<u8>),
+}
+
+impl LexBound {
+    /// Whether `member` is below the range that this bound is the minimum of.
+    pub fn below_min(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Lowest => false,
+            LexBound::Highest => true,
+            LexBound::Inclusive(bound) => member < bound.as_slice(),
+            LexBound::Exclusive(bound) => member <= bound.as_slice(),
+        }
+    }
+
+    /// Whether `member` is above the range that this bound is the maximum of.
+    pub fn above_max(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Lowest => true,
+            LexBound::Highest => false,
+            LexBound::Inclusive(bound) => member > bound.as_slice(),
+            LexBound::Exclusive(bound) => member >= bound.as_slice(),
+        }
+    }
+
+    fn to_arg(&self) -> Vec<u8> {
+        match self {
+            LexBound::Lowest => b"-".to_vec(),
+            LexBound::Highest => b"+".to_vec(),
+            LexBound::Inclusive(member) => [b"[", &member[..]].concat(),
+            LexBound::Exclusive(member) => [b"(", &member[..]].concat(),
+        }
+    }
+}
+
+/// The ID `XADD` gives a new entry: `*` to generate one from the time, `ms-*`
+/// for the next sequence number in a given millisecond, or an explicit one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XAddId {
+    Auto,
+    Seq(u64),
+    Explicit(StreamId),
+}
+
+impl XAddId {
+    fn to_arg(self) -> Vec<u8> {
+        match self {
+            XAddId::Auto => b"*".to_vec(),
+            XAddId::Seq(ms) => format!("{}-*", ms).into_bytes(),
+            XAddId::Explicit(id) => id.to_bytes(),
+        }
+    }
+}
+
+/// Where `XREAD` and `XREADGROUP` start reading a stream, and where `XGROUP`
+/// starts a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamStart {
+    After(StreamId),
+    /// After the last entry of the stream, written as `$`.
+    Latest,
+    /// After the last entry delivered to the group, written as `>`.
+    Undelivered,
+}
+
+impl StreamStart {
+    fn to_arg(self) -> Vec<u8> {
+        match self {
+            StreamStart::After(id) => id.to_bytes(),
+            StreamStart::Latest => b"$".to_vec(),
+            StreamStart::Undelivered => b">".to_vec(),
+        }
+    }
+}
+
+/// The arguments of `XREAD`, which `XREADGROUP` shares.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct XReadOptions {
+    pub count: Option<usize>,
+    /// How long to wait for entries in milliseconds, where 0 waits forever.
+    pub block: Option<u64>,
+    /// Whether `XREADGROUP` leaves the entries it delivers out of the pending
+    /// entries.
+    pub noack: bool,
+    pub streams: Vec<(Vec<u8>, StreamStart)>,
+}
+
+impl XReadOptions {
+    fn to_args(&self) -> Vec<Vec<u8>> {
+        let mut args = Vec::new();
+        if let Some(count) = self.count {
+            args.extend([b"COUNT".to_vec(), count.to_string().into_bytes()]);
+        }
+        if let Some(block) = self.block {
+            args.extend([b"BLOCK".to_vec(), block.to_string().into_bytes()]);
+        }
+        if self.noack {
+            args.push(b"NOACK".to_vec());
+        }
+        args.push(b"STREAMS".to_vec());
+        args.extend(self.streams.iter().map(|(key, _)| key.clone()));
+        args.extend(self.streams.iter().map(|(_, start)| start.to_arg()));
+        args
+    }
+}
+
+/// The subcommands of `XGROUP`, which take the key and the group name first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum XGroup {
+    /// Creates a group, and the stream too if the flag (`MKSTREAM`) is set.
+    Create(Vec<u8>, Vec<u8>, StreamStart, bool),
+    SetId(Vec<u8>, Vec<u8>, StreamStart),
+    Destroy(Vec<u8>, Vec<u8>),
+}
+
+/// The range that the extended form of `XPENDING` lists pending entries in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XPendingRange {
+    /// The minimum idle time in milliseconds.
+    pub idle: Option<i64>,
+    pub start: StreamId,
+    pub end: StreamId,
+    pub count: usize,
+    pub consumer: Option<Vec<u8>>,
+}
+
+/// The modifiers of `XCLAIM`. Times are in milliseconds.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct XClaimOptions {
+    pub idle: Option<i64>,
+    /// The unix time to record as the delivery time, instead of now.
+    pub time: Option<i64>,
+    pub retry_count: Option<u64>,
+    /// Whether to claim entries that are not pending, as long as they exist.
+    pub force: bool,
+    /// Whether to reply with IDs only, without counting a delivery.
+    pub justid: bool,
+    /// Moves the last delivered ID of the group forward to this one.
+    pub last_id: Option<StreamId>,
+}
+
+impl XClaimOptions {
+    fn to_args(&self) -> Vec<Vec<u8>> {
+        let mut args = Vec::new();
+        let mut integer = |name: &[u8], value: Option<String>| {
+            if let Some(value) = value {
+                args.extend([name.to_vec(), value.into_bytes()]);
+            }
+        };
+        integer(b"IDLE", self.idle.map(|idle| idle.to_string()));
+        integer(b"TIME", self.time.map(|time| time.to_string()));
+        integer(b"RETRYCOUNT", self.retry_count.map(|n| n.to_string()));
+        integer(b"LASTID", self.last_id.map(|id| id.to_string()));
+        if self.force {
+            args.push(b"FORCE".to_vec());
+        }
+        if self.justid {
+            args.push(b"JUSTID".to_vec());
+        }
+        args
+    }
+}
+
+/// The subcommands of `COMMAND`.
+#[derive(Debug, Clone)]
+pub enum Introspection {
+    All,
+    Count,
+    Docs,
+    Info(Vec<Vec<u8>>),
+    List,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Write,
+    ReadOnly,
+    Admin,
+    Blocking,
+    PubSub,
+}
+
+impl Flag {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Flag::Write => "write",
+            Flag::ReadOnly => "readonly",
+            Flag::Admin => "admin",
+            Flag::Blocking => "blocking",
+            Flag::PubSub => "pubsub",
+        }
+    }
+}
+
+/// Describes a command the way `COMMAND INFO` reports it.
+///
+/// The arity counts the command name itself, and a negative arity means "at
+/// least that many". Key positions are argument indexes, where a negative
+/// `last_key` counts from the end. Commands without keys have a `first_key` of
+/// zero.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i64,
+    pub flags: &'static [Flag],
+    pub first_key: i64,
+    pub last_key: i64,
+    pub step: i64,
+    pub parse: fn(&[ObjectRef]) -> Result<Command, String>,
+}
+
+/// Every command the server supports, sorted by name so that `lookup` can
+/// binary search it.
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "blmove",
+        arity: 6,
+        flags: &[Flag::Write, Flag::Blocking],
+        first_key: 1,
+        last_key: 2,
+        step: 1,
+        parse: |args| {
+            Ok(Command::BLMove(
+                get_bytes(&args[0])?,
+                get_bytes(&args[1])?,
+                get_end(&args[2])?,
+                get_end(&args[3])?,
+                get_timeout(&args[4])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "blpop",
+        arity: -3,
+        flags: &[Flag::Write, Flag::Blocking],
+        first_key: 1,
+        last_key: -2,
+        step: 1,
+        parse: |args| parse_bpop(args, End::Left),
+    },
+    CommandSpec {
+        name: "brpop",
+        arity: -3,
+        flags: &[Flag::Write, Flag::Blocking],
+        first_key: 1,
+        last_key: -2,
+        step: 1,
+        parse: |args| parse_bpop(args, End::Right),
+    },
+    CommandSpec {
+        name: "cad",
+        arity: 3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::Cad(get_bytes(&args[0])?, get_version(&args[1])?)),
+    },
+    CommandSpec {
+        name: "cas",
+        arity: 4,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::Cas(
+                get_bytes(&args[0])?,
+                get_version(&args[1])?,
+                get_bytes(&args[2])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "command",
+        arity: -1,
+        flags: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: parse_command,
+    },
+    CommandSpec {
+        name: "copy",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 2,
+        step: 1,
+        parse: parse_copy,
+    },
+    CommandSpec {
+        name: "dbsize",
+        arity: 1,
+        flags: &[Flag::ReadOnly],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |_| Ok(Command::DbSize),
+    },
+    CommandSpec {
+        name: "decr",
+        arity: 2,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::IncrBy(get_bytes(&args[0])?, -1)),
+    },
+    CommandSpec {
+        name: "decrby",
+        arity: 3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            let decrement = get_integer(&args[1])?
+                .checked_neg()
+                .ok_or_else(|| "decrement would overflow".to_string())?;
+            Ok(Command::IncrBy(get_bytes(&args[0])?, decrement))
+        },
+    },
+    CommandSpec {
+        name: "del",
+        arity: -2,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        parse: |args| Ok(Command::Del(get_bytes_vec(args)?)),
+    },
+    CommandSpec {
+        name: "discard",
+        arity: 1,
+        flags: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |_| Ok(Command::Discard),
+    },
+    CommandSpec {
+        name: "echo",
+        arity: 2,
+        flags: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| Ok(Command::Echo(get_bytes(&args[0])?)),
+    },
+    CommandSpec {
+        name: "exec",
+        arity: 1,
+        flags: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |_| Ok(Command::Exec),
+    },
+    CommandSpec {
+        name: "exists",
+        arity: -2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        parse: |args| Ok(Command::Exists(get_bytes_vec(args)?)),
+    },
+    CommandSpec {
+        name: "expire",
+        arity: 3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| parse_expire(args, Expiry::Ex, "expire"),
+    },
+    CommandSpec {
+        name: "expireat",
+        arity: 3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| parse_expire(args, Expiry::ExAt, "expireat"),
+    },
+    CommandSpec {
+        name: "flushall",
+        arity: -1,
+        flags: &[Flag::Write, Flag::Admin],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| parse_flush(args, Command::FlushAll),
+    },
+    CommandSpec {
+        name: "flushdb",
+        arity: -1,
+        flags: &[Flag::Write, Flag::Admin],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| parse_flush(args, Command::FlushDb),
+    },
+    CommandSpec {
+        name: "get",
+        arity: 2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::Get(get_bytes(&args[0])?)),
+    },
+    CommandSpec {
+        name: "getv",
+        arity: 2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::GetV(get_bytes(&args[0])?)),
+    },
+    CommandSpec {
+        name: "hdel",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::HDel(
+                get_bytes(&args[0])?,
+                get_bytes_vec(&args[1..])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "hello",
+        arity: -1,
+        flags: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: parse_hello,
+    },
+    CommandSpec {
+        name: "hget",
+        arity: 3,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::HGet(get_bytes(&args[0])?, get_bytes(&args[1])?)),
+    },
+    CommandSpec {
+        name: "hgetall",
+        arity: 2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::HGetAll(get_bytes(&args[0])?)),
+    },
+    CommandSpec {
+        name: "hincrby",
+        arity: 4,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::HIncrBy(
+                get_bytes(&args[0])?,
+                get_bytes(&args[1])?,
+                get_integer(&args[2])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "hmget",
+        arity: -3,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::HMGet(
+                get_bytes(&args[0])?,
+                get_bytes_vec(&args[1..])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "hscan",
+        arity: -3,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::HScan(
+                get_bytes(&args[0])?,
+                parse_scan(&args[1..])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "hset",
+        arity: -4,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::HSet(
+                get_bytes(&args[0])?,
+                get_pairs(&args[1..], "hset")?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "incr",
+        arity: 2,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::IncrBy(get_bytes(&args[0])?, 1)),
+    },
+    CommandSpec {
+        name: "incrby",
+        arity: 3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::IncrBy(
+                get_bytes(&args[0])?,
+                get_integer(&args[1])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "incrbyfloat",
+        arity: 3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::IncrByFloat(
+                get_bytes(&args[0])?,
+                get_float(&args[1])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "keys",
+        arity: 2,
+        flags: &[Flag::ReadOnly],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| Ok(Command::Keys(get_bytes(&args[0])?)),
+    },
+    CommandSpec {
+        name: "llen",
+        arity: 2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::LLen(get_bytes(&args[0])?)),
+    },
+    CommandSpec {
+        name: "lmove",
+        arity: 5,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 2,
+        step: 1,
+        parse: |args| {
+            Ok(Command::LMove(
+                get_bytes(&args[0])?,
+                get_bytes(&args[1])?,
+                get_end(&args[2])?,
+                get_end(&args[3])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "lpop",
+        arity: -2,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| parse_pop(args, End::Left),
+    },
+    CommandSpec {
+        name: "lpush",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::Push(
+                End::Left,
+                get_bytes(&args[0])?,
+                get_bytes_vec(&args[1..])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "lrange",
+        arity: 4,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::LRange(
+                get_bytes(&args[0])?,
+                get_integer(&args[1])?,
+                get_integer(&args[2])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "ltrim",
+        arity: 4,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::LTrim(
+                get_bytes(&args[0])?,
+                get_integer(&args[1])?,
+                get_integer(&args[2])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "mget",
+        arity: -2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        parse: |args| Ok(Command::MGet(get_bytes_vec(args)?)),
+    },
+    CommandSpec {
+        name: "mset",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: -1,
+        step: 2,
+        parse: |args| Ok(Command::MSet(get_pairs(args, "mset")?)),
+    },
+    CommandSpec {
+        name: "msetnx",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: -1,
+        step: 2,
+        parse: |args| Ok(Command::MSetNx(get_pairs(args, "msetnx")?)),
+    },
+    CommandSpec {
+        name: "multi",
+        arity: 1,
+        flags: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |_| Ok(Command::Multi),
+    },
+    CommandSpec {
+        name: "persist",
+        arity: 2,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::Persist(get_bytes(&args[0])?)),
+    },
+    CommandSpec {
+        name: "pexpire",
+        arity: 3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| parse_expire(args, Expiry::Px, "pexpire"),
+    },
+    CommandSpec {
+        name: "pexpireat",
+        arity: 3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| parse_expire(args, Expiry::PxAt, "pexpireat"),
+    },
+    CommandSpec {
+        name: "ping",
+        arity: -1,
+        flags: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| match args {
+            [] => Ok(Command::Ping(None)),
+            [message] => Ok(Command::Ping(Some(get_bytes(message)?))),
+            _ => Err("wrong number of arguments for 'ping' command".to_string()),
+        },
+    },
+    CommandSpec {
+        name: "psubscribe",
+        arity: -2,
+        flags: &[Flag::PubSub],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| Ok(Command::PSubscribe(get_bytes_vec(args)?)),
+    },
+    CommandSpec {
+        name: "pttl",
+        arity: 2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::PTtl(get_bytes(&args[0])?)),
+    },
+    CommandSpec {
+        name: "publish",
+        arity: 3,
+        flags: &[Flag::PubSub],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| Ok(Command::Publish(get_bytes(&args[0])?, get_bytes(&args[1])?)),
+    },
+    CommandSpec {
+        name: "punsubscribe",
+        arity: -1,
+        flags: &[Flag::PubSub],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| Ok(Command::PUnsubscribe(get_bytes_vec(args)?)),
+    },
+    CommandSpec {
+        name: "remove",
+        arity: 2,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::Remove(get_bytes(&args[0])?)),
+    },
+    CommandSpec {
+        name: "rename",
+        arity: 3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 2,
+        step: 1,
+        parse: |args| Ok(Command::Rename(get_bytes(&args[0])?, get_bytes(&args[1])?)),
+    },
+    CommandSpec {
+        name: "renamenx",
+        arity: 3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 2,
+        step: 1,
+        parse: |args| {
+            Ok(Command::RenameNx(
+                get_bytes(&args[0])?,
+                get_bytes(&args[1])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "rpop",
+        arity: -2,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| parse_pop(args, End::Right),
+    },
+    CommandSpec {
+        name: "rpush",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::Push(
+                End::Right,
+                get_bytes(&args[0])?,
+                get_bytes_vec(&args[1..])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "sadd",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::SAdd(
+                get_bytes(&args[0])?,
+                get_bytes_vec(&args[1..])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "scan",
+        arity: -2,
+        flags: &[Flag::ReadOnly],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| Ok(Command::Scan(parse_scan(args)?)),
+    },
+    CommandSpec {
+        name: "sdiff",
+        arity: -2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        parse: |args| Ok(Command::SetOp(SetOp::Diff, get_bytes_vec(args)?)),
+    },
+    CommandSpec {
+        name: "sdiffstore",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::SetOpStore(
+                SetOp::Diff,
+                get_bytes(&args[0])?,
+                get_bytes_vec(&args[1..])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "select",
+        arity: 2,
+        flags: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| Ok(Command::Select(get_db_index(&args[0])?)),
+    },
+    CommandSpec {
+        name: "set",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: parse_set,
+    },
+    CommandSpec {
+        name: "sinter",
+        arity: -2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        parse: |args| Ok(Command::SetOp(SetOp::Inter, get_bytes_vec(args)?)),
+    },
+    CommandSpec {
+        name: "sinterstore",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::SetOpStore(
+                SetOp::Inter,
+                get_bytes(&args[0])?,
+                get_bytes_vec(&args[1..])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "sismember",
+        arity: 3,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::SIsMember(
+                get_bytes(&args[0])?,
+                get_bytes(&args[1])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "smembers",
+        arity: 2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::SMembers(get_bytes(&args[0])?)),
+    },
+    CommandSpec {
+        name: "srem",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::SRem(
+                get_bytes(&args[0])?,
+                get_bytes_vec(&args[1..])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "subscribe",
+        arity: -2,
+        flags: &[Flag::PubSub],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| Ok(Command::Subscribe(get_bytes_vec(args)?)),
+    },
+    CommandSpec {
+        name: "sunion",
+        arity: -2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        parse: |args| Ok(Command::SetOp(SetOp::Union, get_bytes_vec(args)?)),
+    },
+    CommandSpec {
+        name: "sunionstore",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::SetOpStore(
+                SetOp::Union,
+                get_bytes(&args[0])?,
+                get_bytes_vec(&args[1..])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "swapdb",
+        arity: 3,
+        flags: &[Flag::Write, Flag::Admin],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| {
+            Ok(Command::SwapDb(
+                get_db_index(&args[0])?,
+                get_db_index(&args[1])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "ttl",
+        arity: 2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::Ttl(get_bytes(&args[0])?)),
+    },
+    CommandSpec {
+        name: "type",
+        arity: 2,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::Type(get_bytes(&args[0])?)),
+    },
+    CommandSpec {
+        name: "unsubscribe",
+        arity: -1,
+        flags: &[Flag::PubSub],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| Ok(Command::Unsubscribe(get_bytes_vec(args)?)),
+    },
+    CommandSpec {
+        name: "unwatch",
+        arity: 1,
+        flags: &[],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |_| Ok(Command::Unwatch),
+    },
+    CommandSpec {
+        name: "watch",
+        arity: -2,
+        flags: &[],
+        first_key: 1,
+        last_key: -1,
+        step: 1,
+        parse: |args| Ok(Command::Watch(get_bytes_vec(args)?)),
+    },
+    CommandSpec {
+        name: "xack",
+        arity: -4,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: parse_xack,
+    },
+    CommandSpec {
+        name: "xadd",
+        arity: -5,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: parse_xadd,
+    },
+    CommandSpec {
+        name: "xclaim",
+        arity: -6,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: parse_xclaim,
+    },
+    CommandSpec {
+        name: "xgroup",
+        arity: -2,
+        flags: &[Flag::Write],
+        first_key: 2,
+        last_key: 2,
+        step: 1,
+        parse: parse_xgroup,
+    },
+    CommandSpec {
+        name: "xpending",
+        arity: -3,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: parse_xpending,
+    },
+    CommandSpec {
+        name: "xrange",
+        arity: -4,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: parse_xrange,
+    },
+    CommandSpec {
+        name: "xread",
+        arity: -4,
+        flags: &[Flag::ReadOnly, Flag::Blocking],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: |args| Ok(Command::XRead(parse_xread_options(args, false)?)),
+    },
+    CommandSpec {
+        name: "xreadgroup",
+        arity: -7,
+        flags: &[Flag::Write, Flag::Blocking],
+        first_key: 0,
+        last_key: 0,
+        step: 0,
+        parse: parse_xreadgroup,
+    },
+    CommandSpec {
+        name: "zadd",
+        arity: -4,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: parse_zadd,
+    },
+    CommandSpec {
+        name: "zpopmin",
+        arity: -2,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: parse_zpopmin,
+    },
+    CommandSpec {
+        name: "zrange",
+        arity: -4,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| parse_zrange(args, false),
+    },
+    CommandSpec {
+        name: "zrangebyscore",
+        arity: -4,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| parse_zrange(args, true),
+    },
+    CommandSpec {
+        name: "zrank",
+        arity: 3,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::ZRank(get_bytes(&args[0])?, get_bytes(&args[1])?)),
+    },
+    CommandSpec {
+        name: "zrem",
+        arity: -3,
+        flags: &[Flag::Write],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| {
+            Ok(Command::ZRem(
+                get_bytes(&args[0])?,
+                get_bytes_vec(&args[1..])?,
+            ))
+        },
+    },
+    CommandSpec {
+        name: "zscore",
+        arity: 3,
+        flags: &[Flag::ReadOnly],
+        first_key: 1,
+        last_key: 1,
+        step: 1,
+        parse: |args| Ok(Command::ZScore(get_bytes(&args[0])?, get_bytes(&args[1])?)),
+    },
+];
+
+/// Finds a command by name, ignoring case.
+pub fn lookup(name: &[u8]) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .binary_search_by(|spec| {
+            let lowercase = name.iter().map(u8::to_ascii_lowercase);
+            spec.name.bytes().cmp(lowercase)
+        })
+        .ok()
+        .map(|i| &COMMANDS[i])
+}
+
+impl CommandSpec {
+    pub fn has_flag(&self, flag: Flag) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    fn accepts(&self, argc: usize) -> bool {
+        if self.arity >= 0 {
+            argc as i64 == self.arity
+        } else {
+            argc as i64 >= -self.arity
+        }
+    }
+
+    fn info(&self) -> Object {
+        Object::Array(vec![
+            Object::BulkString(Some(self.name.as_bytes().to_vec())),
+            Object::Integer(self.arity),
+            Object::Set(
+                self.flags
+                    .iter()
+                    .map(|flag| Object::SimpleString(flag.name().to_string()))
+                    .collect(),
+            ),
+            Object::Integer(self.first_key),
+            Object::Integer(self.last_key),
+            Object::Integer(self.step),
+        ])
+    }
 }
 
 impl Command {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get(_) => "get",
+            Command::Set(_, _, _) => "set",
+            Command::Remove(_) => "remove",
+            Command::GetV(_) => "getv",
+            Command::Cas(_, _, _) => "cas",
+            Command::Cad(_, _) => "cad",
+            Command::MGet(_) => "mget",
+            Command::MSet(_) => "mset",
+            Command::MSetNx(_) => "msetnx",
+            Command::IncrBy(_, _) => "incrby",
+            Command::IncrByFloat(_, _) => "incrbyfloat",
+            Command::Expire(_, Expiry::Px(_)) => "pexpire",
+            Command::Expire(_, Expiry::ExAt(_)) => "expireat",
+            Command::Expire(_, Expiry::PxAt(_)) => "pexpireat",
+            Command::Expire(_, _) => "expire",
+            Command::Ttl(_) => "ttl",
+            Command::PTtl(_) => "pttl",
+            Command::Persist(_) => "persist",
+            Command::Exists(_) => "exists",
+            Command::Del(_) => "del",
+            Command::Type(_) => "type",
+            Command::Rename(_, _) => "rename",
+            Command::RenameNx(_, _) => "renamenx",
+            Command::Copy(_, _, _) => "copy",
+            Command::DbSize => "dbsize",
+            Command::FlushAll => "flushall",
+            Command::FlushDb => "flushdb",
+            Command::Select(_) => "select",
+            Command::SwapDb(_, _) => "swapdb",
+            Command::Scan(_) => "scan",
+            Command::Keys(_) => "keys",
+            Command::Push(End::Left, _, _) => "lpush",
+            Command::Push(End::Right, _, _) => "rpush",
+            Command::Pop(End::Left, _, _) => "lpop",
+            Command::Pop(End::Right, _, _) => "rpop",
+            Command::LRange(_, _, _) => "lrange",
+            Command::LLen(_) => "llen",
+            Command::LTrim(_, _, _) => "ltrim",
+            Command::LMove(_, _, _, _) => "lmove",
+            Command::BPop(End::Left, _, _) => "blpop",
+            Command::BPop(End::Right, _, _) => "brpop",
+            Command::BLMove(_, _, _, _, _) => "blmove",
+            Command::HSet(_, _) => "hset",
+            Command::HGet(_, _) => "hget",
+            Command::HMGet(_, _) => "hmget",
+            Command::HDel(_, _) => "hdel",
+            Command::HGetAll(_) => "hgetall",
+            Command::HIncrBy(_, _, _) => "hincrby",
+            Command::HScan(_, _) => "hscan",
+            Command::SAdd(_, _) => "sadd",
+            Command::SRem(_, _) => "srem",
+            Command::SIsMember(_, _) => "sismember",
+            Command::SMembers(_) => "smembers",
+            Command::SetOp(SetOp::Inter, _) => "sinter",
+            Command::SetOp(SetOp::Union, _) => "sunion",
+            Command::SetOp(SetOp::Diff, _) => "sdiff",
+            Command::SetOpStore(SetOp::Inter, _, _) => "sinterstore",
+            Command::SetOpStore(SetOp::Union, _, _) => "sunionstore",
+            Command::SetOpStore(SetOp::Diff, _, _) => "sdiffstore",
+            Command::ZAdd(_, _, _) => "zadd",
+            Command::ZScore(_, _) => "zscore",
+            Command::ZRank(_, _) => "zrank",
+            Command::ZRange(_, _) => "zrange",
+            Command::ZRem(_, _) => "zrem",
+            Command::ZPopMin(_, _) => "zpopmin",
+            Command::XAdd(_, _, _) => "xadd",
+            Command::XRange(_, _, _, _) => "xrange",
+            Command::XRead(_) => "xread",
+            Command::XReadGroup(_, _, _) => "xreadgroup",
+            Command::XGroup(_) => "xgroup",
+            Command::XAck(_, _, _) => "xack",
+            Command::XPending(_, _, _) => "xpending",
+            Command::XClaim(_, _, _, _, _, _) => "xclaim",
+            Command::Hello(_) => "hello",
+            Command::Command(_) => "command",
+            Command::Ping(_) => "ping",
+            Command::Echo(_) => "echo",
+            Command::Subscribe(_) => "subscribe",
+            Command::Unsubscribe(_) => "unsubscribe",
+            Command::PSubscribe(_) => "psubscribe",
+            Command::PUnsubscribe(_) => "punsubscribe",
+            Command::Publish(_, _) => "publish",
+            Command::Multi => "multi",
+            Command::Exec => "exec",
+            Command::Discard => "discard",
+            Command::Watch(_) => "watch",
+            Command::Unwatch => "unwatch",
+        }
+    }
+
+    /// Encodes the command the way clients send it: an array of bulk strings
+    /// starting with the command name.
+    pub fn to_object(&self) -> Object {
+        let mut args = vec![self.name().as_bytes().to_vec()];
+        match self {
+            Command::Get(key)
+            | Command::Remove(key)
+            | Command::GetV(key)
+            | Command::Ttl(key)
+            | Command::PTtl(key)
+            | Command::Persist(key)
+            | Command::Type(key)
+            | Command::LLen(key)
+            | Command::HGetAll(key)
+            | Command::SMembers(key) => args.push(key.clone()),
+            Command::Exists(keys)
+            | Command::Del(keys)
+            | Command::SetOp(_, keys)
+            | Command::Watch(keys) => args.extend(keys.iter().cloned()),
+            Command::Rename(source, destination) | Command::RenameNx(source, destination) => {
+                args.extend([source.clone(), destination.clone()])
+            }
+            Command::Copy(source, destination, replace) => {
+                args.extend([source.clone(), destination.clone()]);
+                if *replace {
+                    args.push(b"REPLACE".to_vec());
+                }
+            }
+            Command::Cas(key, version, value) => {
+                args.extend([key.clone(), version.to_string().into_bytes(), value.clone()])
+            }
+            Command::Cad(key, version) => {
+                args.extend([key.clone(), version.to_string().into_bytes()])
+            }
+            Command::Select(index) => args.push(index.to_string().into_bytes()),
+            Command::SwapDb(first, second) => args.extend([
+                first.to_string().into_bytes(),
+                second.to_string().into_bytes(),
+            ]),
+            Command::DbSize
+            | Command::FlushAll
+            | Command::FlushDb
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Unwatch => {}
+            Command::Scan(options) => args.extend(options.to_args()),
+            Command::Keys(pattern) => args.push(pattern.clone()),
+            Command::Push(_, key, values) => {
+                args.push(key.clone());
+                args.extend(values.iter().cloned());
+            }
+            Command::Pop(_, key, count) | Command::ZPopMin(key, count) => {
+                args.push(key.clone());
+                args.extend(count.map(|count| count.to_string().into_bytes()));
+            }
+            Command::LMove(source, destination, from, to) => args.extend([
+                source.clone(),
+                destination.clone(),
+                from.name().to_vec(),
+                to.name().to_vec(),
+            ]),
+            Command::BPop(_, keys, timeout) => {
+                args.extend(keys.iter().cloned());
+                args.push(timeout.to_string().into_bytes());
+            }
+            Command::BLMove(source, destination, from, to, timeout) => args.extend([
+                source.clone(),
+                destination.clone(),
+                from.name().to_vec(),
+                to.name().to_vec(),
+                timeout.to_string().into_bytes(),
+            ]),
+            Command::LRange(key, start, stop) | Command::LTrim(key, start, stop) => args.extend([
+                key.clone(),
+                start.to_string().into_bytes(),
+                stop.to_string().into_bytes(),
+            ]),
+            Command::Expire(key, expiry) => {
+                args.push(key.clone());
+                args.extend(expiry.to_args().into_iter().skip(1));
+            }
+            Command::Set(key, value, options) => {
+                args.extend([key.clone(), value.clone()]);
+                match options.condition {
+                    Some(Condition::Nx) => args.push(b"NX".to_vec()),
+                    Some(Condition::Xx) => args.push(b"XX".to_vec()),
+                    None => {}
+                }
+                if options.get {
+                    args.push(b"GET".to_vec());
+                }
+                args.extend(options.expiry.map(Expiry::to_args).unwrap_or_default());
+            }
+            Command::MGet(keys) => args.extend(keys.iter().cloned()),
+            Command::IncrBy(key, increment) => {
+                args.extend([key.clone(), increment.to_string().into_bytes()])
+            }
+            Command::IncrByFloat(key, increment) => {
+                args.extend([key.clone(), increment.to_string().into_bytes()])
+            }
+            Command::MSet(pairs) | Command::MSetNx(pairs) => {
+                for (key, value) in pairs {
+                    args.extend([key.clone(), value.clone()]);
+                }
+            }
+            Command::HSet(key, pairs) => {
+                args.push(key.clone());
+                for (field, value) in pairs {
+                    args.extend([field.clone(), value.clone()]);
+                }
+            }
+            Command::HGet(key, field)
+            | Command::SIsMember(key, field)
+            | Command::ZScore(key, field)
+            | Command::ZRank(key, field) => args.extend([key.clone(), field.clone()]),
+            Command::HMGet(key, fields)
+            | Command::HDel(key, fields)
+            | Command::SAdd(key, fields)
+            | Command::SRem(key, fields)
+            | Command::ZRem(key, fields)
+            | Command::SetOpStore(_, key, fields) => {
+                args.push(key.clone());
+                args.extend(fields.iter().cloned());
+            }
+            Command::HIncrBy(key, field, increment) => args.extend([
+                key.clone(),
+                field.clone(),
+                increment.to_string().into_bytes(),
+            ]),
+            Command::HScan(key, options) => {
+                args.push(key.clone());
+                args.extend(options.to_args());
+            }
+            Command::ZAdd(key, options, members) => {
+                args.push(key.clone());
+                match options.condition {
+                    Some(Condition::Nx) => args.push(b"NX".to_vec()),
+                    Some(Condition::Xx) => args.push(b"XX".to_vec()),
+                    None => {}
+                }
+                match options.comparison {
+                    Some(Comparison::Gt) => args.push(b"GT".to_vec()),
+                    Some(Comparison::Lt) => args.push(b"LT".to_vec()),
+                    None => {}
+                }
+                if options.ch {
+                    args.push(b"CH".to_vec());
+                }
+                if options.incr {
+                    args.push(b"INCR".to_vec());
+                }
+                for (score, member) in members {
+                    args.extend([format_double(*score).into_bytes(), member.clone()]);
+                }
+            }
+            Command::ZRange(key, options) => {
+                args.push(key.clone());
+                args.extend(options.to_args());
+            }
+            Command::XAdd(key, id, pairs) => {
+                args.extend([key.clone(), id.to_arg()]);
+                for (field, value) in pairs {
+                    args.extend([field.clone(), value.clone()]);
+                }
+            }
+            Command::XRange(key, start, end, count) => {
+                args.extend([key.clone(), start.to_bytes(), end.to_bytes()]);
+                if let Some(count) = count {
+                    args.extend([b"COUNT".to_vec(), count.to_string().into_bytes()]);
+                }
+            }
+            Command::XRead(options) => args.extend(options.to_args()),
+            Command::XReadGroup(group, consumer, options) => {
+                args.extend([b"GROUP".to_vec(), group.clone(), consumer.clone()]);
+                args.extend(options.to_args());
+            }
+            Command::XGroup(XGroup::Create(key, group, start, mkstream)) => {
+                args.extend([
+                    b"CREATE".to_vec(),
+                    key.clone(),
+                    group.clone(),
+                    start.to_arg(),
+                ]);
+                if *mkstream {
+                    args.push(b"MKSTREAM".to_vec());
+                }
+            }
+            Command::XGroup(XGroup::SetId(key, group, start)) => args.extend([
+                b"SETID".to_vec(),
+                key.clone(),
+                group.clone(),
+                start.to_arg(),
+            ]),
+            Command::XGroup(XGroup::Destroy(key, group)) => {
+                args.extend([b"DESTROY".to_vec(), key.clone(), group.clone()])
+            }
+            Command::XAck(key, group, ids) => {
+                args.extend([key.clone(), group.clone()]);
+                args.extend(ids.iter().map(|id| id.to_bytes()));
+            }
+            Command::XPending(key, group, range) => {
+                args.extend([key.clone(), group.clone()]);
+                if let Some(range) = range {
+                    if let Some(idle) = range.idle {
+                        args.extend([b"IDLE".to_vec(), idle.to_string().into_bytes()]);
+                    }
+                    args.extend([
+                        range.start.to_bytes(),
+                        range.end.to_bytes(),
+                        range.count.to_string().into_bytes(),
+                    ]);
+                    args.extend(range.consumer.iter().cloned());
+                }
+            }
+            Command::XClaim(key, group, consumer, min_idle, ids, options) => {
+                args.extend([
+                    key.clone(),
+                    group.clone(),
+                    consumer.clone(),
+                    min_idle.to_string().into_bytes(),
+                ]);
+                args.extend(ids.iter().map(|id| id.to_bytes()));
+                args.extend(options.to_args());
+            }
+            Command::Hello(protover) => args.extend(protover.map(|v| v.to_string().into_bytes())),
+            Command::Command(introspection) => match introspection {
+                Introspection::All => {}
+                Introspection::Count => args.push(b"count".to_vec()),
+                Introspection::Docs => args.push(b"docs".to_vec()),
+                Introspection::Info(names) => {
+                    args.push(b"info".to_vec());
+                    args.extend(names.iter().cloned());
+                }
+                Introspection::List => args.push(b"list".to_vec()),
+            },
+            Command::Ping(message) => args.extend(message.iter().cloned()),
+            Command::Echo(message) => args.push(message.clone()),
+            Command::Subscribe(names)
+            | Command::Unsubscribe(names)
+            | Command::PSubscribe(names)
+            | Command::PUnsubscribe(names) => args.extend(names.iter().cloned()),
+            Command::Publish(channel, message) => args.extend([channel.clone(), message.clone()]),
+        }
+        Object::Array(
+            args.into_iter()
+                .map(|arg| Object::BulkString(Some(arg)))
+                .collect(),
+        )
+    }
+
+    pub fn spec(&self) -> &'static CommandSpec {
+        lookup(self.name().as_bytes()).expect("Every command should have a spec")
+    }
+
     pub fn possibly_dirty(&self) -> bool {
+        self.spec().has_flag(Flag::Write)
+    }
+}
+
+impl Introspection {
+    pub fn reply(&self) -> Object {
         match self {
-            Command::Get(_) => false,
-            Command::Set(_, _) => true,
-            Command::Remove(_) => true,
+            Introspection::All => Object::Array(COMMANDS.iter().map(CommandSpec::info).collect()),
+            Introspection::Count => Object::Integer(COMMANDS.len() as i64),
+            Introspection::Docs => Object::Map(Vec::new()),
+            Introspection::Info(names) => Object::Array(
+                names
+                    .iter()
+                    .map(|name| lookup(name).map_or(Object::Null, CommandSpec::info))
+                    .collect(),
+            ),
+            Introspection::List => Object::Array(
+                COMMANDS
+                    .iter()
+                    .map(|spec| Object::BulkString(Some(spec.name.as_bytes().to_vec())))
+                    .collect(),
+            ),
         }
     }
 }
@@ -22,44 +1898,733 @@ impl TryFrom<Object> for Command {
     type Error = String;
 
     fn try_from(obj: Object) -> Result<Self, Self::Error> {
-        if let Object::Array(vec) = obj {
-            Command::try_from(vec)
+        Command::try_from(&obj.to_ref())
+    }
+}
+
+impl TryFrom<Vec<Object>> for Command {
+    type Error = String;
+
+    fn try_from(vec: Vec<Object>) -> Result<Self, Self::Error> {
+        let vec: Vec<ObjectRef> = vec.iter().map(Object::to_ref).collect();
+        Command::try_from(&vec[..])
+    }
+}
+
+impl<'a> TryFrom<&ObjectRef<'a>> for Command {
+    type Error = String;
+
+    fn try_from(obj: &ObjectRef<'a>) -> Result<Self, Self::Error> {
+        if let ObjectRef::Array(vec) = obj {
+            Command::try_from(&vec[..])
         } else {
             Err("Object is not a valid Command".to_string())
         }
     }
 }
 
-impl TryFrom<Vec<Object>> for Command {
+impl<'a> TryFrom<&[ObjectRef<'a>]> for Command {
     type Error = String;
 
-    fn try_from(vec: Vec<Object>) -> Result<Self, Self::Error> {
-        if vec.is_empty() {
-            return Err("Empty array object".to_string());
+    fn try_from(vec: &[ObjectRef<'a>]) -> Result<Self, Self::Error> {
+        let name = match vec.first() {
+            Some(name) => get_slice(name).map_err(|_| "Unknown command".to_string())?,
+            None => return Err("Empty array object".to_string()),
+        };
+        let spec = lookup(name)
+            .ok_or_else(|| format!("unknown command '{}'", String::from_utf8_lossy(name)))?;
+
+        if !spec.accepts(vec.len()) {
+            return Err(format!(
+                "wrong number of arguments for '{}' command",
+                spec.name
+            ));
         }
+        (spec.parse)(&vec[1..])
+    }
+}
 
-        let arity = vec.len() - 1;
-        match vec[0] {
-            Object::SimpleString(ref s) => match (s.as_str(), arity) {
-                ("get", 1) => Ok(Command::Get(get_string(&vec[1])?)),
-                ("set", 2) => Ok(Command::Set(get_string(&vec[1])?, get_string(&vec[2])?)),
-                ("remove", 1) => Ok(Command::Remove(get_string(&vec[1])?)),
-                _ => Err("Unknown command".to_string()),
-            },
-            _ => Err("Unknown command".to_string()),
+// COMMAND [COUNT | DOCS [name ...] | INFO [name ...] | LIST]
+//
+// DOCS is accepted so that clients that ask for documentation on connect keep
+// working, but there is no documentation to reply with.
+fn parse_command(args: &[ObjectRef]) -> Result<Command, String> {
+    let (subcommand, rest) = match args.split_first() {
+        Some((subcommand, rest)) => (get_slice(subcommand)?.to_ascii_lowercase(), rest),
+        None => return Ok(Command::Command(Introspection::All)),
+    };
+
+    let introspection = match (&subcommand[..], rest.len()) {
+        (b"count", 0) => Introspection::Count,
+        (b"docs", _) => Introspection::Docs,
+        (b"info", _) => Introspection::Info(get_bytes_vec(rest)?),
+        (b"list", 0) => Introspection::List,
+        _ => {
+            return Err(format!(
+                "unknown subcommand or wrong number of arguments for '{}'",
+                String::from_utf8_lossy(&subcommand)
+            ))
+        }
+    };
+    Ok(Command::Command(introspection))
+}
+
+// HELLO [protover [AUTH username password] [SETNAME clientname]]
+//
+// There is no authentication and no client naming yet, so the AUTH and
+// SETNAME clauses are validated but otherwise ignored.
+fn parse_hello(args: &[ObjectRef]) -> Result<Command, String> {
+    let (protover, mut rest) = match args.split_first() {
+        Some((protover, rest)) => (Some(get_integer(protover)?), rest),
+        None => return Ok(Command::Hello(None)),
+    };
+
+    while let Some(option) = rest.first() {
+        let option = get_slice(option)?.to_ascii_lowercase();
+        rest = match (&option[..], rest.len()) {
+            (b"auth", n) if n >= 3 => &rest[3..],
+            (b"setname", n) if n >= 2 => &rest[2..],
+            _ => return Err("Syntax error in HELLO option".to_string()),
+        };
+    }
+
+    Ok(Command::Hello(protover))
+}
+
+fn parse_set(args: &[ObjectRef]) -> Result<Command, String> {
+    let key = get_bytes(&args[0])?;
+    let value = get_bytes(&args[1])?;
+    let mut options = SetOptions::default();
+
+    let mut rest = &args[2..];
+    while let Some(option) = rest.first() {
+        let option = get_slice(option)?.to_ascii_uppercase();
+        let time = rest.get(1);
+        rest = &rest[1..];
+        match &option[..] {
+            b"NX" | b"XX" if options.condition.is_none() => {
+                options.condition = Some(if option == b"NX" {
+                    Condition::Nx
+                } else {
+                    Condition::Xx
+                });
+            }
+            b"GET" if !options.get => options.get = true,
+            b"KEEPTTL" if options.expiry.is_none() => options.expiry = Some(Expiry::KeepTtl),
+            b"EX" | b"PX" | b"EXAT" | b"PXAT" if options.expiry.is_none() => {
+                let time = get_integer(time.ok_or_else(|| "syntax error".to_string())?)?;
+                rest = &rest[1..];
+                let expiry = match &option[..] {
+                    b"EX" => Expiry::Ex(time),
+                    b"PX" => Expiry::Px(time),
+                    b"EXAT" => Expiry::ExAt(time),
+                    _ => Expiry::PxAt(time),
+                };
+                if time <= 0 || expiry.deadline(0).is_none() {
+                    return Err("invalid expire time in 'set' command".to_string());
+                }
+                options.expiry = Some(expiry);
+            }
+            _ => return Err("syntax error".to_string()),
+        }
+    }
+
+    Ok(Command::Set(key, value, options))
+}
+
+/// Parses `cursor [MATCH pattern] [COUNT count]`.
+fn parse_scan(args: &[ObjectRef]) -> Result<ScanOptions, String> {
+    let cursor = std::str::from_utf8(get_slice(&args[0])?)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| "invalid cursor".to_string())?;
+    let mut options = ScanOptions {
+        cursor,
+        pattern: None,
+        count: 10,
+    };
+    for pair in args[1..].chunks(2) {
+        match (
+            get_slice(&pair[0])?.to_ascii_uppercase().as_slice(),
+            pair.get(1),
+        ) {
+            (b"MATCH", Some(pattern)) => options.pattern = Some(get_bytes(pattern)?),
+            (b"COUNT", Some(count)) => {
+                options.count = match get_integer(count)? {
+                    count if count >= 1 => count as usize,
+                    _ => return Err("syntax error".to_string()),
+                }
+            }
+            _ => return Err("syntax error".to_string()),
+        }
+    }
+    Ok(options)
+}
+
+fn parse_pop(args: &[ObjectRef], end: End) -> Result<Command, String> {
+    Ok(Command::Pop(
+        end,
+        get_bytes(&args[0])?,
+        get_count(&args[1..])?,
+    ))
+}
+
+fn parse_zpopmin(args: &[ObjectRef]) -> Result<Command, String> {
+    Ok(Command::ZPopMin(
+        get_bytes(&args[0])?,
+        get_count(&args[1..])?,
+    ))
+}
+
+/// Parses the optional count of the pop commands.
+fn get_count(args: &[ObjectRef]) -> Result<Option<usize>, String> {
+    match args {
+        [] => Ok(None),
+        [count] => match get_integer(count)? {
+            count if count >= 0 => Ok(Some(count as usize)),
+            _ => Err("value is out of range, must be positive".to_string()),
+        },
+        _ => Err("syntax error".to_string()),
+    }
+}
+
+/// Parses `key [NX|XX] [GT|LT] [CH] [INCR] score member [score member ...]`.
+fn parse_zadd(args: &[ObjectRef]) -> Result<Command, String> {
+    let key = get_bytes(&args[0])?;
+    let mut options = ZAddOptions::default();
+
+    const NX_XX: &str = "XX and NX options at the same time are not compatible";
+    const GT_LT_NX: &str = "GT, LT, and/or NX options at the same time are not compatible";
+    let mut rest = &args[1..];
+    while let Some(option) = rest.first() {
+        match &get_slice(option)?.to_ascii_uppercase()[..] {
+            b"NX" => set_exclusive(&mut options.condition, Condition::Nx, NX_XX)?,
+            b"XX" => set_exclusive(&mut options.condition, Condition::Xx, NX_XX)?,
+            b"GT" => set_exclusive(&mut options.comparison, Comparison::Gt, GT_LT_NX)?,
+            b"LT" => set_exclusive(&mut options.comparison, Comparison::Lt, GT_LT_NX)?,
+            b"CH" => options.ch = true,
+            b"INCR" => options.incr = true,
+            _ => break,
+        }
+        rest = &rest[1..];
+    }
+    if options.condition == Some(Condition::Nx) && options.comparison.is_some() {
+        return Err(GT_LT_NX.to_string());
+    }
+
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        return Err("syntax error".to_string());
+    }
+    if options.incr && rest.len() > 2 {
+        return Err("INCR option supports a single increment-element pair".to_string());
+    }
+    let members = rest
+        .chunks(2)
+        .map(|pair| Ok((get_score(&pair[0])?, get_bytes(&pair[1])?)))
+        .collect::<Result<_, String>>()?;
+    Ok(Command::ZAdd(key, options, members))
+}
+
+/// Sets an option of which only one may be given, like `NX` and `XX`.
+fn set_exclusive<T: PartialEq>(
+    option: &mut Option<T>,
+    value: T,
+    error: &str,
+) -> Result<(), String> {
+    if option.as_ref().is_some_and(|old| *old != value) {
+        return Err(error.to_string());
+    }
+    *option = Some(value);
+    Ok(())
+}
+
+/// Parses `ZRANGE key start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count]
+/// [WITHSCORES]`, or `ZRANGEBYSCORE key min max [WITHSCORES] [LIMIT offset
+/// count]` if `by_score` is set.
+fn parse_zrange(args: &[ObjectRef], by_score: bool) -> Result<Command, String> {
+    let key = get_bytes(&args[0])?;
+    let (mut score, mut lex, mut rev) = (by_score, false, false);
+    let mut limit = None;
+    let mut with_scores = false;
+
+    let mut rest = &args[3..];
+    while let Some(option) = rest.first() {
+        rest = &rest[1..];
+        match &get_slice(option)?.to_ascii_uppercase()[..] {
+            b"BYSCORE" if !by_score => score = true,
+            b"BYLEX" if !by_score => lex = true,
+            b"REV" if !by_score => rev = true,
+            b"WITHSCORES" => with_scores = true,
+            b"LIMIT" if rest.len() >= 2 => {
+                limit = Some((get_integer(&rest[0])?, get_integer(&rest[1])?));
+                rest = &rest[2..];
+            }
+            _ => return Err("syntax error".to_string()),
+        }
+    }
+
+    let (start, stop) = match rev {
+        false => (&args[1], &args[2]),
+        true => (&args[2], &args[1]),
+    };
+    let by = match (score, lex) {
+        (true, true) => return Err("syntax error".to_string()),
+        (true, false) => ZRangeBy::Score(get_score_bound(start)?, get_score_bound(stop)?),
+        (false, true) => {
+            if with_scores {
+                return Err(
+                    "syntax error, WITHSCORES not supported in combination with BYLEX".to_string(),
+                );
+            }
+            ZRangeBy::Lex(get_lex_bound(start)?, get_lex_bound(stop)?)
+        }
+        (false, false) => {
+            if limit.is_some() {
+                return Err(
+                    "syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+                        .to_string(),
+                );
+            }
+            // Indexes are not swapped by REV
+            ZRangeBy::Index(get_integer(&args[1])?, get_integer(&args[2])?)
+        }
+    };
+    let options = ZRangeOptions {
+        by,
+        rev,
+        limit,
+        with_scores,
+    };
+    Ok(Command::ZRange(key, options))
+}
+
+/// Parses a score, which unlike other floats can be infinite.
+fn get_score(obj: &ObjectRef) -> Result<f64, String> {
+    std::str::from_utf8(get_slice(obj)?)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|score: &f64| !score.is_nan())
+        .ok_or_else(|| "value is not a valid float".to_string())
+}
+
+fn get_score_bound(obj: &ObjectRef) -> Result<ScoreBound, String> {
+    let bound = get_slice(obj)?;
+    let (score, exclusive) = match bound.strip_prefix(b"(") {
+        Some(score) => (score, true),
+        None => (bound, false),
+    };
+    let score = std::str::from_utf8(score)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|score: &f64| !score.is_nan())
+        .ok_or_else(|| "min or max is not a float".to_string())?;
+    Ok(ScoreBound { score, exclusive })
+}
+
+fn get_lex_bound(obj: &ObjectRef) -> Result<LexBound, String> {
+    match get_slice(obj)? {
+        b"-" => Ok(LexBound::Lowest),
+        b"+" => Ok(LexBound::Highest),
+        [b'[', member @ ..] => Ok(LexBound::Inclusive(member.to_vec())),
+        [b'(', member @ ..] => Ok(LexBound::Exclusive(member.to_vec())),
+        _ => Err("min or max not valid string range item".to_string()),
+    }
+}
+
+const INVALID_STREAM_ID: &str = "Invalid stream ID specified as stream command argument";
+
+fn get_stream_id(obj: &ObjectRef) -> Result<StreamId, String> {
+    StreamId::parse(get_slice(obj)?, 0).ok_or_else(|| INVALID_STREAM_ID.to_string())
+}
+
+/// Parses the start of an ID range: `-` for the lowest ID, or an ID that a
+/// leading `(` excludes. A missing sequence number counts as the lowest.
+fn get_range_start(obj: &ObjectRef) -> Result<StreamId, String> {
+    match get_slice(obj)? {
+        b"-" => Ok(StreamId::MIN),
+        [b'(', id @ ..] => StreamId::parse(id, 0)
+            .ok_or_else(|| INVALID_STREAM_ID.to_string())?
+            .next()
+            .ok_or_else(|| "invalid start ID for the interval".to_string()),
+        id => StreamId::parse(id, 0).ok_or_else(|| INVALID_STREAM_ID.to_string()),
+    }
+}
+
+/// Parses the end of an ID range, where `+` is the highest ID and a missing
+/// sequence number counts as the highest.
+fn get_range_end(obj: &ObjectRef) -> Result<StreamId, String> {
+    match get_slice(obj)? {
+        b"+" => Ok(StreamId::MAX),
+        [b'(', id @ ..] => StreamId::parse(id, u64::MAX)
+            .ok_or_else(|| INVALID_STREAM_ID.to_string())?
+            .prev()
+            .ok_or_else(|| "invalid end ID for the interval".to_string()),
+        id => StreamId::parse(id, u64::MAX).ok_or_else(|| INVALID_STREAM_ID.to_string()),
+    }
+}
+
+/// Parses `key <* | ms-* | id> field value [field value ...]`.
+fn parse_xadd(args: &[ObjectRef]) -> Result<Command, String> {
+    let id = match get_slice(&args[1])? {
+        b"*" => XAddId::Auto,
+        id => match id.strip_suffix(b"-*") {
+            Some(ms) if !ms.contains(&b'-') => {
+                let ms = StreamId::parse(ms, 0).ok_or_else(|| INVALID_STREAM_ID.to_string())?;
+                XAddId::Seq(ms.ms)
+            }
+            _ => XAddId::Explicit(get_stream_id(&args[1])?),
+        },
+    };
+    Ok(Command::XAdd(
+        get_bytes(&args[0])?,
+        id,
+        get_pairs(&args[2..], "xadd")?,
+    ))
+}
+
+/// Parses `key start end [COUNT count]`, where a count below 1 means all.
+fn parse_xrange(args: &[ObjectRef]) -> Result<Command, String> {
+    let count = match &args[3..] {
+        [] => None,
+        [option, count] if get_slice(option)?.eq_ignore_ascii_case(b"count") => {
+            Some(get_integer(count)?.max(0) as usize)
+        }
+        _ => return Err("syntax error".to_string()),
+    };
+    Ok(Command::XRange(
+        get_bytes(&args[0])?,
+        get_range_start(&args[1])?,
+        get_range_end(&args[2])?,
+        count,
+    ))
+}
+
+/// Parses `[COUNT count] [BLOCK milliseconds] [NOACK] STREAMS key [key ...] id
+/// [id ...]`, where only a group read takes `NOACK` and `>` IDs, and only a
+/// plain read takes `$` IDs.
+fn parse_xread_options(args: &[ObjectRef], group: bool) -> Result<XReadOptions, String> {
+    let mut options = XReadOptions::default();
+    let mut rest = args;
+    loop {
+        let option = rest.first().ok_or_else(|| "syntax error".to_string())?;
+        rest = &rest[1..];
+        match &get_slice(option)?.to_ascii_uppercase()[..] {
+            b"COUNT" if !rest.is_empty() => {
+                let count = get_integer(&rest[0])?;
+                options.count = (count > 0).then_some(count as usize);
+                rest = &rest[1..];
+            }
+            b"BLOCK" if !rest.is_empty() => {
+                options.block = match get_integer(&rest[0]) {
+                    Ok(block) if block < 0 => return Err("timeout is negative".to_string()),
+                    Ok(block) => Some(block as u64),
+                    Err(_) => return Err("timeout is not an integer or out of range".to_string()),
+                };
+                rest = &rest[1..];
+            }
+            b"NOACK" if group => options.noack = true,
+            b"STREAMS" => break,
+            _ => return Err("syntax error".to_string()),
+        }
+    }
+
+    let (name, special) = match group {
+        true => ("xreadgroup", "'>'"),
+        false => ("xread", "'$'"),
+    };
+    if rest.is_empty() || !rest.len().is_multiple_of(2) {
+        return Err(format!(
+            "Unbalanced '{}' list of streams: for each stream key an ID or {} must be specified.",
+            name, special
+        ));
+    }
+    let (keys, ids) = rest.split_at(rest.len() / 2);
+    for (key, id) in keys.iter().zip(ids) {
+        let start = match (get_slice(id)?, group) {
+            (b"$", false) => StreamStart::Latest,
+            (b">", true) => StreamStart::Undelivered,
+            (b"$", true) => {
+                return Err(
+                    "The $ ID is meaningless in the context of XREADGROUP: you want to \
+                    read the history of this consumer by specifying a proper ID, or use the > \
+                    ID to get new messages. The $ ID would just return an empty result set."
+                        .to_string(),
+                )
+            }
+            (b">", false) => {
+                return Err(
+                    "The > ID can be specified only when calling XREADGROUP using the \
+                    GROUP <group> <consumer> option."
+                        .to_string(),
+                )
+            }
+            _ => StreamStart::After(get_stream_id(id)?),
+        };
+        options.streams.push((get_bytes(key)?, start));
+    }
+    Ok(options)
+}
+
+/// Parses `GROUP group consumer` followed by the options of `XREAD`.
+fn parse_xreadgroup(args: &[ObjectRef]) -> Result<Command, String> {
+    if !get_slice(&args[0])?.eq_ignore_ascii_case(b"group") {
+        return Err("syntax error".to_string());
+    }
+    Ok(Command::XReadGroup(
+        get_bytes(&args[1])?,
+        get_bytes(&args[2])?,
+        parse_xread_options(&args[3..], true)?,
+    ))
+}
+
+// XGROUP CREATE key group <id | $> [MKSTREAM]
+// XGROUP SETID key group <id | $>
+// XGROUP DESTROY key group
+fn parse_xgroup(args: &[ObjectRef]) -> Result<Command, String> {
+    let subcommand = get_slice(&args[0])?.to_ascii_lowercase();
+    let start = |obj: &ObjectRef| -> Result<StreamStart, String> {
+        match get_slice(obj)? {
+            b"$" => Ok(StreamStart::Latest),
+            _ => Ok(StreamStart::After(get_stream_id(obj)?)),
+        }
+    };
+
+    let xgroup = match (&subcommand[..], &args[1..]) {
+        (b"create", [key, group, id]) => {
+            XGroup::Create(get_bytes(key)?, get_bytes(group)?, start(id)?, false)
+        }
+        (b"create", [key, group, id, option])
+            if get_slice(option)?.eq_ignore_ascii_case(b"mkstream") =>
+        {
+            XGroup::Create(get_bytes(key)?, get_bytes(group)?, start(id)?, true)
         }
+        (b"setid", [key, group, id]) => {
+            XGroup::SetId(get_bytes(key)?, get_bytes(group)?, start(id)?)
+        }
+        (b"destroy", [key, group]) => XGroup::Destroy(get_bytes(key)?, get_bytes(group)?),
+        _ => {
+            return Err(format!(
+                "unknown subcommand or wrong number of arguments for '{}'",
+                String::from_utf8_lossy(&subcommand)
+            ))
+        }
+    };
+    Ok(Command::XGroup(xgroup))
+}
+
+fn parse_xack(args: &[ObjectRef]) -> Result<Command, String> {
+    Ok(Command::XAck(
+        get_bytes(&args[0])?,
+        get_bytes(&args[1])?,
+        args[2..]
+            .iter()
+            .map(get_stream_id)
+            .collect::<Result<_, _>>()?,
+    ))
+}
+
+/// Parses `key group [[IDLE min-idle-time] start end count [consumer]]`.
+fn parse_xpending(args: &[ObjectRef]) -> Result<Command, String> {
+    let mut rest = &args[2..];
+    let mut idle = None;
+    if rest.len() >= 2 && get_slice(&rest[0])?.eq_ignore_ascii_case(b"idle") {
+        idle = Some(get_integer(&rest[1])?);
+        rest = &rest[2..];
+    }
+    let range = match rest {
+        [] if idle.is_none() => None,
+        [start, end, count, consumer @ ..] if consumer.len() <= 1 => Some(XPendingRange {
+            idle,
+            start: get_range_start(start)?,
+            end: get_range_end(end)?,
+            count: get_integer(count)?.max(0) as usize,
+            consumer: consumer.first().map(get_bytes).transpose()?,
+        }),
+        _ => return Err("syntax error".to_string()),
+    };
+    Ok(Command::XPending(
+        get_bytes(&args[0])?,
+        get_bytes(&args[1])?,
+        range,
+    ))
+}
+
+/// Parses `key group consumer min-idle-time id [id ...] [IDLE ms] [TIME
+/// unix-time-ms] [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID id]`.
+fn parse_xclaim(args: &[ObjectRef]) -> Result<Command, String> {
+    let min_idle = get_integer(&args[3])
+        .map_err(|_| "Invalid min-idle-time argument for XCLAIM".to_string())?;
+    // The IDs go on until the first argument that is not one
+    let mut ids = Vec::new();
+    let mut rest = &args[4..];
+    while let Some(id) = rest.first().and_then(|id| get_stream_id(id).ok()) {
+        ids.push(id);
+        rest = &rest[1..];
+    }
+
+    let mut options = XClaimOptions::default();
+    while let Some(option) = rest.first() {
+        let option = get_slice(option)?.to_ascii_uppercase();
+        let value = rest.get(1);
+        rest = &rest[1..];
+        match (&option[..], value) {
+            (b"FORCE", _) => options.force = true,
+            (b"JUSTID", _) => options.justid = true,
+            (b"IDLE", Some(idle)) => options.idle = Some(get_integer(idle)?),
+            (b"TIME", Some(time)) => options.time = Some(get_integer(time)?),
+            (b"RETRYCOUNT", Some(count)) => {
+                options.retry_count = Some(
+                    u64::try_from(get_integer(count)?)
+                        .map_err(|_| "Invalid RETRYCOUNT option argument for XCLAIM")?,
+                )
+            }
+            (b"LASTID", Some(id)) => options.last_id = Some(get_stream_id(id)?),
+            _ => {
+                return Err(format!(
+                    "Unrecognized XCLAIM option '{}'",
+                    String::from_utf8_lossy(&option)
+                ))
+            }
+        }
+        if !matches!(&option[..], b"FORCE" | b"JUSTID") {
+            rest = &rest[1..];
+        }
+    }
+    Ok(Command::XClaim(
+        get_bytes(&args[0])?,
+        get_bytes(&args[1])?,
+        get_bytes(&args[2])?,
+        min_idle,
+        ids,
+        options,
+    ))
+}
+
+fn parse_bpop(args: &[ObjectRef], end: End) -> Result<Command, String> {
+    let (timeout, keys) = args.split_last().unwrap();
+    Ok(Command::BPop(
+        end,
+        get_bytes_vec(keys)?,
+        get_timeout(timeout)?,
+    ))
+}
+
+fn get_end(obj: &ObjectRef) -> Result<End, String> {
+    match &get_slice(obj)?.to_ascii_uppercase()[..] {
+        b"LEFT" => Ok(End::Left),
+        b"RIGHT" => Ok(End::Right),
+        _ => Err("syntax error".to_string()),
+    }
+}
+
+fn get_timeout(obj: &ObjectRef) -> Result<f64, String> {
+    match get_float(obj) {
+        Ok(timeout) if timeout < 0.0 => Err("timeout is negative".to_string()),
+        Ok(timeout) => Ok(timeout),
+        Err(_) => Err("timeout is not a float or out of range".to_string()),
     }
 }
 
-fn get_string(obj: &Object) -> Result<String, String> {
+fn parse_copy(args: &[ObjectRef]) -> Result<Command, String> {
+    let replace = match &args[2..] {
+        [] => false,
+        [option] if get_slice(option)?.eq_ignore_ascii_case(b"replace") => true,
+        _ => return Err("syntax error".to_string()),
+    };
+    Ok(Command::Copy(
+        get_bytes(&args[0])?,
+        get_bytes(&args[1])?,
+        replace,
+    ))
+}
+
+/// Flushing is always synchronous, so `ASYNC` and `SYNC` are accepted and ignored.
+fn parse_flush(args: &[ObjectRef], cmd: Command) -> Result<Command, String> {
+    match args {
+        [] => Ok(cmd),
+        [mode]
+            if matches!(
+                &get_slice(mode)?.to_ascii_lowercase()[..],
+                b"async" | b"sync"
+            ) =>
+        {
+            Ok(cmd)
+        }
+        _ => Err("syntax error".to_string()),
+    }
+}
+
+fn parse_expire(
+    args: &[ObjectRef],
+    expiry: fn(i64) -> Expiry,
+    name: &str,
+) -> Result<Command, String> {
+    let expiry = expiry(get_integer(&args[1])?);
+    if expiry.deadline(0).is_none() {
+        return Err(format!("invalid expire time in '{}' command", name));
+    }
+    Ok(Command::Expire(get_bytes(&args[0])?, expiry))
+}
+
+fn get_integer(obj: &ObjectRef) -> Result<i64, String> {
     match obj {
-        Object::SimpleString(ref s) => Ok(s.clone()),
-        Object::BulkString(Some(ref s)) => Ok(s.clone()),
+        ObjectRef::Integer(int) => Ok(*int),
+        _ => std::str::from_utf8(get_slice(obj)?)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| "Value is not an integer or out of range".to_string()),
+    }
+}
+
+/// A database index. Whether the database exists is up to the server.
+fn get_db_index(obj: &ObjectRef) -> Result<usize, String> {
+    usize::try_from(get_integer(obj)?).map_err(|_| "DB index is out of range".to_string())
+}
+
+fn get_version(obj: &ObjectRef) -> Result<u64, String> {
+    u64::try_from(get_integer(obj)?)
+        .map_err(|_| "Value is not an integer or out of range".to_string())
+}
+
+fn get_float(obj: &ObjectRef) -> Result<f64, String> {
+    match obj {
+        ObjectRef::Double(double) => Some(*double),
+        ObjectRef::Integer(int) => Some(*int as f64),
+        _ => std::str::from_utf8(get_slice(obj)?)
+            .ok()
+            .and_then(|s| s.parse().ok()),
+    }
+    .filter(|double: &f64| double.is_finite())
+    .ok_or_else(|| "Value is not a valid float".to_string())
+}
+
+fn get_slice<'a>(obj: &ObjectRef<'a>) -> Result<&'a [u8], String> {
+    match *obj {
+        ObjectRef::SimpleString(s) => Ok(s.as_bytes()),
+        ObjectRef::BulkString(Some(s)) => Ok(s),
         _ => Err("Unsupported type".to_string()),
     }
 }
 
-#[derive(Debug)]
+fn get_bytes(obj: &ObjectRef) -> Result<Vec<u8>, String> {
+    get_slice(obj).map(<[u8]>::to_vec)
+}
+
+fn get_bytes_vec(args: &[ObjectRef]) -> Result<Vec<Vec<u8>>, String> {
+    args.iter().map(get_bytes).collect()
+}
+
+fn get_pairs(args: &[ObjectRef], name: &str) -> Result<Vec<KeyValue>, String> {
+    if !args.len().is_multiple_of(2) {
+        return Err(format!("wrong number of arguments for '{}' command", name));
+    }
+    args.chunks(2)
+        .map(|pair| Ok((get_bytes(&pair[0])?, get_bytes(&pair[1])?)))
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum NetCommand {
     Leader(String),
 }
@@ -73,36 +2638,307 @@ impl TryFrom<&Object> for NetCommand {
     type Error = NetCommandError;
 
     fn try_from(obj: &Object) -> Result<Self, Self::Error> {
-        if let Object::Array(objs) = obj {
-            if objs.is_empty() {
-                return Err(NetCommandError::NotANetCommand);
-            }
-
-            let arity = objs.len() - 1;
-            if let Object::SimpleString(ref s) = objs[0] {
-                return match (s.as_str(), arity) {
-                    ("leader", 1) => {
-                        if let Object::SimpleString(ref pass) = objs[1] {
-                            Ok(NetCommand::Leader(pass.clone()))
-                        } else {
-                            Err(NetCommandError::Invalid)
-                        }
-                    }
-                    _ => Err(NetCommandError::NotANetCommand),
-                };
-            }
-        }
-        Err(NetCommandError::NotANetCommand)
+        NetCommand::try_from(&obj.to_ref())
+    }
+}
+
+impl<'a> TryFrom<&ObjectRef<'a>> for NetCommand {
+    type Error = NetCommandError;
+
+    fn try_from(obj: &ObjectRef<'a>) -> Result<Self, Self::Error> {
+        object::from_object_ref(obj).map_err(|err| match err {
+            object::Error::UnknownVariant(_) => NetCommandError::NotANetCommand,
+            _ => NetCommandError::Invalid,
+        })
     }
 }
 
+#[allow(clippy::from_over_into)]
 impl Into<Object> for NetCommand {
     fn into(self) -> Object {
-        match self {
-            NetCommand::Leader(ref s) => Object::Array(vec![
-                Object::SimpleString("leader".to_string()),
-                Object::SimpleString(s.clone()),
-            ]),
+        object::to_object(&self).expect("NetCommand should always serialize")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        let args: Vec<_> = args.iter().map(|a| ObjectRef::SimpleString(a)).collect();
+        Command::try_from(&args[..])
+    }
+
+    #[test]
+    fn commands_are_sorted() {
+        for pair in COMMANDS.windows(2) {
+            assert!(
+                pair[0].name < pair[1].name,
+                "{} >= {}",
+                pair[0].name,
+                pair[1].name
+            );
         }
     }
+
+    #[test]
+    fn lookup_ignores_case() {
+        assert_eq!(lookup(b"GET").unwrap().name, "get");
+        assert_eq!(lookup(b"sEt").unwrap().name, "set");
+        assert!(lookup(b"nope").is_none());
+    }
+
+    #[test]
+    fn arity_is_checked() {
+        let args = [ObjectRef::SimpleString("GET")];
+        let err = Command::try_from(&args[..]).unwrap_err();
+        assert_eq!(err, "wrong number of arguments for 'get' command");
+
+        let args = [
+            ObjectRef::SimpleString("Get"),
+            ObjectRef::BulkString(Some(b"k")),
+        ];
+        assert!(matches!(Command::try_from(&args[..]), Ok(Command::Get(k)) if k == b"k"));
+    }
+
+    #[test]
+    fn names_and_arguments_must_be_strings() {
+        let args = [
+            ObjectRef::BulkString(Some(b"get")),
+            ObjectRef::BulkString(Some(b"\xff\x00")),
+        ];
+        assert!(matches!(Command::try_from(&args[..]), Ok(Command::Get(k)) if k == b"\xff\x00"));
+
+        let args = [ObjectRef::BulkString(None), ObjectRef::SimpleString("k")];
+        let err = Command::try_from(&args[..]).unwrap_err();
+        assert_eq!(err, "Unknown command");
+        let args = [ObjectRef::BulkString(Some(b"get")), ObjectRef::Integer(1)];
+        let err = Command::try_from(&args[..]).unwrap_err();
+        assert_eq!(err, "Unsupported type");
+        let err = Command::try_from(&[][..]).unwrap_err();
+        assert_eq!(err, "Empty array object");
+        let err = Command::try_from(Object::BulkString(Some(b"get".to_vec()))).unwrap_err();
+        assert_eq!(err, "Object is not a valid Command");
+    }
+
+    #[test]
+    fn mset_takes_pairs() {
+        let args = [
+            ObjectRef::SimpleString("MSET"),
+            ObjectRef::BulkString(Some(b"a")),
+            ObjectRef::BulkString(Some(b"1")),
+            ObjectRef::BulkString(Some(b"b")),
+        ];
+        let err = Command::try_from(&args[..]).unwrap_err();
+        assert_eq!(err, "wrong number of arguments for 'mset' command");
+
+        let cmd = Command::try_from(&args[..3]).unwrap();
+        assert!(matches!(cmd, Command::MSet(pairs) if pairs == [(b"a".to_vec(), b"1".to_vec())]));
+    }
+
+    #[test]
+    fn db_indexes_are_checked() {
+        assert!(matches!(parse(&["select", "3"]), Ok(Command::Select(3))));
+        assert!(matches!(
+            parse(&["swapdb", "0", "1"]),
+            Ok(Command::SwapDb(0, 1))
+        ));
+        let err = parse(&["select", "-1"]).unwrap_err();
+        assert_eq!(err, "DB index is out of range");
+        let err = parse(&["swapdb", "0", "x"]).unwrap_err();
+        assert_eq!(err, "Value is not an integer or out of range");
+        assert!(matches!(parse(&["flushdb", "sync"]), Ok(Command::FlushDb)));
+    }
+
+    #[test]
+    fn set_options() {
+        let cmd = parse(&["set", "k", "v", "nx", "GET", "px", "100"]).unwrap();
+        let expected = SetOptions {
+            condition: Some(Condition::Nx),
+            get: true,
+            expiry: Some(Expiry::Px(100)),
+        };
+        assert!(matches!(cmd, Command::Set(_, _, options) if options == expected));
+
+        let err = parse(&["set", "k", "v", "NX", "XX"]).unwrap_err();
+        assert_eq!(err, "syntax error");
+        let err = parse(&["set", "k", "v", "EX", "10", "KEEPTTL"]).unwrap_err();
+        assert_eq!(err, "syntax error");
+        let err = parse(&["set", "k", "v", "EX"]).unwrap_err();
+        assert_eq!(err, "syntax error");
+        let err = parse(&["set", "k", "v", "EX", "0"]).unwrap_err();
+        assert_eq!(err, "invalid expire time in 'set' command");
+        let err = parse(&["set", "k", "v", "EX", "9223372036854775807"]).unwrap_err();
+        assert_eq!(err, "invalid expire time in 'set' command");
+    }
+
+    #[test]
+    fn to_object_round_trip() {
+        let cmd = Command::Set(
+            b"drink".to_vec(),
+            b"\x00water".to_vec(),
+            SetOptions::default(),
+        );
+        let obj = cmd.to_object();
+        assert_eq!(
+            obj,
+            Object::Array(vec![
+                Object::BulkString(Some(b"set".to_vec())),
+                Object::BulkString(Some(b"drink".to_vec())),
+                Object::BulkString(Some(b"\x00water".to_vec())),
+            ])
+        );
+        assert!(matches!(
+            Command::try_from(obj),
+            Ok(Command::Set(k, v, _)) if k == b"drink" && v == b"\x00water"
+        ));
+    }
+
+    #[test]
+    fn zset_options() {
+        let cmd = parse(&["zadd", "z", "xx", "gt", "CH", "1", "a", "-inf", "b"]).unwrap();
+        let expected = ZAddOptions {
+            condition: Some(Condition::Xx),
+            comparison: Some(Comparison::Gt),
+            ch: true,
+            incr: false,
+        };
+        assert!(matches!(
+            cmd,
+            Command::ZAdd(_, options, members) if options == expected && members.len() == 2
+        ));
+        let err = parse(&["zadd", "z", "NX", "GT", "1", "a"]).unwrap_err();
+        assert_eq!(
+            err,
+            "GT, LT, and/or NX options at the same time are not compatible"
+        );
+        let err = parse(&["zadd", "z", "INCR", "1", "a", "2", "b"]).unwrap_err();
+        assert_eq!(err, "INCR option supports a single increment-element pair");
+        let err = parse(&["zadd", "z", "nan", "a"]).unwrap_err();
+        assert_eq!(err, "value is not a valid float");
+
+        // REV takes the maximum first, but the range is kept as min and max
+        let cmd = parse(&[
+            "zrange", "z", "(5", "-inf", "BYSCORE", "REV", "LIMIT", "1", "2",
+        ]);
+        let options = match cmd.unwrap() {
+            Command::ZRange(_, options) => options,
+            cmd => panic!("unexpected command {:?}", cmd),
+        };
+        let min = ScoreBound {
+            score: f64::NEG_INFINITY,
+            exclusive: false,
+        };
+        let max = ScoreBound {
+            score: 5.0,
+            exclusive: true,
+        };
+        assert_eq!(options.by, ZRangeBy::Score(min, max));
+        assert_eq!(options.limit, Some((1, 2)));
+        let cmd = Command::ZRange(b"z".to_vec(), options.clone());
+        assert!(matches!(
+            Command::try_from(cmd.to_object()),
+            Ok(Command::ZRange(_, parsed)) if parsed == options
+        ));
+
+        let err = parse(&["zrange", "z", "0", "1", "LIMIT", "0", "1"]).unwrap_err();
+        assert!(err.starts_with("syntax error, LIMIT"));
+        let err = parse(&["zrange", "z", "a", "[b", "BYLEX"]).unwrap_err();
+        assert_eq!(err, "min or max not valid string range item");
+    }
+
+    #[test]
+    fn stream_arguments() {
+        let cmd = parse(&["xadd", "s", "5-*", "f", "v"]).unwrap();
+        assert!(matches!(cmd, Command::XAdd(_, XAddId::Seq(5), _)));
+        let err = parse(&["xadd", "s", "5-x", "f", "v"]).unwrap_err();
+        assert_eq!(err, INVALID_STREAM_ID);
+        let err = parse(&["xadd", "s", "*", "f", "v", "g"]).unwrap_err();
+        assert_eq!(err, "wrong number of arguments for 'xadd' command");
+
+        // Incomplete and exclusive bounds are resolved to whole IDs
+        let cmd = parse(&["xrange", "s", "(5-1", "7", "COUNT", "2"]).unwrap();
+        assert!(matches!(
+            cmd,
+            Command::XRange(_, start, end, Some(2))
+                if start == StreamId::new(5, 2) && end == StreamId::new(7, u64::MAX)
+        ));
+        let err = parse(&[
+            "xrange",
+            "s",
+            "(18446744073709551615-18446744073709551615",
+            "+",
+        ]);
+        assert_eq!(err.unwrap_err(), "invalid start ID for the interval");
+
+        let cmd = parse(&[
+            "xread", "COUNT", "2", "block", "0", "STREAMS", "a", "b", "$", "1",
+        ]);
+        let expected = XReadOptions {
+            count: Some(2),
+            block: Some(0),
+            noack: false,
+            streams: vec![
+                (b"a".to_vec(), StreamStart::Latest),
+                (b"b".to_vec(), StreamStart::After(StreamId::new(1, 0))),
+            ],
+        };
+        let options = match cmd.unwrap() {
+            Command::XRead(options) => options,
+            cmd => panic!("unexpected command {:?}", cmd),
+        };
+        assert_eq!(options, expected);
+        assert!(matches!(
+            Command::try_from(Command::XRead(options).to_object()),
+            Ok(Command::XRead(parsed)) if parsed == expected
+        ));
+        let err = parse(&["xread", "STREAMS", "a", "b", "$"]).unwrap_err();
+        assert!(err.starts_with("Unbalanced 'xread' list of streams"));
+        let err = parse(&["xread", "STREAMS", "a", ">"]).unwrap_err();
+        assert!(err.starts_with("The > ID can be specified only"));
+        let err = parse(&["xreadgroup", "GROUP", "g", "c", "STREAMS", "a", "$"]).unwrap_err();
+        assert!(err.starts_with("The $ ID is meaningless"));
+        let err = parse(&[
+            "xreadgroup",
+            "GROUP",
+            "g",
+            "c",
+            "NOACK",
+            "BLOCK",
+            "-1",
+            "STREAMS",
+            "a",
+            ">",
+        ]);
+        assert_eq!(err.unwrap_err(), "timeout is negative");
+
+        let cmd = parse(&[
+            "xclaim",
+            "s",
+            "g",
+            "c",
+            "10",
+            "1-1",
+            "2",
+            "FORCE",
+            "RETRYCOUNT",
+            "3",
+        ]);
+        let expected = XClaimOptions {
+            retry_count: Some(3),
+            force: true,
+            ..XClaimOptions::default()
+        };
+        assert!(matches!(
+            cmd.unwrap(),
+            Command::XClaim(_, _, _, 10, ids, options) if ids.len() == 2 && options == expected
+        ));
+        let err = parse(&["xpending", "s", "g", "IDLE", "5"]).unwrap_err();
+        assert_eq!(err, "syntax error");
+        let err = parse(&["xgroup", "create", "s", "g"]).unwrap_err();
+        assert_eq!(
+            err,
+            "unknown subcommand or wrong number of arguments for 'create'"
+        );
+    }
 }
@@ -1,28 +1,147 @@
 use crate::cluster::Cluster;
-use crate::command::{Command, NetCommand};
-use crate::db::Database;
-use crate::object::parse;
-use crate::object::Object;
-use crate::server::{ServerOptions, MESSAGE_MAX_SIZE};
+use crate::command::{Command, Flag, NetCommand, StreamStart};
+use crate::db::{Database, DatabaseResponse, Watched};
+use crate::object::parse_request;
+use crate::object::{Object, ObjectRef, Protocol};
+use crate::pubsub::PubSub;
+use crate::server::{
+    propagate, propagate_expired, propagate_transaction, set_replaying, ServerOptions,
+};
 use crate::wal::Wal;
 use log::{debug, error, trace};
+use nix::libc::MSG_DONTWAIT;
 use socket2::{Domain, Socket, Type};
+use std::collections::BTreeSet;
 use std::convert::TryFrom;
 use std::error::Error;
 use std::io;
 use std::io::prelude::*;
 use std::net::SocketAddr;
 use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct Connection {
+    pub id: u64,
     pub socket: Socket,
-    pub buf: [u8; MESSAGE_MAX_SIZE],
+    pub buf: Vec<u8>,
     pub offset: usize,
     pub closed: bool,
+    out: Vec<u8>,
+    proto_max_bulk_len: usize,
+    pubsub_max_output_len: usize,
     password: String,
     mode: ConnectionMode,
+    protocol: Protocol,
+    pub blocked: Option<Blocked>,
+    channels: BTreeSet<Vec<u8>>,
+    patterns: BTreeSet<Vec<u8>>,
+    transaction: Option<Transaction>,
+    /// The index of the selected database.
+    pub selected: usize,
+    /// The index of the database of each watched key, the key, and what it
+    /// looked like when watched.
+    watched: Vec<(usize, Vec<u8>, Watched)>,
+}
+
+const INITIAL_BUFFER_SIZE: usize = 4096;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A blocking command that is waiting for one of its keys to receive a value.
+#[derive(Debug)]
+pub struct Blocked {
+    pub keys: Vec<Vec<u8>>,
+    pub deadline: Option<Instant>,
+    /// Whether the server has queued the connection on its keys yet.
+    pub queued: bool,
+    /// Whether serving the client takes what it waited for, see `is_exclusive`.
+    exclusive: bool,
+    /// The command to retry, which a `BLMOVE` replaces with its rewrite.
+    cmd: Command,
+}
+
+impl Blocked {
+    /// Describes how a command with the blocking flag blocks, or returns `None`
+    /// if this use of it does not, like `XREAD` without `BLOCK`.
+    fn new(cmd: &Command) -> Option<Self> {
+        let (keys, timeout) = match cmd {
+            Command::BPop(_, keys, timeout) => (keys.clone(), *timeout),
+            Command::BLMove(source, _, _, _, timeout) => (vec![source.clone()], *timeout),
+            Command::XRead(options) | Command::XReadGroup(_, _, options) => {
+                let block = options.block?;
+                // A group only waits for new entries, not for its history
+                if matches!(cmd, Command::XReadGroup(..))
+                    && options
+                        .streams
+                        .iter()
+                        .any(|(_, start)| *start != StreamStart::Undelivered)
+                {
+                    return None;
+                }
+                let keys = options.streams.iter().map(|(key, _)| key.clone()).collect();
+                (keys, block as f64 / 1000.0)
+            }
+            _ => unreachable!("{} is not a blocking command", cmd.name()),
+        };
+        // A timeout of 0, or one too large to represent, waits forever
+        let deadline = Duration::try_from_secs_f64(timeout)
+            .ok()
+            .filter(|timeout| !timeout.is_zero())
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        Some(Self {
+            keys,
+            deadline,
+            queued: false,
+            exclusive: matches!(cmd, Command::BPop(..) | Command::BLMove(..)),
+            cmd: cmd.clone(),
+        })
+    }
+
+    /// Whether serving the client takes what it waited for, so that once it
+    /// cannot be served nobody else waiting on the same key can either. Stream
+    /// readers do not take entries from each other.
+    pub fn is_exclusive(&self) -> bool {
+        self.exclusive
+    }
+
+    /// The command that serves the client once `key` has a value.
+    fn command_for(&self, key: &[u8]) -> Command {
+        match &self.cmd {
+            Command::BPop(end, _, _) => Command::BPop(*end, vec![key.to_vec()], 0.0),
+            cmd => cmd.clone(),
+        }
+    }
+}
+
+/// Commands of a transaction that run in one database, with the records
+/// to log for them, and the command on several databases that follows them.
+struct Run {
+    index: usize,
+    cmds: Vec<Command>,
+    records: Vec<Option<Object>>,
+    then: Option<Command>,
+}
+
+impl Run {
+    fn new(index: usize) -> Self {
+        Self {
+            index,
+            cmds: Vec::new(),
+            records: Vec::new(),
+            then: None,
+        }
+    }
+}
+
+/// The commands queued since `MULTI`.
+#[derive(Debug, Default)]
+struct Transaction {
+    cmds: Vec<Command>,
+    /// Whether a command could not be queued, which makes `EXEC` fail.
+    failed: bool,
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -45,16 +164,27 @@ impl Connection {
 
     pub fn new(socket: Socket, read_only: bool, opt: &ServerOptions) -> Self {
         Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
             socket,
-            buf: [0u8; MESSAGE_MAX_SIZE],
+            buf: vec![0u8; INITIAL_BUFFER_SIZE],
             offset: 0,
             closed: false,
+            out: Vec::new(),
+            proto_max_bulk_len: opt.proto_max_bulk_len,
+            pubsub_max_output_len: opt.pubsub_max_output_len,
             mode: if read_only {
                 ConnectionMode::Read
             } else {
                 ConnectionMode::ReadWrite
             },
             password: opt.cluster_password.clone(),
+            protocol: Protocol::Resp2,
+            blocked: None,
+            channels: BTreeSet::new(),
+            patterns: BTreeSet::new(),
+            transaction: None,
+            selected: 0,
+            watched: Vec::new(),
         }
     }
 
@@ -64,7 +194,14 @@ impl Connection {
         Ok(Connection::new(stream, opt.read_only, opt))
     }
 
+    /// Reads into the free space at the end of the buffer, doubling the buffer
+    /// first if it is full. The buffer never grows beyond `proto_max_bulk_len`.
     pub fn read(&mut self) -> io::Result<usize> {
+        if self.offset == self.buf.len() && self.buf.len() < self.proto_max_bulk_len {
+            let len = (self.buf.len() * 2).min(self.proto_max_bulk_len);
+            trace!("Growing connection buffer to {} bytes", len);
+            self.buf.resize(len, 0);
+        }
         self.socket.read(&mut self.buf[self.offset..])
     }
 
@@ -76,89 +213,624 @@ impl Connection {
         }
     }
 
+    /// The null reply of commands that otherwise reply with an array.
+    fn null_array(&self) -> Object {
+        match self.protocol {
+            Protocol::Resp2 => Object::NullArray,
+            Protocol::Resp3 => Object::Null,
+        }
+    }
+
+    fn reply(&mut self, obj: Object) {
+        let obj = match self.protocol {
+            Protocol::Resp2 => obj.into_resp2(),
+            Protocol::Resp3 => obj,
+        };
+        obj.write_to(&mut self.out)
+            .expect("Writing to a Vec should never fail");
+    }
+
+    /// Writes as much of the output as the socket takes without blocking. The
+    /// server polls for the socket to become writable while output is left.
+    /// A connection that cannot be written to is closed, rather than going on
+    /// without the output it missed.
+    pub fn flush(&mut self) {
+        while !self.out.is_empty() {
+            match self.socket.send_with_flags(&self.out, MSG_DONTWAIT) {
+                Ok(written) => {
+                    self.out.drain(..written);
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+                Err(error) => {
+                    error!("Write: {}", error);
+                    self.out.clear();
+                    self.closed = true;
+                }
+            }
+        }
+        self.out.shrink_to(INITIAL_BUFFER_SIZE);
+    }
+
+    pub fn has_output(&self) -> bool {
+        !self.out.is_empty()
+    }
+
+    /// Queues a published message. A subscriber that reads too slowly to keep
+    /// its output under the limit is disconnected instead.
+    pub fn deliver(&mut self, message: Object) {
+        self.reply(message);
+        if self.out.len() > self.pubsub_max_output_len {
+            error!(
+                "Disconnecting subscriber {} with {} bytes of output waiting",
+                self.id,
+                self.out.len()
+            );
+            self.out.clear();
+            self.closed = true;
+        }
+    }
+
+    fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// Subscribes to or unsubscribes from channels or patterns, confirming each
+    /// one with the number of subscriptions the connection is left with.
+    fn change_subscriptions(&mut self, cmd: Command, pubsub: &mut PubSub) {
+        let (names, current) = match &cmd {
+            Command::Subscribe(names) | Command::Unsubscribe(names) => (names, &self.channels),
+            Command::PSubscribe(names) | Command::PUnsubscribe(names) => (names, &self.patterns),
+            _ => unreachable!("{} is not a subscription command", cmd.name()),
+        };
+        // Unsubscribing from nothing in particular unsubscribes from everything
+        let names = match names.is_empty() {
+            true => current.iter().cloned().collect(),
+            false => names.clone(),
+        };
+        let confirm = |name: Option<Vec<u8>>, count: usize| {
+            Object::Push(vec![
+                Object::BulkString(Some(cmd.name().as_bytes().to_vec())),
+                Object::BulkString(name),
+                Object::Integer(count as i64),
+            ])
+        };
+        if names.is_empty() {
+            self.reply(confirm(None, self.subscriptions()));
+        }
+
+        for name in names {
+            match cmd {
+                Command::Subscribe(_) if self.channels.insert(name.clone()) => {
+                    pubsub.subscribe(&name, self.id)
+                }
+                Command::Unsubscribe(_) if self.channels.remove(&name) => {
+                    pubsub.unsubscribe(&name, self.id)
+                }
+                Command::PSubscribe(_) if self.patterns.insert(name.clone()) => {
+                    pubsub.psubscribe(&name, self.id)
+                }
+                Command::PUnsubscribe(_) if self.patterns.remove(&name) => {
+                    pubsub.punsubscribe(&name, self.id)
+                }
+                _ => {}
+            }
+            let confirmation = confirm(Some(name), self.subscriptions());
+            self.reply(confirmation);
+        }
+    }
+
+    /// Drops the subscriptions of a connection that is going away.
+    pub fn unsubscribe_all(&mut self, pubsub: &mut PubSub) {
+        for channel in std::mem::take(&mut self.channels) {
+            pubsub.unsubscribe(&channel, self.id);
+        }
+        for pattern in std::mem::take(&mut self.patterns) {
+            pubsub.punsubscribe(&pattern, self.id);
+        }
+    }
+
+    fn hello(&mut self, protover: Option<i64>) -> Object {
+        match protover.map(Protocol::try_from).transpose() {
+            Ok(protocol) => self.protocol = protocol.unwrap_or(self.protocol),
+            Err(_) => return Object::Error("NOPROTO unsupported protocol version".to_string()),
+        }
+        trace!("Connection protocol is {:?}", self.protocol);
+
+        let role = match self.mode {
+            ConnectionMode::Leader => "replica",
+            _ => "master",
+        };
+        let field = |name: &str| Object::SimpleString(name.to_string());
+        Object::Map(vec![
+            (field("server"), field("passage")),
+            (field("version"), field(env!("CARGO_PKG_VERSION"))),
+            (field("proto"), Object::Integer(self.protocol.into())),
+            (field("mode"), field("standalone")),
+            (field("role"), field(role)),
+            (field("modules"), Object::Array(Vec::new())),
+        ])
+    }
+
     pub fn handle_incoming_command(
         &mut self,
-        db: Arc<dyn Database>,
+        dbs: &[Arc<dyn Database>],
         wal: Arc<Wal>,
         cluster: &mut Option<Cluster>,
+        pubsub: &mut PubSub,
     ) -> Result<(), Box<dyn Error>> {
         let size = self.read()?;
         if size == 0 {
             trace!("read {} bytes", size);
+            self.closed = true;
             return Ok(());
         }
+        self.offset += size;
+        self.process_requests(dbs, &wal, cluster, pubsub);
+        Ok(())
+    }
+
+    /// Handles the complete requests in the buffer, stopping at a request that
+    /// blocks. The rest are handled once the connection is unblocked.
+    pub fn process_requests(
+        &mut self,
+        dbs: &[Arc<dyn Database>],
+        wal: &Wal,
+        cluster: &mut Option<Cluster>,
+        pubsub: &mut PubSub,
+    ) {
+        let size = self.offset;
 
-        let mut cursor = io::Cursor::new(&self.buf[..]);
+        // The buffer is taken out of the connection while its requests are handled,
+        // so that the parsed requests can borrow from it.
+        let mut buf = std::mem::take(&mut self.buf);
+        let mut cursor = io::Cursor::new(&buf[..size]);
         let mut offset = 0;
 
-        while cursor.position() < size as u64 {
-            let object = match parse(&mut cursor) {
+        while cursor.position() < size as u64 && !self.closed && self.blocked.is_none() {
+            let object = match parse_request(&mut cursor) {
                 Ok(o) => o,
-                Err(err) if matches!(err, crate::object::Error::Incomplete) => {
-                    if offset == 0 {
+                Err(crate::object::Error::Incomplete) => {
+                    if size - offset >= self.proto_max_bulk_len {
                         trace!("Max message size exceeded");
+                        self.reply(Object::Error(
+                            "ERR Protocol error: message exceeds proto-max-bulk-len".to_string(),
+                        ));
                         self.closed = true;
                     }
                     break;
                 }
                 Err(err) => {
                     error!("Parse error: {}", err);
-                    continue;
+                    self.reply(Object::Error(format!("ERR Protocol error: {}", err)));
+                    self.closed = true;
+                    break;
                 }
             };
 
-            if let Ok(net_cmd) = NetCommand::try_from(&object) {
-                trace!("Handling network command! {:?}", net_cmd);
-                match net_cmd {
-                    NetCommand::Leader(ref password) => {
-                        if password == &self.password {
-                            trace!("Connection is the leader node");
-                            self.mode = ConnectionMode::Leader;
-                        } else {
-                            trace!("Incorrect password -- not leader node");
-                            self.closed = true;
-                        }
+            // A follower applies the leader's commands as they were logged
+            let replaying = matches!(self.mode, ConnectionMode::Leader);
+            if replaying {
+                set_replaying(dbs, true);
+            }
+            self.handle_request(object, dbs, wal, cluster, pubsub);
+            if replaying {
+                set_replaying(dbs, false);
+            }
+            offset = cursor.position() as usize;
+        }
+        self.flush();
+
+        buf.copy_within(offset..size, 0);
+        self.offset = size - offset;
+
+        // Give back the memory of an unusually large message once it has been handled
+        if self.offset <= INITIAL_BUFFER_SIZE && buf.len() > INITIAL_BUFFER_SIZE {
+            buf.truncate(INITIAL_BUFFER_SIZE);
+            buf.shrink_to_fit();
+        }
+        self.buf = buf;
+    }
+
+    fn handle_request(
+        &mut self,
+        object: ObjectRef,
+        dbs: &[Arc<dyn Database>],
+        wal: &Wal,
+        cluster: &mut Option<Cluster>,
+        pubsub: &mut PubSub,
+    ) {
+        if let Ok(net_cmd) = NetCommand::try_from(&object) {
+            trace!("Handling network command! {:?}", net_cmd);
+            match net_cmd {
+                NetCommand::Leader(ref password) => {
+                    if password == &self.password {
+                        trace!("Connection is the leader node");
+                        self.mode = ConnectionMode::Leader;
+                    } else {
+                        trace!("Incorrect password -- not leader node");
+                        self.closed = true;
                     }
                 }
-            } else {
-                let cmd = match Command::try_from(object) {
-                    Ok(o) => o,
-                    Err(err) => {
-                        debug!("Invalid command: {}", err);
-                        continue;
-                    }
-                };
-                debug!("Incoming command: {:?}", cmd);
-
-                let response_buf: Vec<u8> = if cmd.possibly_dirty() && !self.write_allowed() {
-                    Object::Error("Read-only mode: Illegal command".to_string()).into()
-                } else {
-                    wal.append(&cmd).unwrap();
-                    let response = db.execute(cmd).unwrap();
-                    if response.is_dirty {
-                        let buf = &self.buf[offset..cursor.position() as usize];
-                        if let Some(cluster) = cluster {
-                            cluster.relay(buf);
+            }
+            return;
+        }
+
+        let cmd = match Command::try_from(&object) {
+            Ok(o) => o,
+            Err(err) => {
+                debug!("Invalid command: {}", err);
+                if let Some(transaction) = &mut self.transaction {
+                    transaction.failed = true;
+                }
+                self.reply(Object::Error(format!("ERR {}", err)));
+                return;
+            }
+        };
+        debug!("Incoming command: {:?}", cmd);
+
+        // Without push messages, RESP2 subscribers can only manage their
+        // subscriptions, so that replies cannot be mistaken for messages.
+        let subscribed = self.subscriptions() > 0 && self.protocol == Protocol::Resp2;
+        let response = match cmd {
+            cmd if self.transaction.is_some()
+                && !matches!(
+                    cmd,
+                    Command::Multi | Command::Exec | Command::Discard | Command::Watch(_)
+                ) =>
+            {
+                self.queue(cmd)
+            }
+            Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PSubscribe(_)
+            | Command::PUnsubscribe(_) => return self.change_subscriptions(cmd, pubsub),
+            Command::Ping(message) if subscribed => Object::Array(vec![
+                Object::BulkString(Some(b"pong".to_vec())),
+                Object::BulkString(Some(message.unwrap_or_default())),
+            ]),
+            cmd if subscribed => Object::Error(format!(
+                "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context",
+                cmd.name()
+            )),
+            Command::Multi if self.transaction.is_some() => {
+                Object::Error("ERR MULTI calls can not be nested".to_string())
+            }
+            Command::Multi => {
+                self.transaction = Some(Transaction::default());
+                Object::SimpleString("OK".to_string())
+            }
+            Command::Exec => self.exec(dbs, wal, cluster, pubsub),
+            Command::Discard => match self.transaction.take() {
+                Some(_) => {
+                    self.unwatch(dbs);
+                    Object::SimpleString("OK".to_string())
+                }
+                None => Object::Error("ERR DISCARD without MULTI".to_string()),
+            },
+            Command::Watch(_) if self.transaction.is_some() => {
+                Object::Error("ERR WATCH inside MULTI is not allowed".to_string())
+            }
+            Command::Watch(keys) => {
+                let db = &dbs[self.selected];
+                let watched = db.watch(&keys).unwrap();
+                for (key, watched) in keys.into_iter().zip(watched) {
+                    self.watched.push((self.selected, key, watched));
+                }
+                Object::SimpleString("OK".to_string())
+            }
+            Command::Select(index) if index >= dbs.len() => {
+                Object::Error("ERR DB index is out of range".to_string())
+            }
+            Command::Select(index) => {
+                self.selected = index;
+                Object::SimpleString("OK".to_string())
+            }
+            Command::Unwatch => {
+                self.unwatch(dbs);
+                Object::SimpleString("OK".to_string())
+            }
+            cmd if is_local(&cmd) => self.local_reply(cmd, pubsub),
+            cmd if cmd.possibly_dirty() && !self.write_allowed() => {
+                Object::Error("Read-only mode: Illegal command".to_string())
+            }
+            cmd @ (Command::SwapDb(_, _) | Command::FlushAll) => {
+                let record = cmd.to_object();
+                let mut response = execute_across(cmd, dbs);
+                for record in changes(&mut response, Some(record)) {
+                    let record: Vec<u8> = record.into();
+                    propagate(self.selected, &record, wal, cluster);
+                }
+                response.object
+            }
+            cmd if cmd.spec().has_flag(Flag::Blocking) => {
+                let blocked = Blocked::new(&cmd);
+                let mut response = self.execute(cmd, dbs, wal, cluster);
+                match blocked {
+                    Some(mut blocked) if response.object == Object::Null => {
+                        trace!(
+                            "Connection {} blocks on {} keys",
+                            self.id,
+                            blocked.keys.len()
+                        );
+                        if let Some(retry) = response.rewrite.pop() {
+                            blocked.cmd = retry;
                         }
+                        self.blocked = Some(blocked);
+                        return;
                     }
-                    response.object.into()
-                };
+                    _ => response.object,
+                }
+            }
+            cmd => self.execute(cmd, dbs, wal, cluster).object,
+        };
+        self.reply(response);
+    }
 
-                if let Err(error) = self.socket.write(&response_buf) {
-                    error!("Write: {}", error);
+    /// Replies to a command that does not touch the database.
+    fn local_reply(&mut self, cmd: Command, pubsub: &mut PubSub) -> Object {
+        match cmd {
+            Command::Publish(channel, message) => {
+                Object::Integer(pubsub.publish(&channel, &message) as i64)
+            }
+            Command::Hello(protover) => self.hello(protover),
+            Command::Command(ref introspection) => introspection.reply(),
+            Command::Ping(None) => Object::SimpleString("PONG".to_string()),
+            Command::Ping(Some(message)) | Command::Echo(message) => {
+                Object::BulkString(Some(message))
+            }
+            // Only queued in a transaction, whose keys are unwatched by `EXEC`
+            Command::Unwatch => Object::SimpleString("OK".to_string()),
+            cmd => unreachable!("{} is not a local command", cmd.name()),
+        }
+    }
+
+    /// Queues a command for `EXEC`. A command that could not run makes the
+    /// whole transaction fail.
+    fn queue(&mut self, cmd: Command) -> Object {
+        let error = match cmd {
+            Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PSubscribe(_)
+            | Command::PUnsubscribe(_) => Some("ERR Command not allowed inside a transaction"),
+            ref cmd if cmd.possibly_dirty() && !self.write_allowed() => {
+                Some("Read-only mode: Illegal command")
+            }
+            _ => None,
+        };
+        let transaction = self.transaction.as_mut().unwrap();
+        match error {
+            Some(error) => {
+                transaction.failed = true;
+                Object::Error(error.to_string())
+            }
+            None => {
+                transaction.cmds.push(cmd);
+                Object::SimpleString("QUEUED".to_string())
+            }
+        }
+    }
+
+    /// Runs the queued commands as one unit, unless a watched key changed, and
+    /// propagates their changes together.
+    fn exec(
+        &mut self,
+        dbs: &[Arc<dyn Database>],
+        wal: &Wal,
+        cluster: &mut Option<Cluster>,
+        pubsub: &mut PubSub,
+    ) -> Object {
+        let transaction = match self.transaction.take() {
+            Some(transaction) => transaction,
+            None => return Object::Error("ERR EXEC without MULTI".to_string()),
+        };
+        if transaction.failed {
+            self.unwatch(dbs);
+            return Object::Error(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            );
+        }
+
+        // Each database runs the commands selected for it in one go, and the
+        // others are answered in between by position.
+        let mut local = Vec::new();
+        let mut runs = vec![Run::new(self.selected)];
+        for cmd in transaction.cmds {
+            let run = runs.last_mut().unwrap();
+            match cmd {
+                Command::Select(index) if index < dbs.len() && index != run.index => {
+                    if run.cmds.is_empty() {
+                        run.index = index;
+                    } else {
+                        runs.push(Run::new(index));
+                    }
+                    local.push(Some(cmd));
+                }
+                cmd if is_local(&cmd) || matches!(cmd, Command::Select(_)) => local.push(Some(cmd)),
+                Command::SwapDb(_, _) | Command::FlushAll => {
+                    local.push(None);
+                    let index = run.index;
+                    run.then = Some(cmd);
+                    runs.push(Run::new(index));
+                }
+                cmd => {
+                    local.push(None);
+                    run.records
+                        .push(cmd.possibly_dirty().then(|| cmd.to_object()));
+                    run.cmds.push(cmd);
                 }
             }
-            offset = cursor.position() as usize;
         }
+        // Nothing else runs until the transaction is done, so the watched keys
+        // can all be checked before it starts.
+        for (index, db) in dbs.iter().enumerate() {
+            let keys: Vec<(Vec<u8>, Watched)> = self
+                .watched
+                .iter()
+                .filter(|(watched_index, _, _)| *watched_index == index)
+                .map(|(_, key, watched)| (key.clone(), *watched))
+                .collect();
+            if !keys.is_empty() && db.execute_transaction(Vec::new(), &keys).unwrap().is_none() {
+                self.unwatch(dbs);
+                return self.null_array();
+            }
+        }
+        self.unwatch(dbs);
 
-        if offset < size {
-            self.buf.rotate_left(offset);
-            self.offset = size - offset;
-        } else {
-            self.offset = 0;
+        let mut responses = Vec::new();
+        let mut records = Vec::new();
+        for run in runs {
+            let index = run.index;
+            let run_responses = dbs[index]
+                .execute_transaction(run.cmds, &[])
+                .unwrap()
+                .expect("Nothing is watched");
+            for (mut response, record) in run_responses.into_iter().zip(run.records) {
+                let changes = changes(&mut response, record);
+                records.extend(changes.into_iter().map(|record| (index, record)));
+                responses.push(response.object);
+            }
+            if let Some(cmd) = run.then {
+                let record = cmd.to_object();
+                let mut response = execute_across(cmd, dbs);
+                let changes = changes(&mut response, Some(record));
+                records.extend(changes.into_iter().map(|record| (index, record)));
+                responses.push(response.object);
+            }
+            self.selected = index;
         }
+        propagate_transaction(records, wal, cluster);
 
-        Ok(())
+        let mut responses = responses.into_iter();
+        let mut replies = Vec::new();
+        for cmd in local {
+            replies.push(match cmd {
+                Some(Command::Select(index)) if index >= dbs.len() => {
+                    Object::Error("ERR DB index is out of range".to_string())
+                }
+                Some(Command::Select(_)) => Object::SimpleString("OK".to_string()),
+                Some(cmd) => self.local_reply(cmd, pubsub),
+                None => responses.next().unwrap(),
+            });
+        }
+        Object::Array(replies)
+    }
+
+    /// Stops watching keys, which happens after every `EXEC` or `DISCARD`.
+    pub fn unwatch(&mut self, dbs: &[Arc<dyn Database>]) {
+        let watched = std::mem::take(&mut self.watched);
+        for (index, db) in dbs.iter().enumerate() {
+            let keys: Vec<Vec<u8>> = watched
+                .iter()
+                .filter(|(watched_index, _, _)| *watched_index == index)
+                .map(|(_, key, _)| key.clone())
+                .collect();
+            if !keys.is_empty() {
+                db.unwatch(&keys).unwrap();
+            }
+        }
+    }
+
+    /// Executes a command and propagates its changes. The response keeps its
+    /// rewrite only if nothing changed.
+    fn execute(
+        &mut self,
+        cmd: Command,
+        dbs: &[Arc<dyn Database>],
+        wal: &Wal,
+        cluster: &mut Option<Cluster>,
+    ) -> DatabaseResponse {
+        let db = &dbs[self.selected];
+        let record = cmd.possibly_dirty().then(|| cmd.to_object());
+        let mut response = db.execute(cmd).unwrap();
+        // Keys the command found expired are deleted before it is applied
+        if self.write_allowed() {
+            propagate_expired(self.selected, &**db, wal, cluster);
+        }
+        // A command rewritten as several is applied as a whole on replay too
+        let records = changes(&mut response, record)
+            .into_iter()
+            .map(|record| (self.selected, record))
+            .collect();
+        propagate_transaction(records, wal, cluster);
+        response
+    }
+
+    /// Retries the blocked command now that `key` has received a value, and
+    /// returns what the connection was blocked on if that unblocked it.
+    pub fn serve_blocked(
+        &mut self,
+        key: &[u8],
+        dbs: &[Arc<dyn Database>],
+        wal: &Wal,
+        cluster: &mut Option<Cluster>,
+    ) -> Option<Blocked> {
+        let cmd = self.blocked.as_ref()?.command_for(key);
+        let response = self.execute(cmd, dbs, wal, cluster).object;
+        if response == Object::Null {
+            return None;
+        }
+        self.reply(response);
+        self.flush();
+        self.blocked.take()
+    }
+
+    /// Unblocks the connection with a null reply.
+    pub fn time_out(&mut self) {
+        self.blocked = None;
+        self.reply(self.null_array());
+        self.flush();
+    }
+}
+
+/// Whether the connection answers a command itself instead of the database.
+fn is_local(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::Publish(..)
+            | Command::Hello(_)
+            | Command::Command(_)
+            | Command::Ping(_)
+            | Command::Echo(_)
+            | Command::Unwatch
+    )
+}
+
+/// The records to log and relay for an executed command, given how it was
+/// sent if it may write. Only commands that changed something are logged and
+/// relayed, in their rewritten form if the database asked for one, which is
+/// taken out of the response.
+/// Executes one of the commands that span databases, `SWAPDB` or `FLUSHALL`.
+/// Connections that selected a swapped database see the other one's keys.
+fn execute_across(cmd: Command, dbs: &[Arc<dyn Database>]) -> DatabaseResponse {
+    match cmd {
+        Command::SwapDb(first, second) if first >= dbs.len() || second >= dbs.len() => {
+            DatabaseResponse::clean(Object::Error("ERR DB index is out of range".to_string()))
+        }
+        Command::SwapDb(first, second) => {
+            if first != second {
+                dbs[first].swap(&*dbs[second]).unwrap();
+            }
+            DatabaseResponse::dirty(Object::SimpleString("OK".to_string()))
+        }
+        Command::FlushAll => {
+            for db in dbs {
+                db.execute(Command::FlushAll).unwrap();
+            }
+            DatabaseResponse::dirty(Object::SimpleString("OK".to_string()))
+        }
+        cmd => unreachable!("{} runs in one database", cmd.name()),
+    }
+}
+
+fn changes(response: &mut DatabaseResponse, record: Option<Object>) -> Vec<Object> {
+    if !response.is_dirty {
+        return Vec::new();
+    }
+    let rewrite = std::mem::take(&mut response.rewrite);
+    match rewrite.is_empty() {
+        true => record.into_iter().collect(),
+        false => rewrite.iter().map(Command::to_object).collect(),
     }
 }
 
@@ -1,26 +1,102 @@
-use crate::command::Command;
+mod hash;
+mod list;
+mod set;
+mod stream;
+mod zset;
+
+use crate::command::{Command, Condition, Expiry, KeyValue, ScanOptions, SetOptions, XGroup};
+use crate::dict::Dict;
+use crate::glob;
 use crate::object::Object;
-use std::collections::HashMap;
+use crate::stream::Stream;
+use crate::zset::ZSet;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::fmt::Display;
-use std::string::FromUtf8Error;
 use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub trait Database: Send + Sync {
-    fn execute(&self, cmd: Command) -> DbResult<DatabaseResponse>;
+    fn execute(&self, cmd: Command) -> DbResult<'_, DatabaseResponse>;
+
+    /// Removes up to `limit` expired keys and returns how many were removed.
+    fn expire_keys(&self, limit: usize) -> DbResult<'_, usize>;
+
+    /// Takes the keys that were removed because they expired, either by
+    /// `expire_keys` or lazily while executing a command. They have to be
+    /// logged and relayed as deletes.
+    fn take_expired(&self) -> DbResult<'_, Vec<Vec<u8>>>;
+
+    /// Takes the keys that received values that blocked clients may be
+    /// waiting for since the last call.
+    fn take_ready(&self) -> DbResult<'_, Vec<Vec<u8>>>;
+
+    /// Starts watching the keys for changes and returns what each one looks
+    /// like so far, for `execute_transaction` to compare.
+    fn watch(&self, keys: &[Vec<u8>]) -> DbResult<'_, Vec<Watched>>;
+
+    /// Stops watching keys, once for every time they were watched.
+    fn unwatch(&self, keys: &[Vec<u8>]) -> DbResult<'_, ()>;
+
+    /// Executes the commands one after the other, without letting anyone see
+    /// the database in between, unless one of the watched keys changed since
+    /// `watch` returned the given state for it. Returns `None` in that case.
+    fn execute_transaction(
+        &self,
+        cmds: Vec<Command>,
+        watched: &[(Vec<u8>, Watched)],
+    ) -> DbResult<'_, Option<Vec<DatabaseResponse>>>;
+
+    /// Swaps the keys with another database, which must be a different one.
+    /// Whoever watches keys in either of them sees them all change.
+    fn swap(&self, other: &dyn Database) -> DbResult<'_, ()>;
+
+    /// Swaps the keys with those of a keyspace, for `swap`.
+    fn swap_keys(&self, keyspace: &mut Keyspace) -> DbResult<'_, ()>;
+
+    /// Sets whether logged commands are being applied, as during a replay or
+    /// on a follower. Keys then only expire through the logged deletes.
+    fn set_replaying(&self, replaying: bool) -> DbResult<'_, ()>;
 }
 
 pub struct DatabaseResponse {
     pub object: Object,
     pub is_dirty: bool,
+    /// The commands to log and relay in place of the executed one, for commands
+    /// whose outcome would not be reproduced by replaying them as they were sent.
+    /// A blocking command that found nothing uses it for the command to retry
+    /// once it is woken up instead.
+    pub rewrite: Vec<Command>,
+}
+
+impl DatabaseResponse {
+    pub fn clean(object: Object) -> Self {
+        Self {
+            object,
+            is_dirty: false,
+            rewrite: Vec::new(),
+        }
+    }
+
+    pub fn dirty(object: Object) -> Self {
+        Self {
+            object,
+            is_dirty: true,
+            rewrite: Vec::new(),
+        }
+    }
+
+    pub fn rewritten(mut self, cmd: Command) -> Self {
+        self.rewrite.push(cmd);
+        self
+    }
 }
 
 pub type DbResult<'a, T> = Result<T, DbError<'a>>;
 
 #[derive(Debug)]
 pub enum DbError<'a> {
-    ReadLock(PoisonError<RwLockReadGuard<'a, HashMap<Object, Object>>>),
-    WriteLock(PoisonError<RwLockWriteGuard<'a, HashMap<Object, Object>>>),
-    Utf8(FromUtf8Error),
+    ReadLock(PoisonError<RwLockReadGuard<'a, Keyspace>>),
+    WriteLock(PoisonError<RwLockWriteGuard<'a, Keyspace>>),
 }
 
 impl<'a> Display for DbError<'a> {
@@ -28,88 +104,1827 @@ impl<'a> Display for DbError<'a> {
         match self {
             DbError::ReadLock(inner) => write!(f, "{}", inner),
             DbError::WriteLock(inner) => write!(f, "{}", inner),
-            DbError::Utf8(inner) => write!(f, "{}", inner),
         }
     }
 }
 
-impl<'a> From<PoisonError<RwLockReadGuard<'a, HashMap<Object, Object>>>> for DbError<'a> {
-    fn from(err: PoisonError<RwLockReadGuard<'a, HashMap<Object, Object>>>) -> Self {
+impl<'a> From<PoisonError<RwLockReadGuard<'a, Keyspace>>> for DbError<'a> {
+    fn from(err: PoisonError<RwLockReadGuard<'a, Keyspace>>) -> Self {
         DbError::ReadLock(err)
     }
 }
 
-impl<'a> From<PoisonError<RwLockWriteGuard<'a, HashMap<Object, Object>>>> for DbError<'a> {
-    fn from(err: PoisonError<RwLockWriteGuard<'a, HashMap<Object, Object>>>) -> Self {
+impl<'a> From<PoisonError<RwLockWriteGuard<'a, Keyspace>>> for DbError<'a> {
+    fn from(err: PoisonError<RwLockWriteGuard<'a, Keyspace>>) -> Self {
         DbError::WriteLock(err)
     }
 }
 
-impl<'a> From<FromUtf8Error> for DbError<'a> {
-    fn from(err: FromUtf8Error) -> Self {
-        DbError::Utf8(err)
+/// A value stored in the database.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Hash(Dict<Vec<u8>>),
+    Set(Dict<()>),
+    ZSet(ZSet),
+    Stream(Stream),
+}
+
+impl Value {
+    /// The name `TYPE` reports for the value.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Hash(_) => "hash",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Stream(_) => "stream",
+        }
     }
+
+    pub fn to_object(&self) -> Object {
+        let bulk = |bytes: &Vec<u8>| Object::BulkString(Some(bytes.clone()));
+        match self {
+            Value::String(string) => bulk(string),
+            Value::List(list) => Object::Array(list.iter().map(bulk).collect()),
+            Value::Hash(hash) => Object::Map(
+                hash.iter()
+                    .map(|(field, value)| (bulk(&field.to_vec()), bulk(value)))
+                    .collect(),
+            ),
+            Value::Set(set) => Object::Set(
+                set.iter()
+                    .map(|(member, _)| bulk(&member.to_vec()))
+                    .collect(),
+            ),
+            Value::ZSet(zset) => Object::Array(
+                zset.iter()
+                    .flat_map(|(member, score)| [bulk(&member.to_vec()), Object::Double(score)])
+                    .collect(),
+            ),
+            Value::Stream(stream) => Object::Array(
+                stream
+                    .entries
+                    .iter()
+                    .map(|(id, fields)| stream::entry(*id, Some(fields)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// The keys of a database and the unix times, in milliseconds, at which some
+/// of them expire. Expired keys are hidden from lookups until they are removed,
+/// either lazily by a write that touches them or by `expire_due`.
+#[derive(Debug, Default)]
+pub struct Keyspace {
+    entries: Dict<Value>,
+    expires: HashMap<Vec<u8>, i64>,
+    /// The keys with an expiry time, ordered by it.
+    deadlines: BTreeSet<(i64, Vec<u8>)>,
+    /// Keys removed because they expired, which have not been logged yet.
+    expired: Vec<Vec<u8>>,
+    /// Keys that received values since the last `take_ready`.
+    ready: Vec<Vec<u8>>,
+    watched: HashMap<Vec<u8>, Watch>,
+    /// The version of each key: the number of the change that last wrote it.
+    versions: HashMap<Vec<u8>, u64>,
+    /// How many changes were made, counting every logged command.
+    last_version: u64,
+    /// Whether a log is being applied, during which keys only expire through logged deletes.
+    replaying: bool,
 }
 
+/// How many connections watch a key, and how many times it changed since the
+/// first of them started.
+#[derive(Debug, Default)]
+struct Watch {
+    watchers: usize,
+    changes: u64,
+}
+
+/// What a connection saw of a key when it started watching it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watched {
+    changes: u64,
+    version: Option<u64>,
+}
+
+impl Keyspace {
+    fn is_expired(&self, key: &[u8], now: i64) -> bool {
+        self.expires
+            .get(key)
+            .is_some_and(|&at| self.is_due(at, now))
+    }
+
+    fn is_due(&self, at: i64, now: i64) -> bool {
+        !self.replaying && at <= now
+    }
+
+    pub fn get(&self, key: &[u8], now: i64) -> Option<&Value> {
+        if self.is_expired(key, now) {
+            return None;
+        }
+        self.entries.get(key)
+    }
+
+    pub fn contains_key(&self, key: &[u8], now: i64) -> bool {
+        self.get(key, now).is_some()
+    }
+
+    /// Like `get`, but removes the key first if it has expired.
+    pub fn get_mut(&mut self, key: &[u8], now: i64) -> Option<&mut Value> {
+        if self.is_expired(key, now) {
+            self.remove(key);
+            self.touch(key);
+            self.push_expired(key.to_vec());
+        }
+        self.entries.get_mut(key)
+    }
+
+    /// Like `get_mut`, but inserts the value returned by `default` if the key
+    /// does not exist.
+    pub fn get_or_insert_with(
+        &mut self,
+        key: &[u8],
+        now: i64,
+        default: impl FnOnce() -> Value,
+    ) -> &mut Value {
+        if self.get_mut(key, now).is_none() {
+            self.insert(key.to_vec(), default(), None);
+        }
+        self.entries.get_mut(key).unwrap()
+    }
+
+    /// Stores `value` under `key`, replacing its expiry time with `expires_at`.
+    pub fn insert(&mut self, key: Vec<u8>, value: Value, expires_at: Option<i64>) {
+        self.set_expiry(&key, expires_at);
+        self.entries.insert(key, value);
+    }
+
+    /// Stores `value` under `key` without touching its expiry time. Callers
+    /// must have removed the key already if it has expired.
+    pub fn insert_keep_ttl(&mut self, key: Vec<u8>, value: Value) {
+        self.entries.insert(key, value);
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Value> {
+        self.set_expiry(key, None);
+        self.versions.remove(key);
+        self.entries.remove(key)
+    }
+
+    /// The number of keys, including expired keys that have not been removed.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Removes every key. Keys that expired earlier are still reported by
+    /// `take_expired`.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.expires.clear();
+        self.deadlines.clear();
+        self.versions.clear();
+        self.touch_all();
+    }
+
+    /// Exchanges the keys with another keyspace. Watchers, blocked clients and
+    /// keys waiting to be logged stay with the keyspace they belong to, and
+    /// the clients blocked on keys that came from the other one are woken up.
+    pub fn swap_keys(&mut self, other: &mut Keyspace) {
+        std::mem::swap(&mut self.entries, &mut other.entries);
+        std::mem::swap(&mut self.expires, &mut other.expires);
+        std::mem::swap(&mut self.deadlines, &mut other.deadlines);
+        std::mem::swap(&mut self.versions, &mut other.versions);
+        // Versions never go back, even for keys that came from the other one
+        let last_version = self.last_version.max(other.last_version);
+        self.last_version = last_version;
+        other.last_version = last_version;
+        self.touch_all();
+        other.touch_all();
+        self.signal_all_ready();
+        other.signal_all_ready();
+    }
+
+    /// Signals every key that blocking commands can wait on.
+    fn signal_all_ready(&mut self) {
+        let keys: Vec<Vec<u8>> = self
+            .entries
+            .iter()
+            .filter(|(_, value)| matches!(value, Value::List(_) | Value::Stream(_)))
+            .map(|(key, _)| key.to_vec())
+            .collect();
+        for key in keys {
+            self.signal_ready(&key);
+        }
+    }
+
+    pub fn expires_at(&self, key: &[u8]) -> Option<i64> {
+        self.expires.get(key).copied()
+    }
+
+    pub fn set_expiry(&mut self, key: &[u8], expires_at: Option<i64>) {
+        let old = match expires_at {
+            Some(at) => {
+                self.deadlines.insert((at, key.to_vec()));
+                self.expires.insert(key.to_vec(), at)
+            }
+            None => self.expires.remove(key),
+        };
+        if let Some(old) = old.filter(|&old| Some(old) != expires_at) {
+            self.deadlines.remove(&(old, key.to_vec()));
+        }
+    }
+
+    /// Removes up to `limit` keys whose expiry time is not after `now`, earliest
+    /// first, and returns how many were removed.
+    pub fn expire_due(&mut self, now: i64, limit: usize) -> usize {
+        let mut count = 0;
+        while count < limit {
+            match self.deadlines.first() {
+                Some((at, _)) if *at <= now => {}
+                _ => break,
+            }
+            let (_, key) = self.deadlines.pop_first().unwrap();
+            self.expires.remove(&key);
+            self.entries.remove(&key);
+            self.versions.remove(&key);
+            self.touch(&key);
+            self.push_expired(key);
+            count += 1;
+        }
+        count
+    }
+
+    /// Scans one bucket of keys, skipping expired keys. See `Dict::scan`.
+    pub fn scan(&self, cursor: u64, now: i64, mut f: impl FnMut(&[u8])) -> u64 {
+        self.entries.scan(cursor, |key, _| {
+            if !self.is_expired(key, now) {
+                f(key)
+            }
+        })
+    }
+
+    pub fn keys(&self, now: i64) -> impl Iterator<Item = &[u8]> {
+        self.entries
+            .iter()
+            .map(|(key, _)| key)
+            .filter(move |key| !self.is_expired(key, now))
+    }
+
+    pub fn signal_ready(&mut self, key: &[u8]) {
+        if !self.ready.iter().any(|ready| ready == key) {
+            self.ready.push(key.to_vec());
+        }
+    }
+
+    pub fn take_ready(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.ready)
+    }
+
+    /// Takes the keys that expired since the last call.
+    pub fn take_expired(&mut self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.expired)
+    }
+
+    /// Expired keys are logged as one delete, which is a change of its own.
+    fn push_expired(&mut self, key: Vec<u8>) {
+        if self.expired.is_empty() {
+            self.last_version += 1;
+        }
+        self.expired.push(key);
+    }
+
+    /// The version of a key, if it exists.
+    pub fn version(&self, key: &[u8], now: i64) -> Option<u64> {
+        self.get(key, now)?;
+        Some(self.versions.get(key).copied().unwrap_or(0))
+    }
+
+    /// Records a change that is logged as a single command, which gives the
+    /// keys it wrote a new version.
+    pub fn record_change<K: AsRef<[u8]>>(&mut self, keys: &[K]) {
+        self.last_version += 1;
+        for key in keys {
+            let key = key.as_ref();
+            if self.entries.get(key).is_some() {
+                self.versions.insert(key.to_vec(), self.last_version);
+            }
+            self.touch(key);
+        }
+    }
+
+    /// Records a change to a key, for those watching it.
+    pub fn touch(&mut self, key: &[u8]) {
+        if let Some(watch) = self.watched.get_mut(key) {
+            watch.changes += 1;
+        }
+    }
+
+    fn touch_all(&mut self) {
+        for watch in self.watched.values_mut() {
+            watch.changes += 1;
+        }
+    }
+
+    /// Starts watching a key. A key that expires while it is watched changes
+    /// its version, even where it is not removed yet.
+    pub fn watch(&mut self, key: &[u8], now: i64) -> Watched {
+        self.watched.entry(key.to_vec()).or_default().watchers += 1;
+        self.watched(key, now).unwrap()
+    }
+
+    pub fn unwatch(&mut self, key: &[u8]) {
+        if let Some(watch) = self.watched.get_mut(key) {
+            watch.watchers -= 1;
+            if watch.watchers == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+
+    fn watched(&self, key: &[u8], now: i64) -> Option<Watched> {
+        let watch = self.watched.get(key)?;
+        Some(Watched {
+            changes: watch.changes,
+            version: self.version(key, now),
+        })
+    }
+}
+
+/// The current unix time in milliseconds.
+pub fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+#[derive(Default)]
 pub struct HashMapDatabase {
-    db: RwLock<HashMap<Object, Object>>,
+    db: RwLock<Keyspace>,
 }
 
 impl HashMapDatabase {
     pub fn new() -> Self {
-        Self {
-            db: RwLock::new(HashMap::new()),
+        Self::default()
+    }
+
+    fn get(&self, key: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        Ok(match self.db.read()?.get(&key, now_ms()) {
+            Some(Value::String(value)) => {
+                DatabaseResponse::clean(Object::BulkString(Some(value.clone())))
+            }
+            Some(_) => wrong_type(),
+            None => DatabaseResponse::clean(Object::Null),
+        })
+    }
+
+    fn set(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        options: SetOptions,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        let old = match db.get_mut(&key, now) {
+            Some(Value::String(old)) => Some(Object::BulkString(Some(old.clone()))),
+            Some(_) if options.get => return Ok(wrong_type()),
+            Some(_) => Some(Object::Null),
+            None => None,
+        };
+        let allowed = match options.condition {
+            Some(Condition::Nx) => old.is_none(),
+            Some(Condition::Xx) => old.is_some(),
+            None => true,
+        };
+        let reply = match (options.get, allowed) {
+            (true, _) => old.unwrap_or(Object::Null),
+            (false, true) => Object::SimpleString("OK".to_string()),
+            (false, false) => Object::Null,
+        };
+        if !allowed {
+            return Ok(DatabaseResponse::clean(reply));
         }
+
+        let stored = Value::String(value.clone());
+        let expiry = match options.expiry {
+            Some(Expiry::KeepTtl) => {
+                db.insert_keep_ttl(key.clone(), stored);
+                Some(Expiry::KeepTtl)
+            }
+            Some(expiry) => match expiry.deadline(now) {
+                Some(at) => {
+                    db.insert(key.clone(), stored, Some(at));
+                    Some(Expiry::PxAt(at))
+                }
+                None => return Ok(error("ERR invalid expire time in 'set' command")),
+            },
+            None => {
+                db.insert(key.clone(), stored, None);
+                None
+            }
+        };
+
+        // The log gets the outcome: an unconditional SET with an absolute expiry
+        let options = SetOptions {
+            expiry,
+            ..SetOptions::default()
+        };
+        Ok(DatabaseResponse::dirty(reply).rewritten(Command::Set(key, value, options)))
     }
 
-    fn get(&self, key: Vec<u8>) -> DbResult<DatabaseResponse> {
-        let key = Object::SimpleString(String::from_utf8(key)?);
-        let old = self
-            .db
-            .read()?
-            .get(&key)
-            .cloned()
-            .unwrap_or(Object::BulkString(None));
-        Ok(DatabaseResponse {
-            object: old,
-            is_dirty: false,
+    fn getv(&self, key: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let db = self.db.read()?;
+        Ok(match db.get(&key, now) {
+            Some(Value::String(value)) => {
+                let version = db.version(&key, now).unwrap();
+                DatabaseResponse::clean(Object::Array(vec![
+                    Object::BulkString(Some(value.clone())),
+                    Object::Integer(version as i64),
+                ]))
+            }
+            Some(_) => wrong_type(),
+            None => DatabaseResponse::clean(Object::Null),
         })
     }
 
-    fn set(&self, key: Vec<u8>, value: Object) -> DbResult<DatabaseResponse> {
-        let key = Object::SimpleString(String::from_utf8(key)?);
-        let old = self
-            .db
-            .write()?
-            .insert(key, value)
-            .unwrap_or(Object::BulkString(None));
+    fn cas(&self, key: Vec<u8>, version: u64, value: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        match db.get_mut(&key, now) {
+            Some(Value::String(_)) | None => {}
+            Some(_) => return Ok(wrong_type()),
+        }
+        if db.version(&key, now).unwrap_or(0) != version {
+            return Ok(DatabaseResponse::clean(Object::Null));
+        }
+        db.insert(key.clone(), Value::String(value.clone()), None);
+        Ok(
+            DatabaseResponse::dirty(Object::SimpleString("OK".to_string()))
+                .rewritten(Command::Set(key, value, SetOptions::default())),
+        )
+    }
+
+    fn cad(&self, key: Vec<u8>, version: u64) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        match db.get_mut(&key, now) {
+            Some(Value::String(_)) | None => {}
+            Some(_) => return Ok(wrong_type()),
+        }
+        if db.version(&key, now) != Some(version) {
+            return Ok(DatabaseResponse::clean(Object::Integer(0)));
+        }
+        db.remove(&key);
+        Ok(DatabaseResponse::dirty(Object::Integer(1)).rewritten(Command::Del(vec![key])))
+    }
+
+    fn mget(&self, keys: Vec<Vec<u8>>) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let db = self.db.read()?;
+        let values = keys
+            .iter()
+            .map(|key| match db.get(key, now) {
+                Some(Value::String(value)) => Object::BulkString(Some(value.clone())),
+                _ => Object::Null,
+            })
+            .collect();
+        Ok(DatabaseResponse::clean(Object::Array(values)))
+    }
+
+    fn mset(&self, pairs: Vec<KeyValue>) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        for (key, value) in pairs {
+            db.insert(key, Value::String(value), None);
+        }
+        Ok(DatabaseResponse::dirty(Object::SimpleString(
+            "OK".to_string(),
+        )))
+    }
+
+    fn msetnx(&self, pairs: Vec<KeyValue>) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        // Expired keys are removed, so that they are logged as deleted first
+        if pairs.iter().any(|(key, _)| db.get_mut(key, now).is_some()) {
+            return Ok(DatabaseResponse::clean(Object::Integer(0)));
+        }
+        for (key, value) in pairs {
+            db.insert(key, Value::String(value), None);
+        }
+        Ok(DatabaseResponse::dirty(Object::Integer(1)))
+    }
+
+    fn remove(&self, key: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        let old = match db.get_mut(&key, now) {
+            Some(_) => db.remove(&key),
+            None => None,
+        };
         Ok(DatabaseResponse {
-            object: old,
-            is_dirty: true,
+            is_dirty: old.is_some(),
+            object: old.map_or(Object::Null, |old| old.to_object()),
+            rewrite: Vec::new(),
         })
     }
 
-    fn remove(&self, key: Vec<u8>) -> DbResult<DatabaseResponse> {
-        let key = Object::SimpleString(String::from_utf8(key)?);
-        let old = self
-            .db
-            .write()?
-            .remove(&key)
-            .unwrap_or(Object::BulkString(None));
-        let is_dirty = old != Object::BulkString(None);
+    fn expire(&self, key: Vec<u8>, expiry: Expiry) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let at = match expiry.deadline(now) {
+            Some(at) => at,
+            None => {
+                let name = Command::Expire(Vec::new(), expiry).name();
+                let message = format!("ERR invalid expire time in '{}' command", name);
+                return Ok(DatabaseResponse::clean(Object::Error(message)));
+            }
+        };
+        let mut db = self.db.write()?;
+        if db.get_mut(&key, now).is_none() {
+            return Ok(DatabaseResponse::clean(Object::Integer(0)));
+        }
+        if db.is_due(at, now) {
+            db.remove(&key);
+            return Ok(
+                DatabaseResponse::dirty(Object::Integer(1)).rewritten(Command::Del(vec![key]))
+            );
+        }
+        db.set_expiry(&key, Some(at));
+        Ok(DatabaseResponse::dirty(Object::Integer(1))
+            .rewritten(Command::Expire(key, Expiry::PxAt(at))))
+    }
+
+    fn ttl(&self, key: Vec<u8>, millis: bool) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let db = self.db.read()?;
+        let ttl = match (db.contains_key(&key, now), db.expires_at(&key)) {
+            (false, _) => -2,
+            (true, None) => -1,
+            (true, Some(at)) if millis => at - now,
+            (true, Some(at)) => (at - now + 500) / 1000,
+        };
+        Ok(DatabaseResponse::clean(Object::Integer(ttl)))
+    }
+
+    fn persist(&self, key: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        if db.get_mut(&key, now).is_none() || db.expires_at(&key).is_none() {
+            return Ok(DatabaseResponse::clean(Object::Integer(0)));
+        }
+        db.set_expiry(&key, None);
+        Ok(DatabaseResponse::dirty(Object::Integer(1)))
+    }
+
+    fn exists(&self, keys: Vec<Vec<u8>>) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let db = self.db.read()?;
+        let count = keys.iter().filter(|key| db.contains_key(key, now)).count();
+        Ok(DatabaseResponse::clean(Object::Integer(count as i64)))
+    }
+
+    fn del(&self, keys: Vec<Vec<u8>>) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        let mut count = 0;
+        for key in &keys {
+            if db.get_mut(key, now).is_some() {
+                db.remove(key);
+                count += 1;
+            }
+        }
         Ok(DatabaseResponse {
-            object: old,
-            is_dirty,
+            object: Object::Integer(count),
+            is_dirty: count > 0,
+            rewrite: Vec::new(),
         })
     }
+
+    fn type_of(&self, key: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        let name = match self.db.read()?.get(&key, now_ms()) {
+            Some(value) => value.type_name(),
+            None => "none",
+        };
+        Ok(DatabaseResponse::clean(Object::SimpleString(
+            name.to_string(),
+        )))
+    }
+
+    fn rename(
+        &self,
+        source: Vec<u8>,
+        destination: Vec<u8>,
+        nx: bool,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        if db.get_mut(&source, now).is_none() {
+            return Ok(error("ERR no such key"));
+        }
+        if nx && db.get_mut(&destination, now).is_some() {
+            return Ok(DatabaseResponse::clean(Object::Integer(0)));
+        }
+        if source != destination {
+            let expires_at = db.expires_at(&source);
+            let value = db.remove(&source).unwrap();
+            db.signal_ready(&destination);
+            db.insert(destination, value, expires_at);
+        }
+        let reply = match nx {
+            true => Object::Integer(1),
+            false => Object::SimpleString("OK".to_string()),
+        };
+        Ok(DatabaseResponse::dirty(reply))
+    }
+
+    fn copy(
+        &self,
+        source: Vec<u8>,
+        destination: Vec<u8>,
+        replace: bool,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        let value = match db.get_mut(&source, now) {
+            Some(value) => value.clone(),
+            None => return Ok(DatabaseResponse::clean(Object::Integer(0))),
+        };
+        if source == destination || (!replace && db.get_mut(&destination, now).is_some()) {
+            return Ok(DatabaseResponse::clean(Object::Integer(0)));
+        }
+        let expires_at = db.expires_at(&source);
+        db.signal_ready(&destination);
+        db.insert(destination, value, expires_at);
+        Ok(DatabaseResponse::dirty(Object::Integer(1)))
+    }
+
+    fn dbsize(&self) -> DbResult<'_, DatabaseResponse> {
+        let len = self.db.read()?.len();
+        Ok(DatabaseResponse::clean(Object::Integer(len as i64)))
+    }
+
+    fn flushall(&self) -> DbResult<'_, DatabaseResponse> {
+        self.db.write()?.clear();
+        Ok(DatabaseResponse::dirty(Object::SimpleString(
+            "OK".to_string(),
+        )))
+    }
+
+    fn scan(&self, options: ScanOptions) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let db = self.db.read()?;
+        let (cursor, keys) = scan(options, |cursor, f| {
+            db.scan(cursor, now, |key| f(key, None))
+        });
+        Ok(DatabaseResponse::clean(Object::Array(vec![
+            Object::BulkString(Some(cursor.to_string().into_bytes())),
+            Object::Array(keys),
+        ])))
+    }
+
+    fn keys(&self, pattern: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        let db = self.db.read()?;
+        let keys = db
+            .keys(now_ms())
+            .filter(|key| glob::matches(&pattern, key))
+            .map(|key| Object::BulkString(Some(key.to_vec())))
+            .collect();
+        Ok(DatabaseResponse::clean(Object::Array(keys)))
+    }
+
+    fn incr_by(&self, key: Vec<u8>, increment: i64) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let current = match db.get_mut(&key, now_ms()) {
+            None => 0,
+            Some(Value::String(value)) => match parse_integer(value) {
+                Some(current) => current,
+                None => return Ok(error("ERR value is not an integer or out of range")),
+            },
+            Some(_) => return Ok(wrong_type()),
+        };
+        let value = match current.checked_add(increment) {
+            Some(value) => value,
+            None => return Ok(error("ERR increment or decrement would overflow")),
+        };
+        db.insert_keep_ttl(key, Value::String(value.to_string().into_bytes()));
+        Ok(DatabaseResponse::dirty(Object::Integer(value)))
+    }
+
+    fn incr_by_float(&self, key: Vec<u8>, increment: f64) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let current = match db.get_mut(&key, now_ms()) {
+            None => 0.0,
+            Some(Value::String(value)) => match parse_float(value) {
+                Some(current) => current,
+                None => return Ok(error("ERR value is not a valid float")),
+            },
+            Some(_) => return Ok(wrong_type()),
+        };
+        let value = current + increment;
+        if !value.is_finite() {
+            return Ok(error("ERR increment would produce NaN or Infinity"));
+        }
+        // Logged as a plain SET, as replaying the addition could round differently
+        let value = value.to_string().into_bytes();
+        db.insert_keep_ttl(key.clone(), Value::String(value.clone()));
+        let options = SetOptions {
+            expiry: Some(Expiry::KeepTtl),
+            ..SetOptions::default()
+        };
+        Ok(
+            DatabaseResponse::dirty(Object::BulkString(Some(value.clone())))
+                .rewritten(Command::Set(key, value, options)),
+        )
+    }
 }
 
-impl Database for HashMapDatabase {
-    fn execute(&self, cmd: Command) -> DbResult<DatabaseResponse> {
+/// Runs a scan until it has visited `options.count` keys, or ten times as many
+/// buckets, and returns the next cursor with the keys that match the pattern,
+/// each followed by its value if the step passes one.
+/// The lock is only held for a single call, never for a whole scan.
+fn scan(
+    options: ScanOptions,
+    mut step: impl FnMut(u64, &mut dyn FnMut(&[u8], Option<&[u8]>)) -> u64,
+) -> (u64, Vec<Object>) {
+    let mut cursor = options.cursor;
+    let mut visited = 0;
+    let mut keys = Vec::new();
+    for _ in 0..options.count.saturating_mul(10) {
+        cursor = step(cursor, &mut |key, value| {
+            visited += 1;
+            if options
+                .pattern
+                .as_ref()
+                .is_none_or(|pattern| glob::matches(pattern, key))
+            {
+                keys.push(Object::BulkString(Some(key.to_vec())));
+                keys.extend(value.map(|value| Object::BulkString(Some(value.to_vec()))));
+            }
+        });
+        if cursor == 0 || visited >= options.count {
+            break;
+        }
+    }
+    (cursor, keys)
+}
+
+fn error(message: &str) -> DatabaseResponse {
+    DatabaseResponse::clean(Object::Error(message.to_string()))
+}
+
+fn wrong_type() -> DatabaseResponse {
+    error("WRONGTYPE Operation against a key holding the wrong kind of value")
+}
+
+fn parse_integer(bytes: &[u8]) -> Option<i64> {
+    // Only the canonical form counts, so that "+1" or "01" are not numbers
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|int: &i64| int.to_string().as_bytes() == bytes)
+}
+
+fn parse_float(bytes: &[u8]) -> Option<f64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .filter(|double: &f64| double.is_finite())
+}
+
+/// The keys a write command may change, which are touched if it does.
+fn written_keys(cmd: &Command) -> Vec<&[u8]> {
+    match cmd {
+        Command::Set(key, _, _)
+        | Command::Remove(key)
+        | Command::IncrBy(key, _)
+        | Command::IncrByFloat(key, _)
+        | Command::Expire(key, _)
+        | Command::Persist(key)
+        | Command::Push(_, key, _)
+        | Command::Pop(_, key, _)
+        | Command::LTrim(key, _, _)
+        | Command::HSet(key, _)
+        | Command::HDel(key, _)
+        | Command::HIncrBy(key, _, _)
+        | Command::SAdd(key, _)
+        | Command::SRem(key, _)
+        | Command::SetOpStore(_, key, _)
+        | Command::ZAdd(key, _, _)
+        | Command::ZRem(key, _)
+        | Command::ZPopMin(key, _)
+        | Command::XAdd(key, _, _)
+        | Command::XGroup(XGroup::Create(key, _, _, _))
+        | Command::XGroup(XGroup::SetId(key, _, _))
+        | Command::XGroup(XGroup::Destroy(key, _))
+        | Command::XAck(key, _, _)
+        | Command::XClaim(key, _, _, _, _, _)
+        | Command::Copy(_, key, _) => vec![key],
+        Command::MSet(pairs) | Command::MSetNx(pairs) => {
+            pairs.iter().map(|(key, _)| &key[..]).collect()
+        }
+        Command::Del(keys) | Command::BPop(_, keys, _) => keys.iter().map(|key| &key[..]).collect(),
+        Command::Rename(source, destination)
+        | Command::RenameNx(source, destination)
+        | Command::LMove(source, destination, _, _)
+        | Command::BLMove(source, destination, _, _, _) => vec![source, destination],
+        Command::XReadGroup(_, _, options) => {
+            options.streams.iter().map(|(key, _)| &key[..]).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+impl HashMapDatabase {
+    fn dispatch(&self, cmd: Command) -> DbResult<'_, DatabaseResponse> {
         match cmd {
-            Command::Get(key) => self.get(key.into()),
-            Command::Set(key, value) => self.set(key.into(), Object::BulkString(Some(value))),
-            Command::Remove(key) => self.remove(key.into()),
+            Command::Get(key) => self.get(key),
+            Command::Set(key, value, options) => self.set(key, value, options),
+            Command::Remove(key) => self.remove(key),
+            Command::GetV(key) => self.getv(key),
+            Command::Cas(key, version, value) => self.cas(key, version, value),
+            Command::Cad(key, version) => self.cad(key, version),
+            Command::MGet(keys) => self.mget(keys),
+            Command::MSet(pairs) => self.mset(pairs),
+            Command::MSetNx(pairs) => self.msetnx(pairs),
+            Command::IncrBy(key, increment) => self.incr_by(key, increment),
+            Command::IncrByFloat(key, increment) => self.incr_by_float(key, increment),
+            Command::Expire(key, expiry) => self.expire(key, expiry),
+            Command::Ttl(key) => self.ttl(key, false),
+            Command::PTtl(key) => self.ttl(key, true),
+            Command::Persist(key) => self.persist(key),
+            Command::Exists(keys) => self.exists(keys),
+            Command::Del(keys) => self.del(keys),
+            Command::Type(key) => self.type_of(key),
+            Command::Rename(source, destination) => self.rename(source, destination, false),
+            Command::RenameNx(source, destination) => self.rename(source, destination, true),
+            Command::Copy(source, destination, replace) => self.copy(source, destination, replace),
+            Command::DbSize => self.dbsize(),
+            Command::FlushAll | Command::FlushDb => self.flushall(),
+            Command::Scan(options) => self.scan(options),
+            Command::Keys(pattern) => self.keys(pattern),
+            Command::Push(end, key, values) => self.push(end, key, values),
+            Command::Pop(end, key, count) => self.pop(end, key, count),
+            Command::LRange(key, start, stop) => self.lrange(key, start, stop),
+            Command::LLen(key) => self.llen(key),
+            Command::LTrim(key, start, stop) => self.ltrim(key, start, stop),
+            Command::LMove(source, destination, from, to) => {
+                self.lmove(source, destination, from, to)
+            }
+            Command::BPop(end, keys, _) => self.bpop(end, keys),
+            Command::BLMove(source, destination, from, to, _) => {
+                self.blmove(source, destination, from, to)
+            }
+            Command::HSet(key, pairs) => self.hset(key, pairs),
+            Command::HGet(key, field) => self.hget(key, field),
+            Command::HMGet(key, fields) => self.hmget(key, fields),
+            Command::HDel(key, fields) => self.hdel(key, fields),
+            Command::HGetAll(key) => self.hgetall(key),
+            Command::HIncrBy(key, field, increment) => self.hincr_by(key, field, increment),
+            Command::HScan(key, options) => self.hscan(key, options),
+            Command::SAdd(key, members) => self.sadd(key, members),
+            Command::SRem(key, members) => self.srem(key, members),
+            Command::SIsMember(key, member) => self.sismember(key, member),
+            Command::SMembers(key) => self.smembers(key),
+            Command::SetOp(op, keys) => self.set_op(op, keys),
+            Command::SetOpStore(op, destination, keys) => self.set_op_store(op, destination, keys),
+            Command::ZAdd(key, options, members) => self.zadd(key, options, members),
+            Command::ZScore(key, member) => self.zscore(key, member),
+            Command::ZRank(key, member) => self.zrank(key, member),
+            Command::ZRange(key, options) => self.zrange(key, options),
+            Command::ZRem(key, members) => self.zrem(key, members),
+            Command::ZPopMin(key, count) => self.zpopmin(key, count),
+            Command::XAdd(key, id, pairs) => self.xadd(key, id, pairs),
+            Command::XRange(key, start, end, count) => self.xrange(key, start, end, count),
+            Command::XRead(options) => self.xread(options),
+            Command::XReadGroup(group, consumer, options) => {
+                self.xreadgroup(group, consumer, options)
+            }
+            Command::XGroup(xgroup) => self.xgroup(xgroup),
+            Command::XAck(key, group, ids) => self.xack(key, group, ids),
+            Command::XPending(key, group, range) => self.xpending(key, group, range),
+            Command::XClaim(key, group, consumer, min_idle, ids, options) => {
+                self.xclaim(key, group, consumer, min_idle, ids, options)
+            }
+            cmd @ (Command::Hello(_)
+            | Command::Command(_)
+            | Command::Ping(_)
+            | Command::Echo(_)
+            | Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PSubscribe(_)
+            | Command::PUnsubscribe(_)
+            | Command::Publish(_, _)
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Watch(_)
+            | Command::Unwatch
+            | Command::Select(_)
+            | Command::SwapDb(_, _)) => Ok(DatabaseResponse::clean(Object::Error(format!(
+                "ERR '{}' is not a database command",
+                cmd.name()
+            )))),
+        }
+    }
+
+    /// Executes a command and gives the keys it wrote a new version.
+    fn apply(&self, cmd: Command) -> DbResult<'_, DatabaseResponse> {
+        let cmd_keys: Vec<Vec<u8>> = written_keys(&cmd).into_iter().map(<[u8]>::to_vec).collect();
+        let response = self.dispatch(cmd)?;
+        if response.is_dirty {
+            // Each logged command is a change of its own
+            let mut db = self.db.write()?;
+            if response.rewrite.is_empty() {
+                db.record_change(&cmd_keys);
+            } else {
+                for cmd in &response.rewrite {
+                    db.record_change(&written_keys(cmd));
+                }
+            }
+        }
+        Ok(response)
+    }
+}
+
+/// Runs `f` on a database the keyspace is moved into while its lock is held.
+fn locked<T>(db: &mut Keyspace, f: impl FnOnce(&HashMapDatabase) -> T) -> T {
+    let locked = HashMapDatabase {
+        db: RwLock::new(std::mem::take(db)),
+    };
+    let ret = f(&locked);
+    *db = locked.db.into_inner().expect(LOCKED);
+    ret
+}
+
+const LOCKED: &str = "Nobody else uses the lock of a locked database";
+
+impl Database for HashMapDatabase {
+    fn execute(&self, cmd: Command) -> DbResult<'_, DatabaseResponse> {
+        if !cmd.possibly_dirty() {
+            return self.dispatch(cmd);
+        }
+        let mut db = self.db.write()?;
+        Ok(locked(&mut db, |db| db.apply(cmd).expect(LOCKED)))
+    }
+
+    fn expire_keys(&self, limit: usize) -> DbResult<'_, usize> {
+        Ok(self.db.write()?.expire_due(now_ms(), limit))
+    }
+
+    fn take_expired(&self) -> DbResult<'_, Vec<Vec<u8>>> {
+        Ok(self.db.write()?.take_expired())
+    }
+
+    fn take_ready(&self) -> DbResult<'_, Vec<Vec<u8>>> {
+        Ok(self.db.write()?.take_ready())
+    }
+
+    fn watch(&self, keys: &[Vec<u8>]) -> DbResult<'_, Vec<Watched>> {
+        let mut db = self.db.write()?;
+        let now = now_ms();
+        Ok(keys.iter().map(|key| db.watch(key, now)).collect())
+    }
+
+    fn unwatch(&self, keys: &[Vec<u8>]) -> DbResult<'_, ()> {
+        let mut db = self.db.write()?;
+        keys.iter().for_each(|key| db.unwatch(key));
+        Ok(())
+    }
+
+    fn execute_transaction(
+        &self,
+        cmds: Vec<Command>,
+        watched: &[(Vec<u8>, Watched)],
+    ) -> DbResult<'_, Option<Vec<DatabaseResponse>>> {
+        let mut db = self.db.write()?;
+        let now = now_ms();
+        if watched
+            .iter()
+            .any(|(key, watched)| db.watched(key, now) != Some(*watched))
+        {
+            return Ok(None);
+        }
+        let responses = locked(&mut db, |transaction| {
+            cmds.into_iter()
+                .map(|cmd| {
+                    let record = cmd.clone();
+                    let mut response = transaction.apply(cmd).expect(LOCKED);
+                    // Keys a command found expired are logged as deleted right before it
+                    let expired = transaction.take_expired().expect(LOCKED);
+                    if !expired.is_empty() {
+                        let rewrite = match (response.is_dirty, response.rewrite.is_empty()) {
+                            (false, _) => Vec::new(),
+                            (true, true) => vec![record],
+                            (true, false) => std::mem::take(&mut response.rewrite),
+                        };
+                        response.rewrite = std::iter::once(Command::Del(expired))
+                            .chain(rewrite)
+                            .collect();
+                        response.is_dirty = true;
+                    }
+                    response
+                })
+                .collect()
+        });
+        Ok(Some(responses))
+    }
+
+    fn swap(&self, other: &dyn Database) -> DbResult<'_, ()> {
+        let mut db = self.db.write()?;
+        other
+            .swap_keys(&mut db)
+            .expect("The other database should not be poisoned");
+        Ok(())
+    }
+
+    fn swap_keys(&self, keyspace: &mut Keyspace) -> DbResult<'_, ()> {
+        self.db.write()?.swap_keys(keyspace);
+        Ok(())
+    }
+
+    fn set_replaying(&self, replaying: bool) -> DbResult<'_, ()> {
+        self.db.write()?.replaying = replaying;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::End;
+    use std::collections::HashSet;
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    pub(super) fn execute(db: &HashMapDatabase, cmd: Command) -> Object {
+        db.execute(cmd).unwrap().object
+    }
+
+    /// A database that logs the commands run on it the way the server does.
+    pub(super) struct Logged {
+        pub db: HashMapDatabase,
+        pub log: Vec<Command>,
+    }
+
+    impl Logged {
+        pub fn new() -> Self {
+            Logged {
+                db: HashMapDatabase::new(),
+                log: Vec::new(),
+            }
+        }
+
+        /// Executes a command and logs it, after the keys it found expired.
+        pub fn run(&mut self, cmd: Command) -> Object {
+            let response = self.db.execute(cmd.clone()).unwrap();
+            let expired = self.db.take_expired().unwrap();
+            if !expired.is_empty() {
+                self.log.push(Command::Del(expired));
+            }
+            self.record(cmd, response)
+        }
+
+        /// Logs what the response to a command changed and returns its reply.
+        pub fn record(&mut self, cmd: Command, response: DatabaseResponse) -> Object {
+            if response.is_dirty {
+                match response.rewrite.is_empty() {
+                    true => self.log.push(cmd),
+                    false => self.log.extend(response.rewrite),
+                }
+            }
+            response.object
+        }
+
+        /// Lets a key expire as if its time had passed.
+        pub fn expire_now(&self, key: &[u8]) {
+            let mut keyspace = self.db.db.write().unwrap();
+            keyspace.set_expiry(key, Some(now_ms() - 1));
+        }
+
+        /// Applies the log, through its encoding, to a new database.
+        pub fn replay(&self) -> HashMapDatabase {
+            let replica = HashMapDatabase::new();
+            replica.set_replaying(true).unwrap();
+            for cmd in &self.log {
+                let cmd = Command::try_from(cmd.to_object()).unwrap();
+                assert!(replica.execute(cmd).unwrap().is_dirty);
+            }
+            replica.set_replaying(false).unwrap();
+            replica
+        }
+
+        /// Checks that replaying the log rebuilds the same keys.
+        pub fn assert_replays(&self) {
+            let replica = self.replay();
+            let (db, replica) = (self.db.db.read().unwrap(), replica.db.read().unwrap());
+            assert_eq!(replica.entries, db.entries);
+            assert_eq!(replica.expires, db.expires);
+            assert_eq!(replica.versions, db.versions);
+            assert_eq!(replica.last_version, db.last_version);
+        }
+    }
+
+    /// Splits a SCAN reply into the next cursor and the page.
+    pub(super) fn scanned(reply: Object) -> (i64, Vec<Object>) {
+        match reply {
+            Object::Array(reply) => match &reply[..] {
+                [Object::BulkString(Some(cursor)), Object::Array(page)] => {
+                    (parse_integer(cursor).unwrap(), page.clone())
+                }
+                _ => panic!("unexpected reply {:?}", reply),
+            },
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    /// Builds a `SET` without options.
+    pub(super) fn set(key: &[u8], value: &[u8]) -> Command {
+        Command::Set(key.to_vec(), value.to_vec(), SetOptions::default())
+    }
+
+    /// Builds a `SET` that expires after a number of seconds.
+    pub(super) fn set_ex(key: &[u8], value: &[u8], seconds: i64) -> Command {
+        let options = SetOptions {
+            expiry: Some(Expiry::Ex(seconds)),
+            ..SetOptions::default()
+        };
+        Command::Set(key.to_vec(), value.to_vec(), options)
+    }
+
+    pub(super) fn bytes(items: &[&[u8]]) -> Vec<Vec<u8>> {
+        items.iter().map(|item| item.to_vec()).collect()
+    }
+
+    pub(super) fn pairs(pairs: &[(&[u8], &[u8])]) -> Vec<KeyValue> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect()
+    }
+
+    pub(super) fn bulk(value: &[u8]) -> Object {
+        Object::BulkString(Some(value.to_vec()))
+    }
+
+    pub(super) fn ok() -> Object {
+        Object::SimpleString("OK".to_string())
+    }
+
+    #[test]
+    fn multi_key_commands_set_every_pair_or_none() {
+        let mut db = Logged::new();
+        db.run(set(b"a", b"1"));
+
+        assert_eq!(
+            db.run(Command::MGet(bytes(&[b"a", b"missing"]))),
+            Object::Array(vec![bulk(b"1"), Object::Null])
+        );
+        // Any existing key fails the whole MSETNX
+        let response = db
+            .db
+            .execute(Command::MSetNx(pairs(&[(b"x", b"1"), (b"a", b"1")])))
+            .unwrap();
+        assert_eq!(response.object, Object::Integer(0));
+        assert!(!response.is_dirty);
+        assert_eq!(
+            db.run(Command::MSetNx(pairs(&[(b"x", b"1"), (b"y", b"2")]))),
+            Object::Integer(1)
+        );
+        // The last pair for a key wins
+        db.run(Command::MSet(pairs(&[(b"a", b"2"), (b"a", b"3")])));
+        assert_eq!(
+            db.run(Command::MGet(bytes(&[b"a", b"x", b"y"]))),
+            Object::Array(vec![bulk(b"3"), bulk(b"1"), bulk(b"2")])
+        );
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn multi_key_commands_skip_expired_keys() {
+        let mut db = Logged::new();
+        db.run(set_ex(b"a", b"1", 100));
+        db.run(set(b"e", b"1"));
+        db.expire_now(b"e");
+
+        assert_eq!(
+            db.run(Command::MGet(bytes(&[b"a", b"e"]))),
+            Object::Array(vec![bulk(b"1"), Object::Null])
+        );
+        assert_eq!(
+            db.run(Command::MSetNx(pairs(&[(b"x", b"1"), (b"e", b"2")]))),
+            Object::Integer(1)
+        );
+        // MSET drops the expiry
+        db.run(Command::MSet(pairs(&[(b"a", b"2")])));
+        assert_eq!(db.run(Command::Ttl(b"a".to_vec())), Object::Integer(-1));
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn incr_by_checks_value_and_overflow() {
+        let db = HashMapDatabase::new();
+        let key = || b"counter".to_vec();
+        assert_eq!(execute(&db, Command::IncrBy(key(), 5)), Object::Integer(5));
+        assert_eq!(
+            execute(&db, Command::IncrBy(key(), -7)),
+            Object::Integer(-2)
+        );
+
+        execute(&db, set(b"counter", i64::MAX.to_string().as_bytes()));
+        let response = db.execute(Command::IncrBy(key(), 1)).unwrap();
+        assert!(matches!(response.object, Object::Error(_)));
+        assert!(!response.is_dirty);
+
+        execute(&db, set(b"counter", b"+1"));
+        assert!(matches!(
+            execute(&db, Command::IncrBy(key(), 1)),
+            Object::Error(_)
+        ));
+    }
+
+    #[test]
+    fn counters_check_their_value() {
+        let mut db = Logged::new();
+        db.run(set(b"n", b"1"));
+        assert_eq!(
+            db.run(Command::IncrByFloat(b"n".to_vec(), 0.5)),
+            bulk(b"1.5")
+        );
+        assert_eq!(
+            db.run(Command::IncrBy(b"n".to_vec(), 1)),
+            Object::Error("ERR value is not an integer or out of range".to_string())
+        );
+        db.run(set(b"f", b"abc"));
+        assert_eq!(
+            db.run(Command::IncrByFloat(b"f".to_vec(), 1.0)),
+            Object::Error("ERR value is not a valid float".to_string())
+        );
+        db.run(set(b"f", f64::MAX.to_string().as_bytes()));
+        assert_eq!(
+            db.run(Command::IncrByFloat(b"f".to_vec(), f64::MAX)),
+            Object::Error("ERR increment would produce NaN or Infinity".to_string())
+        );
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn counters_keep_the_expiry() {
+        let mut db = Logged::new();
+        db.run(set_ex(b"live", b"1", 100));
+        db.run(set_ex(b"expired", b"1", 100));
+        db.expire_now(b"expired");
+
+        assert_eq!(
+            db.run(Command::IncrBy(b"live".to_vec(), 2)),
+            Object::Integer(3)
+        );
+        assert_eq!(db.run(Command::Ttl(b"live".to_vec())), Object::Integer(100));
+        assert_eq!(
+            db.run(Command::IncrByFloat(b"live".to_vec(), 0.5)),
+            bulk(b"3.5")
+        );
+        assert_eq!(db.run(Command::Ttl(b"live".to_vec())), Object::Integer(100));
+        // An expired counter starts again from zero, without the expiry
+        assert_eq!(
+            db.run(Command::IncrBy(b"expired".to_vec(), -1)),
+            Object::Integer(-1)
+        );
+        assert_eq!(
+            db.run(Command::Ttl(b"expired".to_vec())),
+            Object::Integer(-1)
+        );
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn incr_by_float_is_rewritten_as_set() {
+        let db = HashMapDatabase::new();
+        execute(&db, set(b"f", b"10.5"));
+        let response = db
+            .execute(Command::IncrByFloat(b"f".to_vec(), 0.1))
+            .unwrap();
+        assert_eq!(response.object, bulk(b"10.6"));
+        assert!(matches!(
+            &response.rewrite[..],
+            [Command::Set(key, value, _)] if key == b"f" && value == b"10.6"
+        ));
+    }
+
+    #[test]
+    fn set_conditions_are_checked_but_not_logged() {
+        let mut db = Logged::new();
+        let set_with =
+            |key: &[u8], value: &[u8], options| Command::Set(key.to_vec(), value.to_vec(), options);
+        let nx = SetOptions {
+            condition: Some(Condition::Nx),
+            ..SetOptions::default()
+        };
+        let xx = SetOptions {
+            condition: Some(Condition::Xx),
+            ..SetOptions::default()
+        };
+        let response = db.db.execute(set_with(b"k", b"1", xx)).unwrap();
+        assert_eq!(response.object, Object::Null);
+        assert!(!response.is_dirty);
+        assert_eq!(db.run(set_with(b"k", b"1", nx)), ok());
+        assert_eq!(db.run(set_with(b"k", b"2", nx)), Object::Null);
+        let get_xx = SetOptions { get: true, ..xx };
+        assert_eq!(db.run(set_with(b"k", b"2", get_xx)), bulk(b"1"));
+        let get_nx = SetOptions { get: true, ..nx };
+        assert_eq!(db.run(set_with(b"k", b"3", get_nx)), bulk(b"2"));
+
+        // The log holds no conditions, only what was stored
+        assert!(db.log.iter().all(|cmd| match cmd {
+            Command::Set(_, _, options) => options.condition.is_none() && !options.get,
+            _ => true,
+        }));
+        db.assert_replays();
+    }
+
+    #[test]
+    fn set_conditions_see_expired_keys() {
+        let mut db = Logged::new();
+        db.run(set_ex(b"k", b"1", 100));
+        db.expire_now(b"k");
+        let nx_keep_ttl = SetOptions {
+            condition: Some(Condition::Nx),
+            expiry: Some(Expiry::KeepTtl),
+            ..SetOptions::default()
+        };
+        assert_eq!(
+            db.run(Command::Set(b"k".to_vec(), b"2".to_vec(), nx_keep_ttl)),
+            ok()
+        );
+        assert_eq!(db.run(Command::Ttl(b"k".to_vec())), Object::Integer(-1));
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn keys_expire_lazily_and_in_deadline_order() {
+        let mut keyspace = Keyspace::default();
+        let value = || Value::String(b"v".to_vec());
+        keyspace.insert(b"a".to_vec(), value(), Some(30));
+        keyspace.insert(b"b".to_vec(), value(), Some(10));
+        keyspace.insert(b"c".to_vec(), value(), Some(20));
+        keyspace.insert(b"d".to_vec(), value(), None);
+        keyspace.set_expiry(b"c", Some(40));
+
+        assert!(keyspace.get(b"b", 10).is_none());
+        assert!(keyspace.get(b"b", 9).is_some());
+        assert!(keyspace.get_mut(b"b", 10).is_none());
+        assert_eq!(keyspace.take_expired(), [b"b".to_vec()]);
+
+        assert_eq!(keyspace.expire_due(100, 1), 1);
+        assert_eq!(keyspace.expire_due(100, 10), 1);
+        assert_eq!(keyspace.take_expired(), [b"a".to_vec(), b"c".to_vec()]);
+        assert!(keyspace.contains_key(b"d", 100));
+        assert!(keyspace.deadlines.is_empty());
+    }
+
+    #[test]
+    fn expire_is_logged_with_an_absolute_deadline() {
+        let db = HashMapDatabase::new();
+        execute(&db, set(b"k", b"v"));
+        let response = db
+            .execute(Command::Expire(b"k".to_vec(), Expiry::Ex(10)))
+            .unwrap();
+        assert_eq!(response.object, Object::Integer(1));
+        assert!(matches!(
+            &response.rewrite[..],
+            [Command::Expire(_, Expiry::PxAt(_))]
+        ));
+        assert_eq!(
+            execute(&db, Command::Ttl(b"k".to_vec())),
+            Object::Integer(10)
+        );
+
+        let response = db
+            .execute(Command::Expire(b"k".to_vec(), Expiry::Ex(-1)))
+            .unwrap();
+        assert!(matches!(&response.rewrite[..], [Command::Del(_)]));
+        assert_eq!(
+            execute(&db, Command::Ttl(b"k".to_vec())),
+            Object::Integer(-2)
+        );
+    }
+
+    #[test]
+    fn expiry_commands_on_missing_and_expired_keys() {
+        let mut db = Logged::new();
+        db.run(set(b"a", b"v"));
+        db.run(set(b"b", b"v"));
+        db.run(set(b"c", b"v"));
+
+        let response = db
+            .db
+            .execute(Command::Expire(b"missing".to_vec(), Expiry::Ex(10)))
+            .unwrap();
+        assert_eq!(response.object, Object::Integer(0));
+        assert!(!response.is_dirty);
+        assert_eq!(
+            db.run(Command::Expire(b"a".to_vec(), Expiry::Ex(i64::MAX))),
+            Object::Error("ERR invalid expire time in 'expire' command".to_string())
+        );
+        assert_eq!(db.run(Command::Persist(b"a".to_vec())), Object::Integer(0));
+        assert_eq!(db.run(Command::PTtl(b"a".to_vec())), Object::Integer(-1));
+
+        // A deadline in the past deletes the key
+        assert_eq!(
+            db.run(Command::Expire(b"a".to_vec(), Expiry::ExAt(1))),
+            Object::Integer(1)
+        );
+        assert_eq!(db.run(Command::Ttl(b"a".to_vec())), Object::Integer(-2));
+        db.run(Command::Expire(b"b".to_vec(), Expiry::Px(100)));
+        let pttl = match db.run(Command::PTtl(b"b".to_vec())) {
+            Object::Integer(pttl) => pttl,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert!(0 < pttl && pttl <= 100);
+
+        // Expired keys cannot be persisted, and are deleted when found
+        db.expire_now(b"b");
+        assert_eq!(db.run(Command::Persist(b"b".to_vec())), Object::Integer(0));
+        db.run(Command::Expire(b"c".to_vec(), Expiry::Px(1)));
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(db.db.expire_keys(10).unwrap(), 1);
+        assert_eq!(db.db.take_expired().unwrap(), [b"c".to_vec()]);
+        db.log.push(Command::Del(bytes(&[b"c"])));
+
+        let deleted = |cmd: &Command, key: &[u8]| matches!(cmd, Command::Del(keys) if keys == &[key.to_vec()]);
+        let expired = |cmd: &Command| matches!(cmd, Command::Expire(_, Expiry::PxAt(_)));
+        assert!(matches!(&db.log[3..], [a, b_set, b, c_set, c]
+            if deleted(a, b"a") && expired(b_set) && deleted(b, b"b") && expired(c_set) && deleted(c, b"c")));
+        db.assert_replays();
+    }
+
+    #[test]
+    fn replaying_ignores_deadlines_that_passed_since() {
+        let mut db = Logged::new();
+        let set_px = |key: &[u8]| {
+            let options = SetOptions {
+                expiry: Some(Expiry::Px(50)),
+                ..SetOptions::default()
+            };
+            Command::Set(key.to_vec(), b"1".to_vec(), options)
+        };
+        db.run(set_px(b"persisted"));
+        db.run(Command::Expire(b"persisted".to_vec(), Expiry::Px(50)));
+        db.run(Command::Persist(b"persisted".to_vec()));
+        db.run(set_px(b"counter"));
+        db.run(Command::IncrBy(b"counter".to_vec(), 1));
+        let deadline = db.db.db.read().unwrap().expires_at(b"counter").unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(now_ms() > deadline);
+
+        let replica = db.replay();
+        let get = |key: &[u8]| execute(&replica, Command::Get(key.to_vec()));
+        assert_eq!(get(b"persisted"), bulk(b"1"));
+        // The counter was incremented rather than recreated, and kept its
+        // deadline, so it is gone now until it is removed and logged as such
+        {
+            let keyspace = replica.db.read().unwrap();
+            assert_eq!(
+                keyspace.entries.get(&b"counter"[..]),
+                Some(&Value::String(b"2".to_vec()))
+            );
+            assert_eq!(keyspace.expires_at(b"counter"), Some(deadline));
+        }
+        assert_eq!(get(b"counter"), Object::Null);
+        assert_eq!(replica.expire_keys(10).unwrap(), 1);
+        assert_eq!(replica.take_expired().unwrap(), [b"counter".to_vec()]);
+    }
+
+    #[test]
+    fn rename_and_copy_keep_the_expiry() {
+        let db = HashMapDatabase::new();
+        execute(&db, set_ex(b"a", b"v", 100));
+        execute(&db, Command::Rename(b"a".to_vec(), b"b".to_vec()));
+        execute(&db, Command::Copy(b"b".to_vec(), b"c".to_vec(), false));
+        let exists = Command::Exists(bytes(&[b"a", b"b", b"c"]));
+        assert_eq!(execute(&db, exists), Object::Integer(2));
+        assert_eq!(
+            execute(&db, Command::Ttl(b"c".to_vec())),
+            Object::Integer(100)
+        );
+        assert!(matches!(
+            execute(&db, Command::Rename(b"a".to_vec(), b"d".to_vec())),
+            Object::Error(_)
+        ));
+    }
+
+    #[test]
+    fn keyspace_commands_on_missing_and_expired_keys() {
+        let mut db = Logged::new();
+        db.run(set_ex(b"a", b"v", 100));
+        db.run(set(b"b", b"v"));
+        db.run(set(b"expired", b"v"));
+        db.expire_now(b"expired");
+
+        let response = db
+            .db
+            .execute(Command::Rename(b"missing".to_vec(), b"x".to_vec()))
+            .unwrap();
+        assert_eq!(
+            response.object,
+            Object::Error("ERR no such key".to_string())
+        );
+        assert!(!response.is_dirty);
+        assert_eq!(db.run(Command::Rename(b"a".to_vec(), b"a".to_vec())), ok());
+        assert_eq!(db.run(Command::Ttl(b"a".to_vec())), Object::Integer(100));
+        assert_eq!(
+            db.run(Command::RenameNx(b"a".to_vec(), b"b".to_vec())),
+            Object::Integer(0)
+        );
+        assert_eq!(
+            db.run(Command::RenameNx(b"a".to_vec(), b"expired".to_vec())),
+            Object::Integer(1)
+        );
+        assert_eq!(
+            db.run(Command::Type(b"a".to_vec())),
+            Object::SimpleString("none".to_string())
+        );
+
+        assert_eq!(
+            db.run(Command::Copy(b"b".to_vec(), b"expired".to_vec(), false)),
+            Object::Integer(0)
+        );
+        assert_eq!(
+            db.run(Command::Copy(b"b".to_vec(), b"b".to_vec(), true)),
+            Object::Integer(0)
+        );
+        assert_eq!(
+            db.run(Command::Copy(b"b".to_vec(), b"expired".to_vec(), true)),
+            Object::Integer(1)
+        );
+        assert_eq!(
+            db.run(Command::Ttl(b"expired".to_vec())),
+            Object::Integer(-1)
+        );
+
+        // Keys are counted as many times as they are named
+        let named = || bytes(&[b"b", b"b", b"missing"]);
+        assert_eq!(db.run(Command::Exists(named())), Object::Integer(2));
+        assert_eq!(db.run(Command::Del(named())), Object::Integer(1));
+        assert_eq!(db.run(Command::DbSize), Object::Integer(1));
+        db.assert_replays();
+
+        db.run(Command::FlushAll);
+        assert_eq!(db.run(Command::DbSize), Object::Integer(0));
+        db.assert_replays();
+    }
+
+    #[test]
+    fn scan_returns_keys_that_stay_while_others_come_and_go() {
+        let db = HashMapDatabase::new();
+        for i in 0..100 {
+            execute(&db, set(format!("old:{}", i).as_bytes(), b"v"));
+        }
+        execute(&db, set(b"old:expired", b"v"));
+        db.db
+            .write()
+            .unwrap()
+            .set_expiry(b"old:expired", Some(now_ms() - 1));
+
+        let mut seen = HashSet::new();
+        let mut options = ScanOptions {
+            cursor: 0,
+            pattern: Some(b"old:*".to_vec()),
+            count: 7,
+        };
+        for round in 0.. {
+            let (cursor, page) = scanned(execute(&db, Command::Scan(options.clone())));
+            for key in page {
+                match key {
+                    Object::BulkString(Some(key)) => seen.insert(key),
+                    key => panic!("unexpected key {:?}", key),
+                };
+            }
+            if cursor == 0 {
+                break;
+            }
+            options.cursor = cursor as u64;
+            // The table grows and shrinks between calls
+            let keys: Vec<_> = (0..200).map(|i| format!("new:{}", i)).collect();
+            if round % 2 == 0 {
+                for key in keys {
+                    execute(&db, set(key.as_bytes(), b"v"));
+                }
+            } else {
+                let keys = keys.into_iter().map(String::into_bytes).collect();
+                execute(&db, Command::Del(keys));
+            }
         }
+        assert!((0..100).all(|i| seen.contains(format!("old:{}", i).as_bytes())));
+        assert!(seen.iter().all(|key| key.starts_with(b"old:")));
+        assert!(!seen.contains(&b"old:expired"[..]));
+
+        let keys = match execute(&db, Command::Keys(b"old:[^1-9]*".to_vec())) {
+            Object::Array(keys) => keys,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert_eq!(keys, [bulk(b"old:0")]);
+    }
+
+    #[test]
+    fn transactions_fail_once_a_watched_key_changes() {
+        let db = HashMapDatabase::new();
+        let watched = |db: &HashMapDatabase| {
+            let watched = db.watch(&[b"k".to_vec()]).unwrap();
+            vec![(b"k".to_vec(), watched[0])]
+        };
+
+        let first = watched(&db);
+        let responses = db
+            .execute_transaction(vec![set(b"k", b"a"), Command::Get(b"k".to_vec())], &first)
+            .unwrap()
+            .unwrap();
+        assert!(responses[0].is_dirty);
+        assert_eq!(responses[1].object, bulk(b"a"));
+
+        // The transaction itself changed the key
+        let stale = db
+            .execute_transaction(vec![set(b"k", b"b")], &first)
+            .unwrap();
+        assert!(stale.is_none());
+        let second = watched(&db);
+        execute(&db, Command::Get(b"k".to_vec()));
+        assert!(db.execute_transaction(vec![], &second).unwrap().is_some());
+        execute(&db, Command::Del(vec![b"k".to_vec()]));
+        assert!(db.execute_transaction(vec![], &second).unwrap().is_none());
+
+        // Expiring is a change too
+        let third = watched(&db);
+        execute(&db, set(b"k", b"c"));
+        let fourth = watched(&db);
+        db.db.write().unwrap().set_expiry(b"k", Some(now_ms() - 1));
+        assert!(db.execute_transaction(vec![], &third).unwrap().is_none());
+        assert!(db.execute_transaction(vec![], &fourth).unwrap().is_none());
+        // Neither watching nor checking removes the key, followers only hide it
+        let fifth = watched(&db);
+        assert!(db.execute_transaction(vec![], &fifth).unwrap().is_some());
+        assert!(db.take_expired().unwrap().is_empty());
+
+        db.unwatch(&bytes(&[b"k", b"k"])).unwrap();
+        db.unwatch(&bytes(&[b"k", b"k", b"k"])).unwrap();
+        assert!(db.db.read().unwrap().watched.is_empty());
+    }
+
+    #[test]
+    fn transactions_log_expired_keys_before_the_command_that_found_them() {
+        let mut db = Logged::new();
+        db.run(set(b"counter", b"1"));
+        db.run(Command::Push(
+            End::Left,
+            b"list".to_vec(),
+            vec![b"v".to_vec()],
+        ));
+        db.expire_now(b"counter");
+        db.expire_now(b"list");
+
+        let cmds = vec![
+            set(b"other", b"1"),
+            Command::IncrBy(b"counter".to_vec(), 1),
+            Command::Pop(End::Left, b"list".to_vec(), None),
+        ];
+        let responses = db
+            .db
+            .execute_transaction(cmds.clone(), &[])
+            .unwrap()
+            .unwrap();
+        assert!(db.db.take_expired().unwrap().is_empty());
+        assert_eq!(responses[1].object, Object::Integer(1));
+        assert_eq!(responses[2].object, Object::Null);
+        assert!(matches!(
+            &responses[1].rewrite[..],
+            [Command::Del(keys), Command::IncrBy(_, 1)] if keys == &[b"counter".to_vec()]
+        ));
+        assert!(matches!(
+            &responses[2].rewrite[..],
+            [Command::Del(keys)] if keys == &[b"list".to_vec()]
+        ));
+        for (cmd, response) in cmds.into_iter().zip(responses) {
+            db.record(cmd, response);
+        }
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn versions_are_checked_and_rebuilt_from_the_log() {
+        let mut db = Logged::new();
+        let getv = || Command::GetV(b"k".to_vec());
+        let cas = |version, value: &[u8]| Command::Cas(b"k".to_vec(), version, value.to_vec());
+        let versioned =
+            |value: &[u8], version| Object::Array(vec![bulk(value), Object::Integer(version)]);
+
+        assert_eq!(db.run(getv()), Object::Null);
+        assert_eq!(db.run(cas(1, b"a")), Object::Null);
+        assert_eq!(db.run(cas(0, b"a")), ok());
+        assert_eq!(db.run(getv()), versioned(b"a", 1));
+        db.run(set(b"other", b"1"));
+        db.run(Command::IncrByFloat(b"other".to_vec(), 1.5));
+        assert_eq!(db.run(cas(0, b"b")), Object::Null);
+        assert_eq!(db.run(cas(1, b"b")), ok());
+        assert_eq!(db.run(getv()), versioned(b"b", 4));
+        assert_eq!(db.run(Command::Cad(b"k".to_vec(), 1)), Object::Integer(0));
+        assert_eq!(db.run(Command::Cad(b"k".to_vec(), 4)), Object::Integer(1));
+        // A key that comes back never reuses an old version
+        db.run(cas(0, b"c"));
+        assert_eq!(db.run(getv()), versioned(b"c", 6));
+        // An expired key is deleted, in its own logged change, before CAS
+        db.expire_now(b"other");
+        assert_eq!(
+            db.run(Command::Cas(b"other".to_vec(), 0, b"x".to_vec())),
+            ok()
+        );
+        assert_eq!(db.run(Command::GetV(b"other".to_vec())), versioned(b"x", 8));
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn versioned_commands_only_take_strings() {
+        let mut db = Logged::new();
+        db.run(Command::Push(End::Left, b"l".to_vec(), vec![b"v".to_vec()]));
+        // Even with the version the list has
+        for cmd in [
+            Command::GetV(b"l".to_vec()),
+            Command::Cas(b"l".to_vec(), 1, b"v".to_vec()),
+            Command::Cad(b"l".to_vec(), 1),
+        ] {
+            assert_eq!(db.run(cmd), wrong_type().object);
+        }
+        assert_eq!(db.run(Command::LLen(b"l".to_vec())), Object::Integer(1));
+    }
+
+    #[test]
+    fn compare_and_delete_needs_a_live_key() {
+        let mut db = Logged::new();
+        assert_eq!(db.run(Command::Cad(b"k".to_vec(), 0)), Object::Integer(0));
+        db.run(Command::Cas(b"k".to_vec(), 0, b"v".to_vec()));
+        db.expire_now(b"k");
+        // The expired key is removed, but not by the CAD, whose version it had
+        assert_eq!(db.run(Command::Cad(b"k".to_vec(), 1)), Object::Integer(0));
+        assert_eq!(
+            db.run(Command::Cas(b"k".to_vec(), 1, b"w".to_vec())),
+            Object::Null
+        );
+        assert_eq!(db.run(Command::Cas(b"k".to_vec(), 0, b"w".to_vec())), ok());
+        assert!(matches!(&db.log[..], [
+            Command::Set(..),
+            Command::Del(expired),
+            Command::Set(_, value, _),
+        ] if expired == &[b"k".to_vec()] && value == b"w"));
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn swapping_databases_moves_keys_and_versions() {
+        let (first, second) = (HashMapDatabase::new(), HashMapDatabase::new());
+        execute(&first, set(b"a", b"v"));
+        execute(&first, set(b"b", b"v"));
+        execute(&second, set(b"c", b"v"));
+        let watched = second.watch(&[b"a".to_vec()]).unwrap();
+        let watched = [(b"a".to_vec(), watched[0])];
+
+        first.swap(&second).unwrap();
+        assert_eq!(
+            execute(&second, Command::GetV(b"b".to_vec())),
+            Object::Array(vec![bulk(b"v"), Object::Integer(2)])
+        );
+        assert_eq!(
+            execute(&first, Command::Exists(vec![b"a".to_vec()])),
+            Object::Integer(0)
+        );
+        assert_eq!(
+            execute(&first, Command::Exists(vec![b"c".to_vec()])),
+            Object::Integer(1)
+        );
+        // The key a watcher saw is another one now
+        assert!(second
+            .execute_transaction(vec![], &watched)
+            .unwrap()
+            .is_none());
+        // Neither database hands out a version the other already used
+        execute(&first, set(b"d", b"v"));
+        assert_eq!(
+            execute(&first, Command::GetV(b"d".to_vec())),
+            Object::Array(vec![bulk(b"v"), Object::Integer(3)])
+        );
     }
 }
@@ -0,0 +1,257 @@
+use super::{
+    error, now_ms, parse_integer, scan, wrong_type, DatabaseResponse, DbResult, HashMapDatabase,
+    Value,
+};
+use crate::command::{KeyValue, ScanOptions};
+use crate::dict::Dict;
+use crate::object::Object;
+
+impl HashMapDatabase {
+    pub(super) fn hset(
+        &self,
+        key: Vec<u8>,
+        pairs: Vec<KeyValue>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let hash = match db.get_or_insert_with(&key, now_ms(), || Value::Hash(Dict::new())) {
+            Value::Hash(hash) => hash,
+            _ => return Ok(wrong_type()),
+        };
+        let mut added = 0;
+        for (field, value) in pairs {
+            if hash.insert(field, value).is_none() {
+                added += 1;
+            }
+        }
+        Ok(DatabaseResponse::dirty(Object::Integer(added)))
+    }
+
+    pub(super) fn hget(&self, key: Vec<u8>, field: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        let value = match self.db.read()?.get(&key, now_ms()) {
+            Some(Value::Hash(hash)) => hash.get(&field).cloned(),
+            Some(_) => return Ok(wrong_type()),
+            None => None,
+        };
+        Ok(DatabaseResponse::clean(Object::BulkString(value)))
+    }
+
+    pub(super) fn hmget(
+        &self,
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let db = self.db.read()?;
+        let hash = match db.get(&key, now_ms()) {
+            Some(Value::Hash(hash)) => Some(hash),
+            Some(_) => return Ok(wrong_type()),
+            None => None,
+        };
+        let values = fields
+            .iter()
+            .map(|field| Object::BulkString(hash.and_then(|hash| hash.get(field)).cloned()))
+            .collect();
+        Ok(DatabaseResponse::clean(Object::Array(values)))
+    }
+
+    pub(super) fn hdel(
+        &self,
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let hash = match db.get_mut(&key, now_ms()) {
+            Some(Value::Hash(hash)) => hash,
+            Some(_) => return Ok(wrong_type()),
+            None => return Ok(DatabaseResponse::clean(Object::Integer(0))),
+        };
+        let removed = fields
+            .iter()
+            .filter(|field| hash.remove(field).is_some())
+            .count();
+        if hash.is_empty() {
+            db.remove(&key);
+        }
+        Ok(DatabaseResponse {
+            object: Object::Integer(removed as i64),
+            is_dirty: removed > 0,
+            rewrite: Vec::new(),
+        })
+    }
+
+    pub(super) fn hgetall(&self, key: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        let object = match self.db.read()?.get(&key, now_ms()) {
+            Some(value @ Value::Hash(_)) => value.to_object(),
+            Some(_) => return Ok(wrong_type()),
+            None => Object::Map(Vec::new()),
+        };
+        Ok(DatabaseResponse::clean(object))
+    }
+
+    pub(super) fn hincr_by(
+        &self,
+        key: Vec<u8>,
+        field: Vec<u8>,
+        increment: i64,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let hash = match db.get_or_insert_with(&key, now_ms(), || Value::Hash(Dict::new())) {
+            Value::Hash(hash) => hash,
+            _ => return Ok(wrong_type()),
+        };
+        let current = match hash.get(&field) {
+            None => 0,
+            Some(value) => match parse_integer(value) {
+                Some(current) => current,
+                None => return Ok(error("ERR hash value is not an integer")),
+            },
+        };
+        let value = match current.checked_add(increment) {
+            Some(value) => value,
+            None => return Ok(error("ERR increment or decrement would overflow")),
+        };
+        hash.insert(field, value.to_string().into_bytes());
+        Ok(DatabaseResponse::dirty(Object::Integer(value)))
+    }
+
+    pub(super) fn hscan(
+        &self,
+        key: Vec<u8>,
+        options: ScanOptions,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let db = self.db.read()?;
+        let (cursor, fields) = match db.get(&key, now_ms()) {
+            Some(Value::Hash(hash)) => scan(options, |cursor, f| {
+                hash.scan(cursor, |field, value| f(field, Some(value)))
+            }),
+            Some(_) => return Ok(wrong_type()),
+            None => (0, Vec::new()),
+        };
+        Ok(DatabaseResponse::clean(Object::Array(vec![
+            Object::BulkString(Some(cursor.to_string().into_bytes())),
+            Object::Array(fields),
+        ])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::{Command, ScanOptions};
+    use crate::db::tests::{bulk, bytes, execute, pairs, scanned, set, Logged};
+    use crate::db::{wrong_type, Database, HashMapDatabase};
+    use crate::object::Object;
+
+    #[test]
+    fn hash_fields_are_scanned_with_their_values() {
+        let db = HashMapDatabase::new();
+        let pairs = (0..100)
+            .map(|i| {
+                (
+                    format!("field:{}", i).into_bytes(),
+                    i.to_string().into_bytes(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            execute(&db, Command::HSet(b"h".to_vec(), pairs)),
+            Object::Integer(100)
+        );
+        assert_eq!(
+            execute(&db, Command::HIncrBy(b"h".to_vec(), b"field:7".to_vec(), 3)),
+            Object::Integer(10)
+        );
+
+        let mut fields = Vec::new();
+        let mut options = ScanOptions {
+            cursor: 0,
+            pattern: Some(b"field:1*".to_vec()),
+            count: 10,
+        };
+        loop {
+            let reply = execute(&db, Command::HScan(b"h".to_vec(), options.clone()));
+            let (cursor, page) = scanned(reply);
+            for pair in page.chunks(2) {
+                fields.push((pair[0].clone(), pair[1].clone()));
+            }
+            if cursor == 0 {
+                break;
+            }
+            options.cursor = cursor as u64;
+        }
+        // field:1 and field:10 to field:19
+        assert_eq!(fields.len(), 11);
+        assert!(fields.contains(&(bulk(b"field:12"), bulk(b"12"))));
+    }
+
+    #[test]
+    fn hashes_check_types_and_their_counters() {
+        let mut db = Logged::new();
+        let options = ScanOptions {
+            cursor: 0,
+            pattern: None,
+            count: 10,
+        };
+        db.run(set(b"s", b"v"));
+        for cmd in [
+            Command::HSet(b"s".to_vec(), pairs(&[(b"f", b"v")])),
+            Command::HGet(b"s".to_vec(), b"f".to_vec()),
+            Command::HMGet(b"s".to_vec(), bytes(&[b"f"])),
+            Command::HDel(b"s".to_vec(), bytes(&[b"f"])),
+            Command::HGetAll(b"s".to_vec()),
+            Command::HIncrBy(b"s".to_vec(), b"f".to_vec(), 1),
+            Command::HScan(b"s".to_vec(), options),
+        ] {
+            assert_eq!(db.run(cmd), wrong_type().object);
+        }
+
+        let hset = |fields: &[(&[u8], &[u8])]| Command::HSet(b"h".to_vec(), pairs(fields));
+        assert_eq!(
+            db.run(hset(&[(b"n", b"1"), (b"f", b"v")])),
+            Object::Integer(2)
+        );
+        assert_eq!(
+            db.run(hset(&[(b"f", b"w"), (b"g", b"v")])),
+            Object::Integer(1)
+        );
+        assert_eq!(
+            db.run(Command::HIncrBy(b"h".to_vec(), b"f".to_vec(), 1)),
+            Object::Error("ERR hash value is not an integer".to_string())
+        );
+        assert_eq!(
+            db.run(Command::HIncrBy(b"h".to_vec(), b"n".to_vec(), i64::MAX)),
+            Object::Error("ERR increment or decrement would overflow".to_string())
+        );
+        assert_eq!(
+            db.run(Command::HMGet(b"h".to_vec(), bytes(&[b"n", b"f", b"x"]))),
+            Object::Array(vec![bulk(b"1"), bulk(b"w"), Object::BulkString(None)])
+        );
+
+        assert_eq!(
+            db.run(Command::HMGet(b"missing".to_vec(), bytes(&[b"f"]))),
+            Object::Array(vec![Object::BulkString(None)])
+        );
+        assert_eq!(
+            db.run(Command::HGetAll(b"missing".to_vec())),
+            Object::Map(Vec::new())
+        );
+        let response = db
+            .db
+            .execute(Command::HDel(b"h".to_vec(), bytes(&[b"x"])))
+            .unwrap();
+        assert_eq!(response.object, Object::Integer(0));
+        assert!(!response.is_dirty);
+        // Removing the last field removes the key
+        assert_eq!(
+            db.run(Command::HDel(
+                b"h".to_vec(),
+                bytes(&[b"n", b"f", b"g", b"g"])
+            )),
+            Object::Integer(3)
+        );
+        assert_eq!(
+            db.run(Command::Type(b"h".to_vec())),
+            Object::SimpleString("none".to_string())
+        );
+
+        db.assert_replays();
+    }
+}
@@ -0,0 +1,409 @@
+use super::{now_ms, wrong_type, DatabaseResponse, DbResult, HashMapDatabase, Value};
+use crate::command::{Command, End};
+use crate::object::Object;
+use std::collections::VecDeque;
+
+impl HashMapDatabase {
+    pub(super) fn push(
+        &self,
+        end: End,
+        key: Vec<u8>,
+        values: Vec<Vec<u8>>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let list = match db.get_or_insert_with(&key, now_ms(), || Value::List(VecDeque::new())) {
+            Value::List(list) => list,
+            _ => return Ok(wrong_type()),
+        };
+        for value in values {
+            push(list, end, value);
+        }
+        let len = list.len();
+        db.signal_ready(&key);
+        Ok(DatabaseResponse::dirty(Object::Integer(len as i64)))
+    }
+
+    pub(super) fn pop(
+        &self,
+        end: End,
+        key: Vec<u8>,
+        count: Option<usize>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let list = match db.get_mut(&key, now_ms()) {
+            Some(Value::List(list)) => list,
+            Some(_) => return Ok(wrong_type()),
+            None => return Ok(DatabaseResponse::clean(Object::Null)),
+        };
+        let mut popped = Vec::new();
+        while popped.len() < count.unwrap_or(1) {
+            match pop(list, end) {
+                Some(value) => popped.push(Object::BulkString(Some(value))),
+                None => break,
+            }
+        }
+        if list.is_empty() {
+            db.remove(&key);
+        }
+
+        let is_dirty = !popped.is_empty();
+        let object = match count {
+            Some(_) => Object::Array(popped),
+            None => popped.pop().unwrap_or(Object::Null),
+        };
+        Ok(DatabaseResponse {
+            object,
+            is_dirty,
+            rewrite: Vec::new(),
+        })
+    }
+
+    pub(super) fn lmove(
+        &self,
+        source: Vec<u8>,
+        destination: Vec<u8>,
+        from: End,
+        to: End,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        match db.get_mut(&source, now) {
+            Some(Value::List(_)) => {}
+            Some(_) => return Ok(wrong_type()),
+            None => return Ok(DatabaseResponse::clean(Object::Null)),
+        }
+        if let Some(value) = db.get_mut(&destination, now) {
+            if !matches!(value, Value::List(_)) {
+                return Ok(wrong_type());
+            }
+        }
+
+        let list = match db.get_mut(&source, now) {
+            Some(Value::List(list)) => list,
+            _ => unreachable!(),
+        };
+        let value = pop(list, from).expect("Lists in the database are never empty");
+        // A list rotated onto itself stays, even with a single value
+        if source == destination {
+            push(list, to, value.clone());
+        } else {
+            if list.is_empty() {
+                db.remove(&source);
+            }
+            match db.get_or_insert_with(&destination, now, || Value::List(VecDeque::new())) {
+                Value::List(list) => push(list, to, value.clone()),
+                _ => unreachable!(),
+            }
+        }
+        db.signal_ready(&destination);
+        Ok(DatabaseResponse::dirty(Object::BulkString(Some(value))))
+    }
+
+    /// Pops from the first of `keys` that holds a list, or replies with null if
+    /// there is none, in which case the client blocks.
+    pub(super) fn bpop(&self, end: End, keys: Vec<Vec<u8>>) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        for key in keys {
+            let list = match db.get_mut(&key, now) {
+                Some(Value::List(list)) => list,
+                Some(_) => return Ok(wrong_type()),
+                None => continue,
+            };
+            let value = pop(list, end).expect("Lists in the database are never empty");
+            if list.is_empty() {
+                db.remove(&key);
+            }
+            let reply = Object::Array(vec![
+                Object::BulkString(Some(key.clone())),
+                Object::BulkString(Some(value)),
+            ]);
+            return Ok(DatabaseResponse::dirty(reply).rewritten(Command::Pop(end, key, None)));
+        }
+        Ok(DatabaseResponse::clean(Object::Null))
+    }
+
+    pub(super) fn blmove(
+        &self,
+        source: Vec<u8>,
+        destination: Vec<u8>,
+        from: End,
+        to: End,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let rewrite = Command::LMove(source.clone(), destination.clone(), from, to);
+        let response = self.lmove(source, destination, from, to)?;
+        Ok(response.rewritten(rewrite))
+    }
+
+    pub(super) fn lrange(
+        &self,
+        key: Vec<u8>,
+        start: i64,
+        stop: i64,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let db = self.db.read()?;
+        let list = match db.get(&key, now_ms()) {
+            Some(Value::List(list)) => list,
+            Some(_) => return Ok(wrong_type()),
+            None => return Ok(DatabaseResponse::clean(Object::Array(Vec::new()))),
+        };
+        let values = match range(start, stop, list.len()) {
+            Some((start, stop)) => list
+                .range(start..=stop)
+                .map(|value| Object::BulkString(Some(value.clone())))
+                .collect(),
+            None => Vec::new(),
+        };
+        Ok(DatabaseResponse::clean(Object::Array(values)))
+    }
+
+    pub(super) fn llen(&self, key: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        let len = match self.db.read()?.get(&key, now_ms()) {
+            Some(Value::List(list)) => list.len(),
+            Some(_) => return Ok(wrong_type()),
+            None => 0,
+        };
+        Ok(DatabaseResponse::clean(Object::Integer(len as i64)))
+    }
+
+    pub(super) fn ltrim(
+        &self,
+        key: Vec<u8>,
+        start: i64,
+        stop: i64,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let list = match db.get_mut(&key, now_ms()) {
+            Some(Value::List(list)) => list,
+            Some(_) => return Ok(wrong_type()),
+            None => return Ok(DatabaseResponse::clean(ok())),
+        };
+        let len = list.len();
+        match range(start, stop, len) {
+            Some((start, stop)) => {
+                list.truncate(stop + 1);
+                list.drain(..start);
+            }
+            None => list.clear(),
+        }
+        let is_dirty = list.len() != len;
+        if list.is_empty() {
+            db.remove(&key);
+        }
+        Ok(DatabaseResponse {
+            object: ok(),
+            is_dirty,
+            rewrite: Vec::new(),
+        })
+    }
+}
+
+fn push(list: &mut VecDeque<Vec<u8>>, end: End, value: Vec<u8>) {
+    match end {
+        End::Left => list.push_front(value),
+        End::Right => list.push_back(value),
+    }
+}
+
+fn pop(list: &mut VecDeque<Vec<u8>>, end: End) -> Option<Vec<u8>> {
+    match end {
+        End::Left => list.pop_front(),
+        End::Right => list.pop_back(),
+    }
+}
+
+fn ok() -> Object {
+    Object::SimpleString("OK".to_string())
+}
+
+/// Resolves `LRANGE`-style indexes, where negative indexes count from the end,
+/// to an inclusive range of positions. Returns `None` if the range is empty.
+pub(super) fn range(start: i64, stop: i64, len: usize) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::range;
+    use crate::command::{Command, End, Expiry, SetOptions};
+    use crate::db::tests::{bulk, bytes, execute, ok, pairs, set, Logged};
+    use crate::db::{wrong_type, Database, HashMapDatabase};
+    use crate::object::Object;
+
+    #[test]
+    fn range_resolves_negative_indexes() {
+        assert_eq!(range(0, -1, 5), Some((0, 4)));
+        assert_eq!(range(-2, -1, 5), Some((3, 4)));
+        assert_eq!(range(-100, 100, 5), Some((0, 4)));
+        assert_eq!(range(3, 1, 5), None);
+        assert_eq!(range(5, 10, 5), None);
+        assert_eq!(range(0, -6, 5), None);
+        assert_eq!(range(0, 0, 0), None);
+    }
+
+    #[test]
+    fn lists_check_types_and_are_removed_once_empty() {
+        let mut db = Logged::new();
+        let type_of = |name: &str| Object::SimpleString(name.to_string());
+        db.run(set(b"s", b"v"));
+        db.run(Command::Push(
+            End::Right,
+            b"l".to_vec(),
+            bytes(&[b"a", b"b"]),
+        ));
+        for cmd in [
+            Command::Push(End::Left, b"s".to_vec(), bytes(&[b"a"])),
+            Command::LRange(b"s".to_vec(), 0, -1),
+            Command::LLen(b"s".to_vec()),
+            Command::Pop(End::Left, b"s".to_vec(), None),
+            Command::LTrim(b"s".to_vec(), 0, 1),
+        ] {
+            assert_eq!(db.run(cmd), wrong_type().object);
+        }
+
+        let response = db
+            .db
+            .execute(Command::Pop(End::Left, b"l".to_vec(), Some(0)))
+            .unwrap();
+        assert_eq!(response.object, Object::Array(Vec::new()));
+        assert!(!response.is_dirty);
+        assert_eq!(
+            db.run(Command::Pop(End::Right, b"l".to_vec(), Some(5))),
+            Object::Array(vec![bulk(b"b"), bulk(b"a")])
+        );
+        assert_eq!(db.run(Command::Type(b"l".to_vec())), type_of("none"));
+        assert_eq!(
+            db.run(Command::Pop(End::Right, b"l".to_vec(), Some(5))),
+            Object::Null
+        );
+        db.run(Command::Push(End::Left, b"l".to_vec(), bytes(&[b"a"])));
+        assert_eq!(db.run(Command::LTrim(b"l".to_vec(), 5, 10)), ok());
+        assert_eq!(db.run(Command::Type(b"l".to_vec())), type_of("none"));
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn string_commands_see_lists_as_another_type() {
+        let mut db = Logged::new();
+        let type_of = |name: &str| Object::SimpleString(name.to_string());
+        for key in [b"a", b"b", b"c"] {
+            db.run(Command::Push(End::Left, key.to_vec(), bytes(&[b"v"])));
+        }
+        db.run(set(b"s", b"v"));
+
+        for cmd in [
+            Command::Get(b"a".to_vec()),
+            Command::IncrBy(b"a".to_vec(), 1),
+            Command::IncrByFloat(b"a".to_vec(), 1.0),
+            Command::Set(
+                b"a".to_vec(),
+                b"v".to_vec(),
+                SetOptions {
+                    get: true,
+                    ..SetOptions::default()
+                },
+            ),
+        ] {
+            assert_eq!(db.run(cmd), wrong_type().object);
+        }
+        assert_eq!(
+            db.run(Command::MGet(bytes(&[b"a", b"s"]))),
+            Object::Array(vec![Object::Null, bulk(b"v")])
+        );
+        // Any existing key fails the whole MSETNX, whatever its type
+        assert_eq!(
+            db.run(Command::MSetNx(pairs(&[(b"x", b"1"), (b"a", b"1")]))),
+            Object::Integer(0)
+        );
+        assert_eq!(
+            db.run(Command::Copy(b"s".to_vec(), b"a".to_vec(), false)),
+            Object::Integer(0)
+        );
+        assert_eq!(db.run(Command::Type(b"a".to_vec())), type_of("list"));
+
+        // Strings replace lists wherever they may replace a key
+        assert_eq!(
+            db.run(Command::Copy(b"s".to_vec(), b"a".to_vec(), true)),
+            Object::Integer(1)
+        );
+        db.run(Command::MSet(pairs(&[(b"b", b"v")])));
+        assert_eq!(db.run(set(b"c", b"v")), ok());
+        for key in [b"a", b"b", b"c"] {
+            assert_eq!(db.run(Command::Type(key.to_vec())), type_of("string"));
+        }
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn lmove_checks_both_lists_and_keeps_a_list_rotated_onto_itself() {
+        let mut db = Logged::new();
+        let lmove = |source: &[u8], destination: &[u8]| {
+            Command::LMove(source.to_vec(), destination.to_vec(), End::Left, End::Right)
+        };
+        db.run(set(b"s", b"v"));
+        db.run(Command::Push(
+            End::Right,
+            b"l".to_vec(),
+            bytes(&[b"a", b"b"]),
+        ));
+
+        // Neither list is changed when the other is of another type
+        assert_eq!(db.run(lmove(b"l", b"s")), wrong_type().object);
+        assert_eq!(db.run(lmove(b"s", b"l")), wrong_type().object);
+        assert_eq!(db.run(Command::LLen(b"l".to_vec())), Object::Integer(2));
+        assert_eq!(db.run(lmove(b"missing", b"l")), Object::Null);
+
+        db.run(Command::Expire(b"l".to_vec(), Expiry::Ex(100)));
+        assert_eq!(db.run(lmove(b"l", b"l")), bulk(b"a"));
+        assert_eq!(db.run(Command::Ttl(b"l".to_vec())), Object::Integer(100));
+        assert_eq!(
+            db.run(Command::LRange(b"l".to_vec(), 0, -1)),
+            Object::Array(vec![bulk(b"b"), bulk(b"a")])
+        );
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn blocking_pops_signal_and_are_logged_as_pops() {
+        let db = HashMapDatabase::new();
+        let watched = bytes(&[b"a", b"b"]);
+        let response = db
+            .execute(Command::BPop(End::Left, watched.clone(), 0.0))
+            .unwrap();
+        assert_eq!(response.object, Object::Null);
+        assert!(!response.is_dirty);
+
+        execute(
+            &db,
+            Command::Push(End::Right, b"b".to_vec(), bytes(&[b"x"])),
+        );
+        assert_eq!(db.take_ready().unwrap(), [b"b".to_vec()]);
+        let response = db.execute(Command::BPop(End::Left, watched, 0.0)).unwrap();
+        assert_eq!(response.object, Object::Array(vec![bulk(b"b"), bulk(b"x")]));
+        assert!(matches!(
+            &response.rewrite[..],
+            [Command::Pop(End::Left, key, None)] if key == b"b"
+        ));
+        assert_eq!(
+            execute(&db, Command::Exists(bytes(&[b"b"]))),
+            Object::Integer(0)
+        );
+    }
+}
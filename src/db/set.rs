@@ -0,0 +1,316 @@
+use super::{now_ms, wrong_type, DatabaseResponse, DbResult, HashMapDatabase, Keyspace, Value};
+use crate::command::{Command, SetOp};
+use crate::dict::Dict;
+use crate::object::Object;
+
+impl HashMapDatabase {
+    pub(super) fn sadd(
+        &self,
+        key: Vec<u8>,
+        members: Vec<Vec<u8>>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let set = match db.get_or_insert_with(&key, now_ms(), || Value::Set(Dict::new())) {
+            Value::Set(set) => set,
+            _ => return Ok(wrong_type()),
+        };
+        let mut added = 0;
+        for member in members {
+            if set.insert(member, ()).is_none() {
+                added += 1;
+            }
+        }
+        Ok(DatabaseResponse {
+            object: Object::Integer(added),
+            is_dirty: added > 0,
+            rewrite: Vec::new(),
+        })
+    }
+
+    pub(super) fn srem(
+        &self,
+        key: Vec<u8>,
+        members: Vec<Vec<u8>>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let set = match db.get_mut(&key, now_ms()) {
+            Some(Value::Set(set)) => set,
+            Some(_) => return Ok(wrong_type()),
+            None => return Ok(DatabaseResponse::clean(Object::Integer(0))),
+        };
+        let removed = members
+            .iter()
+            .filter(|member| set.remove(member).is_some())
+            .count();
+        if set.is_empty() {
+            db.remove(&key);
+        }
+        Ok(DatabaseResponse {
+            object: Object::Integer(removed as i64),
+            is_dirty: removed > 0,
+            rewrite: Vec::new(),
+        })
+    }
+
+    pub(super) fn sismember(
+        &self,
+        key: Vec<u8>,
+        member: Vec<u8>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let is_member = match self.db.read()?.get(&key, now_ms()) {
+            Some(Value::Set(set)) => set.contains_key(&member),
+            Some(_) => return Ok(wrong_type()),
+            None => false,
+        };
+        Ok(DatabaseResponse::clean(Object::Integer(is_member as i64)))
+    }
+
+    pub(super) fn smembers(&self, key: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        self.set_op(SetOp::Union, vec![key])
+    }
+
+    pub(super) fn set_op(&self, op: SetOp, keys: Vec<Vec<u8>>) -> DbResult<'_, DatabaseResponse> {
+        let db = self.db.read()?;
+        let result = match combine(&db, op, &keys) {
+            Some(result) => result,
+            None => return Ok(wrong_type()),
+        };
+        Ok(DatabaseResponse::clean(Value::Set(result).to_object()))
+    }
+
+    /// Stores the result of `op` in `destination`, replacing whatever it held,
+    /// or deletes it if the result is empty. It is logged as the members stored.
+    pub(super) fn set_op_store(
+        &self,
+        op: SetOp,
+        destination: Vec<u8>,
+        keys: Vec<Vec<u8>>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let result = match combine(&db, op, &keys) {
+            Some(result) => result,
+            None => return Ok(wrong_type()),
+        };
+        let len = result.len();
+        let mut rewrite = Vec::new();
+        if db.remove(&destination).is_some() {
+            rewrite.push(Command::Del(vec![destination.clone()]));
+        }
+        if !result.is_empty() {
+            let members = result.iter().map(|(member, _)| member.to_vec()).collect();
+            rewrite.push(Command::SAdd(destination.clone(), members));
+            db.insert(destination, Value::Set(result), None);
+        }
+        Ok(DatabaseResponse {
+            object: Object::Integer(len as i64),
+            is_dirty: !rewrite.is_empty(),
+            rewrite,
+        })
+    }
+}
+
+/// Combines the sets stored at `keys`, where missing keys are empty sets.
+/// Returns `None` if one of the keys holds another type.
+fn combine(db: &Keyspace, op: SetOp, keys: &[Vec<u8>]) -> Option<Dict<()>> {
+    let now = now_ms();
+    let empty = Dict::new();
+    let mut sets = Vec::with_capacity(keys.len());
+    for key in keys {
+        match db.get(key, now) {
+            Some(Value::Set(set)) => sets.push(set),
+            Some(_) => return None,
+            None => sets.push(&empty),
+        }
+    }
+
+    let mut result = Dict::new();
+    match op {
+        SetOp::Inter => {
+            // Only the members of the smallest set can be in all of them
+            sets.sort_by_key(|set| set.len());
+            for (member, _) in sets[0].iter() {
+                if sets[1..].iter().all(|set| set.contains_key(member)) {
+                    result.insert(member.to_vec(), ());
+                }
+            }
+        }
+        SetOp::Union => {
+            for (member, _) in sets.iter().flat_map(|set| set.iter()) {
+                result.insert(member.to_vec(), ());
+            }
+        }
+        SetOp::Diff => {
+            for (member, _) in sets[0].iter() {
+                if !sets[1..].iter().any(|set| set.contains_key(member)) {
+                    result.insert(member.to_vec(), ());
+                }
+            }
+        }
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::{Command, SetOp};
+    use crate::db::tests::{bulk, bytes, execute, set, Logged};
+    use crate::db::{wrong_type, Database, HashMapDatabase};
+    use crate::object::Object;
+
+    fn members(members: &[&[u8]]) -> Object {
+        Object::Set(members.iter().map(|member| bulk(member)).collect())
+    }
+
+    #[test]
+    fn set_operations_store_their_result() {
+        let db = HashMapDatabase::new();
+        let sadd = |key: &[u8], members: &[&[u8]]| {
+            execute(&db, Command::SAdd(key.to_vec(), bytes(members)))
+        };
+        assert_eq!(sadd(b"a", &[b"1", b"2", b"3"]), Object::Integer(3));
+        assert_eq!(sadd(b"b", &[b"2", b"3", b"4", b"2"]), Object::Integer(3));
+
+        let store = |op, keys: &[&[u8]]| {
+            execute(&db, Command::SetOpStore(op, b"dst".to_vec(), bytes(keys)))
+        };
+        assert_eq!(store(SetOp::Inter, &[b"a", b"b"]), Object::Integer(2));
+        assert_eq!(
+            store(SetOp::Union, &[b"a", b"b", b"missing"]),
+            Object::Integer(4)
+        );
+        assert_eq!(store(SetOp::Diff, &[b"a", b"b"]), Object::Integer(1));
+        assert_eq!(
+            execute(&db, Command::SMembers(b"dst".to_vec())),
+            members(&[b"1"])
+        );
+
+        // An empty result deletes the destination
+        assert_eq!(store(SetOp::Inter, &[b"a", b"missing"]), Object::Integer(0));
+        assert_eq!(
+            execute(&db, Command::Exists(bytes(&[b"dst"]))),
+            Object::Integer(0)
+        );
+    }
+
+    #[test]
+    fn set_operations_are_logged_as_their_result() {
+        let mut db = Logged::new();
+        let store = |op, keys: &[&[u8]]| Command::SetOpStore(op, b"dst".to_vec(), bytes(keys));
+        db.run(Command::SAdd(b"a".to_vec(), bytes(&[b"1", b"2"])));
+        db.run(Command::SAdd(b"b".to_vec(), bytes(&[b"2"])));
+        db.run(set(b"dst", b"x"));
+        assert_eq!(
+            db.run(store(SetOp::Union, &[b"a", b"dst"])),
+            wrong_type().object
+        );
+        // The destination may hold any type, it is replaced
+        assert_eq!(
+            db.run(store(SetOp::Diff, &[b"a", b"b"])),
+            Object::Integer(1)
+        );
+        assert_eq!(
+            db.run(store(SetOp::Inter, &[b"a", b"b"])),
+            Object::Integer(1)
+        );
+        assert_eq!(
+            db.run(store(SetOp::Inter, &[b"a", b"missing"])),
+            Object::Integer(0)
+        );
+        // Nothing is left to delete
+        assert_eq!(
+            db.run(store(SetOp::Inter, &[b"a", b"missing"])),
+            Object::Integer(0)
+        );
+
+        let dst = |keys: &[Vec<u8>]| keys == [b"dst".to_vec()];
+        assert!(matches!(
+            &db.log[3..],
+            [
+                Command::Del(first),
+                Command::SAdd(second, diff),
+                Command::Del(third),
+                Command::SAdd(fourth, inter),
+                Command::Del(fifth),
+            ] if dst(first) && dst(third) && dst(fifth)
+                && second == b"dst"
+                && fourth == b"dst"
+                && diff == &[b"1".to_vec()]
+                && inter == &[b"2".to_vec()]
+        ));
+        db.assert_replays();
+    }
+
+    #[test]
+    fn set_commands_check_types_and_skip_expired_sources() {
+        let mut db = Logged::new();
+        db.run(set(b"s", b"v"));
+        db.run(Command::SAdd(b"a".to_vec(), bytes(&[b"1", b"2"])));
+        db.run(Command::SAdd(b"b".to_vec(), bytes(&[b"2", b"3"])));
+        for cmd in [
+            Command::SAdd(b"s".to_vec(), bytes(&[b"1"])),
+            Command::SRem(b"s".to_vec(), bytes(&[b"1"])),
+            Command::SIsMember(b"s".to_vec(), b"1".to_vec()),
+            Command::SMembers(b"s".to_vec()),
+            Command::SetOp(SetOp::Inter, bytes(&[b"a", b"s"])),
+            Command::SetOpStore(SetOp::Diff, b"a".to_vec(), bytes(&[b"a", b"s"])),
+        ] {
+            assert_eq!(db.run(cmd), wrong_type().object);
+        }
+        assert_eq!(
+            db.run(Command::SIsMember(b"a".to_vec(), b"1".to_vec())),
+            Object::Integer(1)
+        );
+
+        let response = db
+            .db
+            .execute(Command::SAdd(b"a".to_vec(), bytes(&[b"1", b"1"])))
+            .unwrap();
+        assert_eq!(response.object, Object::Integer(0));
+        assert!(!response.is_dirty);
+        assert_eq!(
+            db.run(Command::SetOp(SetOp::Inter, bytes(&[b"a", b"missing"]))),
+            members(&[])
+        );
+        assert_eq!(
+            db.run(Command::SetOp(SetOp::Diff, bytes(&[b"missing", b"a"]))),
+            members(&[])
+        );
+        // The destination can be one of the sources
+        assert_eq!(
+            db.run(Command::SetOpStore(
+                SetOp::Inter,
+                b"a".to_vec(),
+                bytes(&[b"a", b"b"])
+            )),
+            Object::Integer(1)
+        );
+        assert_eq!(db.run(Command::SMembers(b"a".to_vec())), members(&[b"2"]));
+
+        // An expired source is empty, and the result is logged as stored, so
+        // that the source being there on replay does not matter
+        db.expire_now(b"b");
+        assert_eq!(
+            db.run(Command::SetOpStore(
+                SetOp::Union,
+                b"dst".to_vec(),
+                bytes(&[b"b", b"missing"])
+            )),
+            Object::Integer(0)
+        );
+        assert_eq!(
+            db.run(Command::SIsMember(b"b".to_vec(), b"2".to_vec())),
+            Object::Integer(0)
+        );
+        assert_eq!(
+            db.run(Command::SRem(b"a".to_vec(), bytes(&[b"2", b"3"]))),
+            Object::Integer(1)
+        );
+        assert_eq!(db.run(Command::Del(bytes(&[b"b"]))), Object::Integer(0));
+        assert_eq!(
+            db.run(Command::Exists(bytes(&[b"a", b"b", b"dst"]))),
+            Object::Integer(0)
+        );
+
+        db.assert_replays();
+    }
+}
@@ -0,0 +1,657 @@
+use super::{error, now_ms, wrong_type, DatabaseResponse, DbResult, HashMapDatabase, Value};
+use crate::command::{
+    Command, KeyValue, StreamStart, XAddId, XClaimOptions, XGroup, XPendingRange, XReadOptions,
+};
+use crate::object::Object;
+use crate::stream::{Group, Pending, Stream, StreamId};
+use std::collections::BTreeMap;
+
+impl HashMapDatabase {
+    /// Adds an entry, logged with the ID it was given.
+    pub(super) fn xadd(
+        &self,
+        key: Vec<u8>,
+        id: XAddId,
+        pairs: Vec<KeyValue>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        let id = match db.get(&key, now) {
+            Some(Value::Stream(stream)) => new_id(stream, id, now),
+            Some(_) => return Ok(wrong_type()),
+            None => new_id(&Stream::new(), id, now),
+        };
+        let id = match id {
+            Ok(id) => id,
+            Err(message) => return Ok(error(message)),
+        };
+
+        let stream = match db.get_or_insert_with(&key, now, || Value::Stream(Stream::new())) {
+            Value::Stream(stream) => stream,
+            _ => unreachable!(),
+        };
+        stream.entries.insert(id, pairs.clone());
+        stream.last_id = id;
+        db.signal_ready(&key);
+        Ok(
+            DatabaseResponse::dirty(id_object(id)).rewritten(Command::XAdd(
+                key,
+                XAddId::Explicit(id),
+                pairs,
+            )),
+        )
+    }
+
+    pub(super) fn xrange(
+        &self,
+        key: Vec<u8>,
+        start: StreamId,
+        end: StreamId,
+        count: Option<usize>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let db = self.db.read()?;
+        let stream = match db.get(&key, now_ms()) {
+            Some(Value::Stream(stream)) if start <= end => stream,
+            Some(Value::Stream(_)) | None => {
+                return Ok(DatabaseResponse::clean(Object::Array(Vec::new())))
+            }
+            Some(_) => return Ok(wrong_type()),
+        };
+        let entries = stream
+            .entries
+            .range(start..=end)
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(id, fields)| entry(*id, Some(fields)))
+            .collect();
+        Ok(DatabaseResponse::clean(Object::Array(entries)))
+    }
+
+    /// Reads the entries after the given IDs. If there are none, the reply is
+    /// null and the rewrite is the read to retry, with `$` replaced by the
+    /// last IDs the streams have now.
+    pub(super) fn xread(&self, options: XReadOptions) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let db = self.db.read()?;
+        let mut reply = Vec::new();
+        let mut resolved = Vec::with_capacity(options.streams.len());
+        for (key, start) in &options.streams {
+            let stream = match db.get(key, now) {
+                Some(Value::Stream(stream)) => Some(stream),
+                Some(_) => return Ok(wrong_type()),
+                None => None,
+            };
+            let after = match start {
+                StreamStart::After(id) => *id,
+                StreamStart::Latest => stream.map_or(StreamId::MIN, |stream| stream.last_id),
+                StreamStart::Undelivered => unreachable!("XREAD does not parse >"),
+            };
+            resolved.push((key.clone(), StreamStart::After(after)));
+            let entries: Vec<_> = stream
+                .into_iter()
+                .flat_map(|stream| stream.after(after))
+                .take(options.count.unwrap_or(usize::MAX))
+                .map(|(id, fields)| entry(*id, Some(fields)))
+                .collect();
+            if !entries.is_empty() {
+                reply.push(stream_object(key, entries));
+            }
+        }
+
+        if reply.is_empty() {
+            let retry = XReadOptions {
+                streams: resolved,
+                ..options
+            };
+            return Ok(DatabaseResponse::clean(Object::Null).rewritten(Command::XRead(retry)));
+        }
+        Ok(DatabaseResponse::clean(Object::Array(reply)))
+    }
+
+    /// Reads streams as a consumer of a group. `>` delivers the entries the
+    /// group has not delivered yet, which become pending for the consumer
+    /// unless `NOACK` is given, and an ID reads back the entries pending for
+    /// the consumer after it.
+    ///
+    /// Deliveries are logged as the `XCLAIM` that reproduces them, or as an
+    /// `XGROUP SETID` without pending entries.
+    pub(super) fn xreadgroup(
+        &self,
+        group_name: Vec<u8>,
+        consumer: Vec<u8>,
+        options: XReadOptions,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        // Nothing is delivered unless every stream has the group
+        for (key, _) in &options.streams {
+            match db.get(key, now) {
+                Some(Value::Stream(stream)) if stream.groups.contains_key(&group_name) => {}
+                Some(Value::Stream(_)) | None => {
+                    return Ok(error(&format!(
+                        "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                        String::from_utf8_lossy(key),
+                        String::from_utf8_lossy(&group_name)
+                    )))
+                }
+                Some(_) => return Ok(wrong_type()),
+            }
+        }
+
+        let count = options.count.unwrap_or(usize::MAX);
+        let mut reply = Vec::new();
+        let mut rewrite = Vec::new();
+        for (key, start) in options.streams {
+            let stream = match db.get_mut(&key, now) {
+                Some(Value::Stream(stream)) => stream,
+                _ => unreachable!(),
+            };
+            let ids: Vec<StreamId> = match start {
+                StreamStart::Undelivered => {
+                    let group = &stream.groups[&group_name];
+                    let ids: Vec<_> = stream
+                        .after(group.last_delivered)
+                        .take(count)
+                        .map(|(id, _)| *id)
+                        .collect();
+                    let last = match ids.last() {
+                        Some(&last) => last,
+                        None => continue,
+                    };
+                    let group = stream.groups.get_mut(&group_name).unwrap();
+                    group.last_delivered = last;
+                    if options.noack {
+                        rewrite.push(Command::XGroup(XGroup::SetId(
+                            key.clone(),
+                            group_name.clone(),
+                            StreamStart::After(last),
+                        )));
+                    } else {
+                        for id in &ids {
+                            let pending = Pending {
+                                consumer: consumer.clone(),
+                                delivered_at: now,
+                                deliveries: 1,
+                            };
+                            group.pending.insert(*id, pending);
+                        }
+                        let options = XClaimOptions {
+                            time: Some(now),
+                            retry_count: Some(1),
+                            force: true,
+                            justid: true,
+                            last_id: Some(last),
+                            ..XClaimOptions::default()
+                        };
+                        rewrite.push(Command::XClaim(
+                            key.clone(),
+                            group_name.clone(),
+                            consumer.clone(),
+                            0,
+                            ids.clone(),
+                            options,
+                        ));
+                    }
+                    ids
+                }
+                StreamStart::After(after) => stream.groups[&group_name]
+                    .pending
+                    .range(after..)
+                    .filter(|(id, pending)| **id > after && pending.consumer == consumer)
+                    .take(count)
+                    .map(|(id, _)| *id)
+                    .collect(),
+                StreamStart::Latest => unreachable!("XREADGROUP does not parse $"),
+            };
+            let entries = ids
+                .into_iter()
+                .map(|id| entry(id, stream.entries.get(&id)))
+                .collect();
+            reply.push(stream_object(&key, entries));
+        }
+
+        if reply.is_empty() {
+            return Ok(DatabaseResponse::clean(Object::Null));
+        }
+        Ok(DatabaseResponse {
+            object: Object::Array(reply),
+            is_dirty: !rewrite.is_empty(),
+            rewrite,
+        })
+    }
+
+    /// Creates, moves or destroys a group, where `$` is logged as the ID it
+    /// stood for.
+    pub(super) fn xgroup(&self, xgroup: XGroup) -> DbResult<'_, DatabaseResponse> {
+        const NO_KEY: &str = "ERR The XGROUP subcommand requires the key to exist. Note that \
+            for CREATE you may want to use the MKSTREAM option to create an empty stream \
+            automatically.";
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        let (key, name) = match &xgroup {
+            XGroup::Create(key, name, _, _)
+            | XGroup::SetId(key, name, _)
+            | XGroup::Destroy(key, name) => (key.clone(), name.clone()),
+        };
+        let stream = match (db.get_mut(&key, now), &xgroup) {
+            (Some(Value::Stream(stream)), _) => stream,
+            (Some(_), _) => return Ok(wrong_type()),
+            (None, XGroup::Create(_, _, _, true)) => {
+                match db.get_or_insert_with(&key, now, || Value::Stream(Stream::new())) {
+                    Value::Stream(stream) => stream,
+                    _ => unreachable!(),
+                }
+            }
+            (None, _) => return Ok(error(NO_KEY)),
+        };
+        let resolve = |start: StreamStart, stream: &Stream| match start {
+            StreamStart::After(id) => id,
+            _ => stream.last_id,
+        };
+
+        match xgroup {
+            XGroup::Create(_, _, start, mkstream) => {
+                if stream.groups.contains_key(&name) {
+                    return Ok(error("BUSYGROUP Consumer Group name already exists"));
+                }
+                let id = resolve(start, stream);
+                let group = Group {
+                    last_delivered: id,
+                    ..Group::default()
+                };
+                stream.groups.insert(name.clone(), group);
+                Ok(
+                    DatabaseResponse::dirty(Object::SimpleString("OK".to_string())).rewritten(
+                        Command::XGroup(XGroup::Create(
+                            key,
+                            name,
+                            StreamStart::After(id),
+                            mkstream,
+                        )),
+                    ),
+                )
+            }
+            XGroup::SetId(_, _, start) => {
+                let id = resolve(start, stream);
+                match stream.groups.get_mut(&name) {
+                    Some(group) => group.last_delivered = id,
+                    None => {
+                        return Ok(error(&format!(
+                            "NOGROUP No such consumer group '{}' for key name '{}'",
+                            String::from_utf8_lossy(&name),
+                            String::from_utf8_lossy(&key)
+                        )))
+                    }
+                }
+                Ok(
+                    DatabaseResponse::dirty(Object::SimpleString("OK".to_string())).rewritten(
+                        Command::XGroup(XGroup::SetId(key, name, StreamStart::After(id))),
+                    ),
+                )
+            }
+            XGroup::Destroy(_, _) => {
+                let destroyed = stream.groups.remove(&name).is_some();
+                Ok(DatabaseResponse {
+                    object: Object::Integer(destroyed as i64),
+                    is_dirty: destroyed,
+                    rewrite: Vec::new(),
+                })
+            }
+        }
+    }
+
+    pub(super) fn xack(
+        &self,
+        key: Vec<u8>,
+        group: Vec<u8>,
+        ids: Vec<StreamId>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let group = match db.get_mut(&key, now_ms()) {
+            Some(Value::Stream(stream)) => stream.groups.get_mut(&group),
+            Some(_) => return Ok(wrong_type()),
+            None => None,
+        };
+        let acked = match group {
+            Some(group) => ids
+                .iter()
+                .filter(|id| group.pending.remove(id).is_some())
+                .count(),
+            None => 0,
+        };
+        Ok(DatabaseResponse {
+            object: Object::Integer(acked as i64),
+            is_dirty: acked > 0,
+            rewrite: Vec::new(),
+        })
+    }
+
+    /// Summarizes the pending entries of a group, or lists those in a range.
+    pub(super) fn xpending(
+        &self,
+        key: Vec<u8>,
+        group_name: Vec<u8>,
+        range: Option<XPendingRange>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let db = self.db.read()?;
+        let group = match db.get(&key, now) {
+            Some(Value::Stream(stream)) => stream.groups.get(&group_name),
+            Some(_) => return Ok(wrong_type()),
+            None => None,
+        };
+        let group = match group {
+            Some(group) => group,
+            None => return Ok(no_group(&key, &group_name)),
+        };
+
+        let range = match range {
+            Some(range) => range,
+            None => return Ok(DatabaseResponse::clean(summary(group))),
+        };
+        if range.start > range.end {
+            return Ok(DatabaseResponse::clean(Object::Array(Vec::new())));
+        }
+        let entries = group
+            .pending
+            .range(range.start..=range.end)
+            .filter(|(_, pending)| {
+                range
+                    .consumer
+                    .as_ref()
+                    .is_none_or(|consumer| pending.consumer == *consumer)
+            })
+            .filter(|(_, pending)| {
+                range
+                    .idle
+                    .is_none_or(|idle| now - pending.delivered_at >= idle)
+            })
+            .take(range.count)
+            .map(|(id, pending)| {
+                Object::Array(vec![
+                    id_object(*id),
+                    Object::BulkString(Some(pending.consumer.clone())),
+                    Object::Integer((now - pending.delivered_at).max(0)),
+                    Object::Integer(pending.deliveries as i64),
+                ])
+            })
+            .collect();
+        Ok(DatabaseResponse::clean(Object::Array(entries)))
+    }
+
+    /// Gives pending entries that have been idle long enough to a consumer.
+    pub(super) fn xclaim(
+        &self,
+        key: Vec<u8>,
+        group_name: Vec<u8>,
+        consumer: Vec<u8>,
+        min_idle: i64,
+        ids: Vec<StreamId>,
+        options: XClaimOptions,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        let stream = match db.get_mut(&key, now) {
+            Some(Value::Stream(stream)) => stream,
+            Some(_) => return Ok(wrong_type()),
+            None => return Ok(no_group(&key, &group_name)),
+        };
+        let group = match stream.groups.get_mut(&group_name) {
+            Some(group) => group,
+            None => return Ok(no_group(&key, &group_name)),
+        };
+
+        let moved = match options.last_id {
+            Some(last_id) if last_id > group.last_delivered => {
+                group.last_delivered = last_id;
+                true
+            }
+            _ => false,
+        };
+        let delivered_at = match (options.time, options.idle) {
+            (Some(time), _) => time,
+            (None, Some(idle)) => now - idle,
+            (None, None) => now,
+        };
+        // The claimed IDs by the delivery count they end up with
+        let mut claimed: BTreeMap<u64, Vec<StreamId>> = BTreeMap::new();
+        let mut reply = Vec::new();
+        for id in ids {
+            let fields = match stream.entries.get(&id) {
+                Some(fields) => fields,
+                None => continue,
+            };
+            match group.pending.get(&id) {
+                Some(pending) if min_idle > 0 && now - pending.delivered_at < min_idle => continue,
+                Some(_) => {}
+                None if options.force => {
+                    let pending = Pending {
+                        consumer: consumer.clone(),
+                        delivered_at,
+                        deliveries: 0,
+                    };
+                    group.pending.insert(id, pending);
+                }
+                None => continue,
+            }
+            let pending = group.pending.get_mut(&id).unwrap();
+            pending.consumer = consumer.clone();
+            pending.delivered_at = delivered_at;
+            pending.deliveries = match options.retry_count {
+                Some(count) => count,
+                None if options.justid => pending.deliveries,
+                None => pending.deliveries + 1,
+            };
+            claimed.entry(pending.deliveries).or_default().push(id);
+            reply.push(match options.justid {
+                true => id_object(id),
+                false => entry(id, Some(fields)),
+            });
+        }
+
+        let mut rewrite = Vec::new();
+        for (deliveries, ids) in claimed {
+            let options = XClaimOptions {
+                time: Some(delivered_at),
+                retry_count: Some(deliveries),
+                force: true,
+                justid: true,
+                last_id: options.last_id,
+                ..XClaimOptions::default()
+            };
+            let cmd = Command::XClaim(
+                key.clone(),
+                group_name.clone(),
+                consumer.clone(),
+                0,
+                ids,
+                options,
+            );
+            rewrite.push(cmd);
+        }
+        if rewrite.is_empty() && moved {
+            let last = StreamStart::After(group.last_delivered);
+            rewrite.push(Command::XGroup(XGroup::SetId(key, group_name, last)));
+        }
+        Ok(DatabaseResponse {
+            object: Object::Array(reply),
+            is_dirty: !rewrite.is_empty(),
+            rewrite,
+        })
+    }
+}
+
+/// Works out the ID of a new entry, which has to be greater than the last one.
+fn new_id(stream: &Stream, id: XAddId, now: i64) -> Result<StreamId, &'static str> {
+    const TOO_SMALL: &str =
+        "ERR The ID specified in XADD is equal or smaller than the target stream top item";
+    let last = stream.last_id;
+    let id = match id {
+        XAddId::Auto => stream
+            .next_id(now)
+            .ok_or("ERR The stream has exhausted the last possible ID, unable to add more items")?,
+        XAddId::Seq(ms) if ms == last.ms => {
+            last.next().filter(|id| id.ms == ms).ok_or(TOO_SMALL)?
+        }
+        XAddId::Seq(ms) => StreamId::new(ms, 0),
+        XAddId::Explicit(id) => id,
+    };
+    if id == StreamId::MIN {
+        return Err("ERR The ID specified in XADD must be greater than 0-0");
+    }
+    if id <= last {
+        return Err(TOO_SMALL);
+    }
+    Ok(id)
+}
+
+/// The `XPENDING` summary: the number of pending entries, the lowest and
+/// highest of their IDs, and how many each consumer has.
+fn summary(group: &Group) -> Object {
+    let (first, last) = match (group.pending.keys().next(), group.pending.keys().last()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => {
+            return Object::Array(vec![
+                Object::Integer(0),
+                Object::Null,
+                Object::Null,
+                Object::Null,
+            ])
+        }
+    };
+    let mut consumers: BTreeMap<&[u8], usize> = BTreeMap::new();
+    for pending in group.pending.values() {
+        *consumers.entry(&pending.consumer).or_default() += 1;
+    }
+    Object::Array(vec![
+        Object::Integer(group.pending.len() as i64),
+        id_object(first),
+        id_object(last),
+        Object::Array(
+            consumers
+                .into_iter()
+                .map(|(consumer, count)| {
+                    Object::Array(vec![
+                        Object::BulkString(Some(consumer.to_vec())),
+                        Object::BulkString(Some(count.to_string().into_bytes())),
+                    ])
+                })
+                .collect(),
+        ),
+    ])
+}
+
+fn no_group(key: &[u8], group: &[u8]) -> DatabaseResponse {
+    error(&format!(
+        "NOGROUP No such key '{}' or consumer group '{}'",
+        String::from_utf8_lossy(key),
+        String::from_utf8_lossy(group)
+    ))
+}
+
+fn id_object(id: StreamId) -> Object {
+    Object::BulkString(Some(id.to_bytes()))
+}
+
+/// An entry as an array of its ID and its fields and values, which are null
+/// for an entry that no longer exists.
+pub(super) fn entry(id: StreamId, fields: Option<&Vec<KeyValue>>) -> Object {
+    let fields = fields.map_or(Object::Null, |fields| {
+        Object::Array(
+            fields
+                .iter()
+                .flat_map(|(field, value)| {
+                    [
+                        Object::BulkString(Some(field.clone())),
+                        Object::BulkString(Some(value.clone())),
+                    ]
+                })
+                .collect(),
+        )
+    });
+    Object::Array(vec![id_object(id), fields])
+}
+
+fn stream_object(key: &[u8], entries: Vec<Object>) -> Object {
+    Object::Array(vec![
+        Object::BulkString(Some(key.to_vec())),
+        Object::Array(entries),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::{Command, StreamStart, XAddId, XClaimOptions, XGroup, XReadOptions};
+    use crate::db::tests::{bulk, Logged};
+    use crate::db::{now_ms, Value};
+    use crate::object::Object;
+    use crate::stream::StreamId;
+
+    #[test]
+    fn stream_groups_are_rebuilt_from_their_log() {
+        let mut db = Logged::new();
+        let add = |id| Command::XAdd(b"s".to_vec(), id, vec![(b"f".to_vec(), b"v".to_vec())]);
+        let read = |consumer: &[u8], start| {
+            let options = XReadOptions {
+                streams: vec![(b"s".to_vec(), start)],
+                ..XReadOptions::default()
+            };
+            Command::XReadGroup(b"g".to_vec(), consumer.to_vec(), options)
+        };
+
+        db.run(add(XAddId::Explicit(StreamId::new(5, 0))));
+        assert_eq!(db.run(add(XAddId::Seq(5))), bulk(b"5-1"));
+        assert_eq!(
+            db.run(add(XAddId::Seq(4))),
+            Object::Error(
+                "ERR The ID specified in XADD is equal or smaller than the target stream top item"
+                    .to_string()
+            )
+        );
+        db.run(Command::XGroup(XGroup::Create(
+            b"s".to_vec(),
+            b"g".to_vec(),
+            StreamStart::After(StreamId::MIN),
+            false,
+        )));
+        db.run(read(b"alice", StreamStart::Undelivered));
+        db.run(add(XAddId::Auto));
+        db.run(read(b"bob", StreamStart::Undelivered));
+        assert_eq!(db.run(read(b"bob", StreamStart::Undelivered)), Object::Null);
+        db.run(Command::XAck(
+            b"s".to_vec(),
+            b"g".to_vec(),
+            vec![StreamId::new(5, 0)],
+        ));
+        db.run(Command::XClaim(
+            b"s".to_vec(),
+            b"g".to_vec(),
+            b"bob".to_vec(),
+            0,
+            vec![StreamId::new(5, 1)],
+            XClaimOptions::default(),
+        ));
+
+        // Bob's history holds his own delivery and the one claimed from Alice
+        let history = match db.run(read(b"bob", StreamStart::After(StreamId::MIN))) {
+            Object::Array(streams) => streams,
+            reply => panic!("unexpected reply {:?}", reply),
+        };
+        assert!(matches!(&history[..], [Object::Array(stream)] if matches!(
+            &stream[1], Object::Array(entries) if entries.len() == 2
+        )));
+        {
+            let keyspace = db.db.db.read().unwrap();
+            let stream = match keyspace.get(b"s", now_ms()) {
+                Some(Value::Stream(stream)) => stream,
+                value => panic!("unexpected value {:?}", value),
+            };
+            let pending = &stream.groups[&b"g".to_vec()].pending[&StreamId::new(5, 1)];
+            assert_eq!(
+                (&pending.consumer[..], pending.deliveries),
+                (&b"bob"[..], 2)
+            );
+        }
+
+        db.assert_replays();
+    }
+}
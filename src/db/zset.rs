@@ -0,0 +1,543 @@
+use super::list::range;
+use super::{error, now_ms, wrong_type, DatabaseResponse, DbResult, HashMapDatabase, Value};
+use crate::command::{Comparison, Condition, ZAddOptions, ZRangeBy, ZRangeOptions};
+use crate::object::Object;
+use crate::zset::ZSet;
+
+impl HashMapDatabase {
+    pub(super) fn zadd(
+        &self,
+        key: Vec<u8>,
+        options: ZAddOptions,
+        members: Vec<(f64, Vec<u8>)>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let now = now_ms();
+        let mut db = self.db.write()?;
+        match db.get_mut(&key, now) {
+            Some(Value::ZSet(_)) => {}
+            Some(_) => return Ok(wrong_type()),
+            // There is nothing for XX to update, and the key is not created
+            None if options.condition == Some(Condition::Xx) => {
+                let object = match options.incr {
+                    true => Object::Null,
+                    false => Object::Integer(0),
+                };
+                return Ok(DatabaseResponse::clean(object));
+            }
+            None => {}
+        }
+        let zset = match db.get_or_insert_with(&key, now, || Value::ZSet(ZSet::new())) {
+            Value::ZSet(zset) => zset,
+            _ => unreachable!(),
+        };
+
+        let (mut added, mut changed) = (0, 0);
+        let mut last_score = None;
+        for (score, member) in members {
+            let score = match zset.score(&member) {
+                Some(_) if options.condition == Some(Condition::Nx) => continue,
+                Some(old) => {
+                    let new = if options.incr { old + score } else { score };
+                    if new.is_nan() {
+                        return Ok(error("ERR resulting score is not a number (NaN)"));
+                    }
+                    match options.comparison {
+                        Some(Comparison::Gt) if new <= old => continue,
+                        Some(Comparison::Lt) if new >= old => continue,
+                        _ => {}
+                    }
+                    if new != old {
+                        zset.insert(member, new);
+                        changed += 1;
+                    }
+                    new
+                }
+                None if options.condition == Some(Condition::Xx) => continue,
+                None => {
+                    zset.insert(member, score);
+                    added += 1;
+                    score
+                }
+            };
+            last_score = Some(score);
+        }
+
+        let object = match (options.incr, options.ch) {
+            (true, _) => last_score.map_or(Object::Null, Object::Double),
+            (false, true) => Object::Integer(added + changed),
+            (false, false) => Object::Integer(added),
+        };
+        Ok(DatabaseResponse {
+            object,
+            is_dirty: added + changed > 0,
+            rewrite: Vec::new(),
+        })
+    }
+
+    pub(super) fn zscore(&self, key: Vec<u8>, member: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        let score = match self.db.read()?.get(&key, now_ms()) {
+            Some(Value::ZSet(zset)) => zset.score(&member),
+            Some(_) => return Ok(wrong_type()),
+            None => None,
+        };
+        Ok(DatabaseResponse::clean(
+            score.map_or(Object::Null, Object::Double),
+        ))
+    }
+
+    pub(super) fn zrank(&self, key: Vec<u8>, member: Vec<u8>) -> DbResult<'_, DatabaseResponse> {
+        let rank = match self.db.read()?.get(&key, now_ms()) {
+            Some(Value::ZSet(zset)) => zset.rank(&member),
+            Some(_) => return Ok(wrong_type()),
+            None => None,
+        };
+        Ok(DatabaseResponse::clean(
+            rank.map_or(Object::Null, |rank| Object::Integer(rank as i64)),
+        ))
+    }
+
+    pub(super) fn zrange(
+        &self,
+        key: Vec<u8>,
+        options: ZRangeOptions,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let db = self.db.read()?;
+        let zset = match db.get(&key, now_ms()) {
+            Some(Value::ZSet(zset)) => zset,
+            Some(_) => return Ok(wrong_type()),
+            None => return Ok(DatabaseResponse::clean(Object::Array(Vec::new()))),
+        };
+
+        let rev = options.rev;
+        let members: Box<dyn Iterator<Item = (&[u8], f64)>> = match options.by {
+            ZRangeBy::Index(start, stop) => match range(start, stop, zset.len()) {
+                Some((start, stop)) => {
+                    // Ranks count from the highest score in reverse
+                    let first = if rev { zset.len() - 1 - start } else { start };
+                    Box::new(zset.iter_from_rank(first, rev).take(stop - start + 1))
+                }
+                None => Box::new(std::iter::empty()),
+            },
+            ZRangeBy::Score(min, max) => Box::new(between(
+                zset,
+                move |score, _| min.below_min(score),
+                move |score, _| max.above_max(score),
+                rev,
+            )),
+            ZRangeBy::Lex(min, max) => Box::new(between(
+                zset,
+                move |_, member| min.below_min(member),
+                move |_, member| max.above_max(member),
+                rev,
+            )),
+        };
+        let members: Box<dyn Iterator<Item = _>> = match options.limit {
+            Some((offset, _)) if offset < 0 => Box::new(std::iter::empty()),
+            Some((offset, count)) if count >= 0 => {
+                Box::new(members.skip(offset as usize).take(count as usize))
+            }
+            Some((offset, _)) => Box::new(members.skip(offset as usize)),
+            None => members,
+        };
+
+        let mut reply = Vec::new();
+        for (member, score) in members {
+            reply.push(Object::BulkString(Some(member.to_vec())));
+            if options.with_scores {
+                reply.push(Object::Double(score));
+            }
+        }
+        Ok(DatabaseResponse::clean(Object::Array(reply)))
+    }
+
+    pub(super) fn zrem(
+        &self,
+        key: Vec<u8>,
+        members: Vec<Vec<u8>>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let zset = match db.get_mut(&key, now_ms()) {
+            Some(Value::ZSet(zset)) => zset,
+            Some(_) => return Ok(wrong_type()),
+            None => return Ok(DatabaseResponse::clean(Object::Integer(0))),
+        };
+        let mut removed = 0;
+        for member in members {
+            if zset.remove(&member).is_some() {
+                removed += 1;
+            }
+        }
+        if zset.is_empty() {
+            db.remove(&key);
+        }
+        Ok(DatabaseResponse {
+            object: Object::Integer(removed),
+            is_dirty: removed > 0,
+            rewrite: Vec::new(),
+        })
+    }
+
+    pub(super) fn zpopmin(
+        &self,
+        key: Vec<u8>,
+        count: Option<usize>,
+    ) -> DbResult<'_, DatabaseResponse> {
+        let mut db = self.db.write()?;
+        let zset = match db.get_mut(&key, now_ms()) {
+            Some(Value::ZSet(zset)) => zset,
+            Some(_) => return Ok(wrong_type()),
+            None => return Ok(DatabaseResponse::clean(Object::Array(Vec::new()))),
+        };
+        let mut popped = Vec::new();
+        for _ in 0..count.unwrap_or(1) {
+            match zset.pop_min() {
+                Some((member, score)) => {
+                    popped.push(Object::BulkString(Some(member)));
+                    popped.push(Object::Double(score));
+                }
+                None => break,
+            }
+        }
+        if zset.is_empty() {
+            db.remove(&key);
+        }
+        let is_dirty = !popped.is_empty();
+        Ok(DatabaseResponse {
+            object: Object::Array(popped),
+            is_dirty,
+            rewrite: Vec::new(),
+        })
+    }
+}
+
+/// Iterates over the members between a minimum and a maximum, which the
+/// members below and above are told apart by, from the maximum if `rev` is set.
+fn between<'a>(
+    zset: &'a ZSet,
+    below_min: impl Fn(f64, &[u8]) -> bool + 'a,
+    above_max: impl Fn(f64, &[u8]) -> bool + 'a,
+    rev: bool,
+) -> Box<dyn Iterator<Item = (&'a [u8], f64)> + 'a> {
+    match rev {
+        false => Box::new(
+            zset.iter_from(&below_min, false)
+                .take_while(move |&(member, score)| !above_max(score, member)),
+        ),
+        true => Box::new(
+            zset.iter_from(|score, member| !above_max(score, member), true)
+                .take_while(move |&(member, score)| !below_min(score, member)),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::{
+        Command, Comparison, Condition, LexBound, ScoreBound, ZAddOptions, ZRangeBy, ZRangeOptions,
+    };
+    use crate::db::tests::{bulk, bytes, set, Logged};
+    use crate::db::{wrong_type, Database};
+    use crate::object::Object;
+
+    fn zadd(key: &[u8], options: ZAddOptions, members: &[(f64, &[u8])]) -> Command {
+        let members = members
+            .iter()
+            .map(|(score, member)| (*score, member.to_vec()))
+            .collect();
+        Command::ZAdd(key.to_vec(), options, members)
+    }
+
+    fn zrange(key: &[u8], by: ZRangeBy, rev: bool, limit: Option<(i64, i64)>) -> Command {
+        let options = ZRangeOptions {
+            by,
+            rev,
+            limit,
+            with_scores: false,
+        };
+        Command::ZRange(key.to_vec(), options)
+    }
+
+    fn members(members: &[&[u8]]) -> Object {
+        Object::Array(members.iter().map(|member| bulk(member)).collect())
+    }
+
+    #[test]
+    fn zadd_options_choose_what_is_updated_and_counted() {
+        let mut db = Logged::new();
+        let plain = ZAddOptions::default();
+        let ch = ZAddOptions { ch: true, ..plain };
+        assert_eq!(
+            db.run(zadd(b"z", plain, &[(1.0, b"a"), (2.0, b"b")])),
+            Object::Integer(2)
+        );
+        // Only added members are counted, unless CH counts changed ones too
+        assert_eq!(
+            db.run(zadd(b"z", plain, &[(1.5, b"a"), (3.0, b"c")])),
+            Object::Integer(1)
+        );
+        assert_eq!(
+            db.run(zadd(b"z", ch, &[(1.0, b"a"), (2.0, b"b"), (4.0, b"d")])),
+            Object::Integer(2)
+        );
+
+        let nx = ZAddOptions {
+            condition: Some(Condition::Nx),
+            ..ch
+        };
+        assert_eq!(
+            db.run(zadd(b"z", nx, &[(9.0, b"a"), (5.0, b"e")])),
+            Object::Integer(1)
+        );
+        let xx = ZAddOptions {
+            condition: Some(Condition::Xx),
+            ..ch
+        };
+        assert_eq!(
+            db.run(zadd(b"z", xx, &[(9.0, b"a"), (6.0, b"f")])),
+            Object::Integer(1)
+        );
+        // GT and LT only compare with scores that are there already
+        let gt = ZAddOptions {
+            comparison: Some(Comparison::Gt),
+            ..ch
+        };
+        assert_eq!(
+            db.run(zadd(b"z", gt, &[(1.0, b"a"), (3.0, b"b"), (0.5, b"g")])),
+            Object::Integer(2)
+        );
+        let lt = ZAddOptions {
+            comparison: Some(Comparison::Lt),
+            ..ch
+        };
+        assert_eq!(
+            db.run(zadd(b"z", lt, &[(1.0, b"a"), (4.0, b"b")])),
+            Object::Integer(1)
+        );
+
+        // INCR replies with the new score, or nothing for a skipped member
+        let incr = ZAddOptions {
+            incr: true,
+            ..plain
+        };
+        assert_eq!(
+            db.run(zadd(b"z", incr, &[(2.5, b"a")])),
+            Object::Double(3.5)
+        );
+        let incr_nx = ZAddOptions { incr: true, ..nx };
+        assert_eq!(db.run(zadd(b"z", incr_nx, &[(1.0, b"a")])), Object::Null);
+        let incr_gt = ZAddOptions { incr: true, ..gt };
+        assert_eq!(db.run(zadd(b"z", incr_gt, &[(-1.0, b"a")])), Object::Null);
+        assert_eq!(
+            db.run(Command::ZScore(b"z".to_vec(), b"a".to_vec())),
+            Object::Double(3.5)
+        );
+        assert_eq!(
+            db.run(Command::ZScore(b"z".to_vec(), b"f".to_vec())),
+            Object::Null
+        );
+
+        // XX does not create the key
+        let response = db.db.execute(zadd(b"new", xx, &[(1.0, b"a")])).unwrap();
+        assert_eq!(response.object, Object::Integer(0));
+        assert!(!response.is_dirty);
+        assert_eq!(
+            db.run(Command::Exists(bytes(&[b"new"]))),
+            Object::Integer(0)
+        );
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn ranges_go_by_rank_score_or_member() {
+        let mut db = Logged::new();
+        let plain = ZAddOptions::default();
+        db.run(zadd(
+            b"z",
+            plain,
+            &[
+                (1.0, b"a"),
+                (2.0, b"c"),
+                (2.0, b"b"),
+                (3.0, b"d"),
+                (4.0, b"e"),
+            ],
+        ));
+
+        assert_eq!(
+            db.run(zrange(b"z", ZRangeBy::Index(1, -2), false, None)),
+            members(&[b"b", b"c", b"d"])
+        );
+        assert_eq!(
+            db.run(zrange(b"z", ZRangeBy::Index(0, 1), true, None)),
+            members(&[b"e", b"d"])
+        );
+        assert_eq!(
+            db.run(zrange(b"z", ZRangeBy::Index(5, 10), false, None)),
+            members(&[])
+        );
+
+        let score = |score, exclusive| ScoreBound { score, exclusive };
+        assert_eq!(
+            db.run(zrange(
+                b"z",
+                ZRangeBy::Score(score(2.0, false), score(3.0, false)),
+                false,
+                None
+            )),
+            members(&[b"b", b"c", b"d"])
+        );
+        // REV starts from the highest score, before LIMIT skips any
+        assert_eq!(
+            db.run(zrange(
+                b"z",
+                ZRangeBy::Score(score(1.0, true), score(f64::INFINITY, false)),
+                true,
+                Some((1, 2))
+            )),
+            members(&[b"d", b"c"])
+        );
+        assert_eq!(
+            db.run(zrange(
+                b"z",
+                ZRangeBy::Score(score(2.0, true), score(2.0, false)),
+                false,
+                None
+            )),
+            members(&[])
+        );
+
+        // Lex ranges are for members that all have the same score
+        db.run(zadd(
+            b"lex",
+            plain,
+            &[(0.0, b"d"), (0.0, b"b"), (0.0, b"a"), (0.0, b"c")],
+        ));
+        let inclusive = |member: &[u8]| LexBound::Inclusive(member.to_vec());
+        let exclusive = |member: &[u8]| LexBound::Exclusive(member.to_vec());
+        assert_eq!(
+            db.run(zrange(
+                b"lex",
+                ZRangeBy::Lex(inclusive(b"b"), exclusive(b"d")),
+                false,
+                None
+            )),
+            members(&[b"b", b"c"])
+        );
+        assert_eq!(
+            db.run(zrange(
+                b"lex",
+                ZRangeBy::Lex(LexBound::Lowest, LexBound::Highest),
+                true,
+                Some((0, 2))
+            )),
+            members(&[b"d", b"c"])
+        );
+        // A negative count takes every member after the offset
+        assert_eq!(
+            db.run(zrange(
+                b"lex",
+                ZRangeBy::Lex(exclusive(b"a"), LexBound::Highest),
+                false,
+                Some((1, -1))
+            )),
+            members(&[b"c", b"d"])
+        );
+
+        let with_scores = ZRangeOptions {
+            by: ZRangeBy::Index(0, 0),
+            rev: false,
+            limit: None,
+            with_scores: true,
+        };
+        assert_eq!(
+            db.run(Command::ZRange(b"z".to_vec(), with_scores)),
+            Object::Array(vec![bulk(b"a"), Object::Double(1.0)])
+        );
+        assert_eq!(
+            db.run(Command::ZRange(
+                b"missing".to_vec(),
+                ZRangeOptions {
+                    by: ZRangeBy::Index(0, -1),
+                    rev: false,
+                    limit: None,
+                    with_scores: false,
+                }
+            )),
+            members(&[])
+        );
+    }
+
+    #[test]
+    fn ranks_and_pops_follow_the_scores_and_empty_keys_are_removed() {
+        let mut db = Logged::new();
+        let plain = ZAddOptions::default();
+        db.run(zadd(b"z", plain, &[(2.0, b"b"), (1.0, b"a"), (3.0, b"c")]));
+        let rank = |member: &[u8]| Command::ZRank(b"z".to_vec(), member.to_vec());
+        assert_eq!(db.run(rank(b"a")), Object::Integer(0));
+        assert_eq!(db.run(rank(b"c")), Object::Integer(2));
+        assert_eq!(db.run(rank(b"missing")), Object::Null);
+        // A new score moves the member
+        db.run(zadd(b"z", plain, &[(0.0, b"c")]));
+        assert_eq!(db.run(rank(b"c")), Object::Integer(0));
+
+        assert_eq!(
+            db.run(Command::ZPopMin(b"z".to_vec(), None)),
+            Object::Array(vec![bulk(b"c"), Object::Double(0.0)])
+        );
+        assert_eq!(db.run(rank(b"b")), Object::Integer(1));
+        assert_eq!(
+            db.run(Command::ZPopMin(b"z".to_vec(), Some(5))),
+            Object::Array(vec![
+                bulk(b"a"),
+                Object::Double(1.0),
+                bulk(b"b"),
+                Object::Double(2.0),
+            ])
+        );
+        assert_eq!(db.run(Command::Exists(bytes(&[b"z"]))), Object::Integer(0));
+        assert_eq!(
+            db.run(Command::ZPopMin(b"z".to_vec(), None)),
+            Object::Array(Vec::new())
+        );
+
+        db.run(zadd(b"z", plain, &[(1.0, b"a"), (2.0, b"b")]));
+        let response = db
+            .db
+            .execute(Command::ZRem(b"z".to_vec(), bytes(&[b"missing"])))
+            .unwrap();
+        assert_eq!(response.object, Object::Integer(0));
+        assert!(!response.is_dirty);
+        assert_eq!(
+            db.run(Command::ZRem(b"z".to_vec(), bytes(&[b"a", b"b", b"a"]))),
+            Object::Integer(2)
+        );
+        assert_eq!(db.run(Command::Exists(bytes(&[b"z"]))), Object::Integer(0));
+
+        db.assert_replays();
+    }
+
+    #[test]
+    fn zset_commands_check_types() {
+        let mut db = Logged::new();
+        db.run(set(b"s", b"v"));
+        db.run(zadd(b"z", ZAddOptions::default(), &[(1.0, b"a")]));
+        for cmd in [
+            zadd(b"s", ZAddOptions::default(), &[(1.0, b"a")]),
+            Command::ZScore(b"s".to_vec(), b"a".to_vec()),
+            Command::ZRank(b"s".to_vec(), b"a".to_vec()),
+            zrange(b"s", ZRangeBy::Index(0, -1), false, None),
+            Command::ZRem(b"s".to_vec(), bytes(&[b"a"])),
+            Command::ZPopMin(b"s".to_vec(), None),
+            Command::Get(b"z".to_vec()),
+        ] {
+            assert_eq!(db.run(cmd), wrong_type().object);
+        }
+        assert_eq!(
+            db.run(Command::Type(b"z".to_vec())),
+            Object::SimpleString("zset".to_string())
+        );
+
+        db.assert_replays();
+    }
+}
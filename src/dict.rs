@@ -0,0 +1,221 @@
+//! A hash map from byte strings that can be scanned with a cursor.
+//!
+//! The table is an array of buckets whose length is a power of two, so a key
+//! lives in the bucket given by the low bits of its hash. `scan` visits one
+//! bucket per step and moves the cursor by incrementing its *reversed* bits,
+//! which is the order Redis uses: after the table doubles, a bucket's keys are
+//! spread over buckets that the cursor has not reached yet, and after it
+//! halves, the merged bucket was either fully visited or not at all. A key that
+//! is present for a whole scan is therefore returned at least once, no matter
+//! how the table is resized in between.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+const MIN_BUCKETS: usize = 4;
+
+#[derive(Debug, Clone)]
+struct Entry<V> {
+    hash: u64,
+    key: Vec<u8>,
+    value: V,
+}
+
+#[derive(Debug, Clone)]
+pub struct Dict<V> {
+    buckets: Vec<Vec<Entry<V>>>,
+    len: usize,
+    hasher: RandomState,
+}
+
+impl<V> Default for Dict<V> {
+    fn default() -> Self {
+        Self {
+            buckets: Self::empty_buckets(MIN_BUCKETS),
+            len: 0,
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<V: PartialEq> PartialEq for Dict<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl<V> Dict<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn empty_buckets(count: usize) -> Vec<Vec<Entry<V>>> {
+        (0..count).map(|_| Vec::new()).collect()
+    }
+
+    fn hash(&self, key: &[u8]) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write(key);
+        hasher.finish()
+    }
+
+    fn mask(&self) -> u64 {
+        self.buckets.len() as u64 - 1
+    }
+
+    fn bucket(&self, key: &[u8]) -> &Vec<Entry<V>> {
+        &self.buckets[(self.hash(key) & self.mask()) as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        self.bucket(key)
+            .iter()
+            .find(|entry| entry.key == key)
+            .map(|entry| &entry.value)
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut V> {
+        let index = (self.hash(key) & self.mask()) as usize;
+        self.buckets[index]
+            .iter_mut()
+            .find(|entry| entry.key == key)
+            .map(|entry| &mut entry.value)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Inserts a value, returning the one it replaced.
+    pub fn insert(&mut self, key: Vec<u8>, value: V) -> Option<V> {
+        if let Some(old) = self.get_mut(&key) {
+            return Some(std::mem::replace(old, value));
+        }
+        let hash = self.hash(&key);
+        let index = (hash & self.mask()) as usize;
+        self.buckets[index].push(Entry { hash, key, value });
+        self.len += 1;
+        if self.len > self.buckets.len() {
+            self.resize(self.buckets.len() * 2);
+        }
+        None
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        let index = (self.hash(key) & self.mask()) as usize;
+        let bucket = &mut self.buckets[index];
+        let position = bucket.iter().position(|entry| entry.key == key)?;
+        let entry = bucket.swap_remove(position);
+        self.len -= 1;
+        if self.buckets.len() > MIN_BUCKETS && self.len < self.buckets.len() / 8 {
+            self.resize(self.len.next_power_of_two().max(MIN_BUCKETS));
+        }
+        Some(entry.value)
+    }
+
+    pub fn clear(&mut self) {
+        self.buckets = Self::empty_buckets(MIN_BUCKETS);
+        self.len = 0;
+    }
+
+    fn resize(&mut self, count: usize) {
+        let old = std::mem::replace(&mut self.buckets, Self::empty_buckets(count));
+        let mask = self.mask();
+        for entry in old.into_iter().flatten() {
+            self.buckets[(entry.hash & mask) as usize].push(entry);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &V)> {
+        self.buckets
+            .iter()
+            .flatten()
+            .map(|entry| (&entry.key[..], &entry.value))
+    }
+
+    /// Calls `f` for every entry in the bucket at `cursor` and returns the
+    /// cursor of the next bucket, which is 0 once the scan is complete.
+    pub fn scan(&self, cursor: u64, mut f: impl FnMut(&[u8], &V)) -> u64 {
+        if self.is_empty() {
+            return 0;
+        }
+        let mask = self.mask();
+        for entry in &self.buckets[(cursor & mask) as usize] {
+            f(&entry.key, &entry.value);
+        }
+        // Increment the bits covered by the mask, starting from the highest
+        (cursor | !mask)
+            .reverse_bits()
+            .wrapping_add(1)
+            .reverse_bits()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key:{}", i).into_bytes()
+    }
+
+    #[test]
+    fn insert_get_remove() {
+        let mut dict = Dict::new();
+        for i in 0..100 {
+            assert_eq!(dict.insert(key(i), i), None);
+        }
+        assert_eq!(dict.insert(key(7), 70), Some(7));
+        assert_eq!(dict.len(), 100);
+        assert_eq!(dict.get(&key(7)), Some(&70));
+        for i in 0..100 {
+            assert!(dict.remove(&key(i)).is_some());
+        }
+        assert!(dict.is_empty());
+        assert_eq!(dict.buckets.len(), MIN_BUCKETS);
+    }
+
+    #[test]
+    fn scan_survives_resizes() {
+        let mut dict = Dict::new();
+        for i in 0..500 {
+            dict.insert(key(i), ());
+        }
+
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut step = 0;
+        loop {
+            cursor = dict.scan(cursor, |key, _| {
+                seen.insert(key.to_vec());
+            });
+            // Grow the table with new keys, then shrink it by removing them
+            step += 1;
+            if step == 10 {
+                for i in 500..5000 {
+                    dict.insert(key(i), ());
+                }
+            } else if step == 50 {
+                for i in 500..5000 {
+                    dict.remove(&key(i));
+                }
+            }
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert!((0..500).all(|i| seen.contains(&key(i))));
+    }
+}
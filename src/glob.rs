@@ -0,0 +1,122 @@
+//! Glob-style pattern matching, as used by `KEYS`, `SCAN` and `PSUBSCRIBE`.
+//!
+//! `*` matches any sequence, `?` any single byte, `[abc]`, `[a-z]` and `[^a]`
+//! match a class of bytes, and `\` escapes the next byte.
+
+/// Returns whether `string` matches `pattern`.
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume when a mismatch follows the last `*`
+    let mut backtrack = None;
+
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            backtrack = Some((p, s));
+            continue;
+        }
+        if let Some(len) = match_one(&pattern[p..], string[s]) {
+            p += len;
+            s += 1;
+            continue;
+        }
+        match backtrack {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                backtrack = Some((star_p, s));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches the first token of `pattern` against `c`, returning the length of
+/// the token if it matches.
+fn match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match *pattern {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'\\', escaped, ..] => (escaped == c).then_some(2),
+        [b'[', ..] => match_class(pattern, c),
+        [literal, ..] => (literal == c).then_some(1),
+    }
+}
+
+/// Matches a `[...]` class. An unterminated class extends to the end of the
+/// pattern.
+fn match_class(pattern: &[u8], c: u8) -> Option<usize> {
+    let mut i = 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        match pattern[i..] {
+            [b'\\', escaped, ..] => {
+                matched |= escaped == c;
+                i += 2;
+            }
+            [start, b'-', end, ..] if end != b']' => {
+                let (low, high) = (start.min(end), start.max(end));
+                matched |= (low..=high).contains(&c);
+                i += 3;
+            }
+            [literal, ..] => {
+                matched |= literal == c;
+                i += 1;
+            }
+            [] => unreachable!(),
+        }
+    }
+    let len = (i + 1).min(pattern.len());
+    (matched != negate).then_some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches(b"*", b""));
+        assert!(matches(b"*", b"anything"));
+        assert!(matches(b"h?llo", b"hello"));
+        assert!(!matches(b"h?llo", b"hllo"));
+        assert!(matches(b"h*llo", b"heeeello"));
+        assert!(matches(b"user:*:name", b"user:42:name"));
+        assert!(!matches(b"user:*:name", b"user:42:names"));
+        assert!(matches(b"*a*b*", b"xxaxxbxx"));
+        assert!(!matches(b"a*b", b"acbc"));
+    }
+
+    #[test]
+    fn classes_and_escapes() {
+        assert!(matches(b"h[ae]llo", b"hallo"));
+        assert!(!matches(b"h[ae]llo", b"hillo"));
+        assert!(matches(b"h[^e]llo", b"hallo"));
+        assert!(!matches(b"h[^e]llo", b"hello"));
+        assert!(matches(b"h[a-c]llo", b"hbllo"));
+        assert!(matches(b"h[c-a]llo", b"hbllo"));
+        assert!(matches(b"a\\*", b"a*"));
+        assert!(!matches(b"a\\*", b"ab"));
+        assert!(matches(b"[\\]]", b"]"));
+    }
+
+    #[test]
+    fn incomplete_patterns() {
+        assert!(matches(b"", b""));
+        assert!(!matches(b"", b"a"));
+        // An unterminated class takes the rest of the pattern
+        assert!(matches(b"a[bc", b"ab"));
+        assert!(!matches(b"a[bc", b"abc"));
+        // A dash that ends a class, or a backslash that ends the pattern, is
+        // taken literally
+        assert!(matches(b"[a-]", b"-"));
+        assert!(!matches(b"[a-]", b"b"));
+        assert!(matches(b"a\\", b"a\\"));
+        assert!(!matches(b"a\\", b"a"));
+    }
+}
@@ -3,8 +3,13 @@ pub mod cluster;
 pub mod command;
 pub mod connection;
 pub mod db;
+pub mod dict;
+pub mod glob;
 pub mod macros;
 pub mod object;
+pub mod pubsub;
 pub mod server;
+pub mod stream;
 pub mod thread_pool;
 pub mod wal;
+pub mod zset;
@@ -1,7 +1,14 @@
-use std::collections::VecDeque;
+mod de;
+mod ser;
+
+pub use self::de::{from_bytes, from_object, from_object_ref, Deserializer};
+pub use self::ser::{to_bytes, to_object, Serializer};
+
+use std::convert::TryFrom;
 use std::fmt::Display;
 use std::io::prelude::*;
 use std::io::Cursor;
+use std::str::Utf8Error;
 use std::string::FromUtf8Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -10,8 +17,14 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub enum Error {
     Incomplete,
     InvalidInput,
+    TooLarge,
     Io(std::io::Error),
-    Utf8(FromUtf8Error),
+    Utf8(Utf8Error),
+
+    // (De)serialization errors
+    Message(String),
+    UnknownVariant(String),
+    Reply(String),
 }
 
 impl std::error::Error for Error {}
@@ -21,106 +34,392 @@ impl Display for Error {
         match self {
             Error::Incomplete => write!(f, "Incomplete data"),
             Error::InvalidInput => write!(f, "Could not identify a valid Object type"),
+            Error::TooLarge => write!(f, "Object exceeds the maximum message size"),
             Error::Io(err) => write!(f, "{}", err),
             Error::Utf8(err) => write!(f, "{}", err),
+            Error::Message(msg) => write!(f, "{}", msg),
+            Error::UnknownVariant(variant) => write!(f, "Unknown variant `{}`", variant),
+            Error::Reply(msg) => write!(f, "Error reply: {}", msg),
         }
     }
 }
 
+impl serde::ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+}
+
+impl serde::de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+
+    fn unknown_variant(variant: &str, _expected: &'static [&'static str]) -> Self {
+        Error::UnknownVariant(variant.to_string())
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Error::Io(err)
     }
 }
 
+impl From<Utf8Error> for Error {
+    fn from(err: Utf8Error) -> Self {
+        Error::Utf8(err)
+    }
+}
+
 impl From<FromUtf8Error> for Error {
     fn from(err: FromUtf8Error) -> Self {
-        Error::Utf8(err)
+        Error::Utf8(err.utf8_error())
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+impl TryFrom<i64> for Protocol {
+    type Error = Error;
+
+    fn try_from(version: i64) -> Result<Self> {
+        match version {
+            2 => Ok(Protocol::Resp2),
+            3 => Ok(Protocol::Resp3),
+            _ => Err(Error::InvalidInput),
+        }
+    }
+}
+
+impl From<Protocol> for i64 {
+    fn from(protocol: Protocol) -> Self {
+        match protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Object {
     Array(Vec<Object>),
     SimpleString(String),
     Error(String),
     Integer(i64),
-    BulkString(Option<String>),
+    BulkString(Option<Vec<u8>>),
+    /// The null array of RESP2, which RESP3 replaces with `Null`.
+    NullArray,
+
+    // RESP3 types
+    Null,
+    Boolean(bool),
+    Double(f64),
+    BigNumber(String),
+    Map(Vec<(Object, Object)>),
+    Set(Vec<Object>),
+    VerbatimString(String, Vec<u8>),
+    Attribute(Vec<(Object, Object)>, Box<Object>),
+    Push(Vec<Object>),
+}
+
+impl Object {
+    /// Converts RESP3-only types into their closest RESP2 equivalent, the same
+    /// way Redis replies to clients that have not negotiated RESP3 with HELLO.
+    pub fn into_resp2(self) -> Object {
+        match self {
+            Object::Array(inner) | Object::Set(inner) | Object::Push(inner) => {
+                Object::Array(inner.into_iter().map(Object::into_resp2).collect())
+            }
+            Object::Map(pairs) => Object::Array(
+                pairs
+                    .into_iter()
+                    .flat_map(|(k, v)| vec![k.into_resp2(), v.into_resp2()])
+                    .collect(),
+            ),
+            Object::Null => Object::BulkString(None),
+            Object::Boolean(b) => Object::Integer(b as i64),
+            Object::Double(d) => Object::BulkString(Some(format_double(d).into_bytes())),
+            Object::BigNumber(s) => Object::BulkString(Some(s.into_bytes())),
+            Object::VerbatimString(_, value) => Object::BulkString(Some(value)),
+            Object::Attribute(_, inner) => inner.into_resp2(),
+            o => o,
+        }
+    }
 }
 
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else if d.is_infinite() && d > 0.0 {
+        "inf".to_string()
+    } else if d.is_infinite() {
+        "-inf".to_string()
+    } else {
+        d.to_string()
+    }
+}
+
+fn write_header<W: Write>(w: &mut W, kind: u8, len: usize) -> std::io::Result<()> {
+    write!(w, "{}{}\r\n", kind as char, len)
+}
+
+fn write_line<W: Write>(w: &mut W, kind: u8, line: &[u8]) -> std::io::Result<()> {
+    w.write_all(&[kind])?;
+    w.write_all(line)?;
+    w.write_all(b"\r\n")
+}
+
+impl Object {
+    /// Writes the RESP encoding of the object to `w`. Aggregates are written
+    /// depth-first, so nested arrays and maps come out in the right order.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        match self {
+            Object::Array(inner) | Object::Set(inner) | Object::Push(inner) => {
+                let kind = match self {
+                    Object::Array(_) => b'*',
+                    Object::Set(_) => b'~',
+                    _ => b'>',
+                };
+                write_header(w, kind, inner.len())?;
+                for o in inner.iter() {
+                    o.write_to(w)?;
+                }
+                Ok(())
+            }
+            Object::Map(pairs) => {
+                write_header(w, b'%', pairs.len())?;
+                write_pairs(w, pairs)
+            }
+            Object::Attribute(pairs, inner) => {
+                write_header(w, b'|', pairs.len())?;
+                write_pairs(w, pairs)?;
+                inner.write_to(w)
+            }
+            Object::SimpleString(value) => write_line(w, b'+', value.as_bytes()),
+            Object::Error(value) => write_line(w, b'-', value.as_bytes()),
+            Object::Integer(int) => write!(w, ":{}\r\n", int),
+            Object::BulkString(Some(value)) => {
+                write_header(w, b'$', value.len())?;
+                w.write_all(value)?;
+                w.write_all(b"\r\n")
+            }
+            Object::BulkString(None) => w.write_all(b"$-1\r\n"),
+            Object::NullArray => w.write_all(b"*-1\r\n"),
+            Object::Null => w.write_all(b"_\r\n"),
+            Object::Boolean(true) => w.write_all(b"#t\r\n"),
+            Object::Boolean(false) => w.write_all(b"#f\r\n"),
+            Object::Double(d) => write_line(w, b',', format_double(*d).as_bytes()),
+            Object::BigNumber(value) => write_line(w, b'(', value.as_bytes()),
+            Object::VerbatimString(format, value) => {
+                write_header(w, b'=', format.len() + 1 + value.len())?;
+                w.write_all(format.as_bytes())?;
+                w.write_all(b":")?;
+                w.write_all(value)?;
+                w.write_all(b"\r\n")
+            }
+        }
+    }
+}
+
+fn write_pairs<W: Write>(w: &mut W, pairs: &[(Object, Object)]) -> std::io::Result<()> {
+    for (key, value) in pairs.iter() {
+        key.write_to(w)?;
+        value.write_to(w)?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::from_over_into)]
 impl Into<Vec<u8>> for Object {
     fn into(self) -> Vec<u8> {
         let mut ret: Vec<u8> = Vec::new();
+        self.write_to(&mut ret)
+            .expect("Writing to a Vec should never fail");
+        ret
+    }
+}
 
-        let mut queue: VecDeque<&Object> = VecDeque::new();
-        queue.push_back(&self);
+/// A borrowed view of an `Object` that points into the buffer it was parsed
+/// from, so that incoming frames can be inspected without allocating.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ObjectRef<'a> {
+    Array(Vec<ObjectRef<'a>>),
+    SimpleString(&'a str),
+    Error(&'a str),
+    Integer(i64),
+    BulkString(Option<&'a [u8]>),
 
-        while let Some(o) = queue.pop_front() {
-            match o {
-                Object::Array(inner) => {
-                    ret.push(b'*');
-                    ret.extend(inner.len().to_string().as_bytes());
-                    ret.extend(b"\r\n");
+    // RESP3 types
+    Null,
+    Boolean(bool),
+    Double(f64),
+    BigNumber(&'a str),
+    Map(Vec<(ObjectRef<'a>, ObjectRef<'a>)>),
+    Set(Vec<ObjectRef<'a>>),
+    VerbatimString(&'a str, &'a [u8]),
+    Attribute(Vec<(ObjectRef<'a>, ObjectRef<'a>)>, Box<ObjectRef<'a>>),
+    Push(Vec<ObjectRef<'a>>),
+}
 
-                    for inner_o in inner.iter() {
-                        queue.push_back(inner_o);
-                    }
-                }
-                Object::SimpleString(value) => {
-                    ret.push(b'+');
-                    ret.extend(value.as_bytes());
-                    ret.extend(b"\r\n");
-                }
-                Object::Error(value) => {
-                    ret.push(b'-');
-                    ret.extend(value.as_bytes());
-                    ret.extend(b"\r\n");
-                }
-                Object::Integer(int) => {
-                    ret.push(b':');
-                    ret.extend(int.to_string().as_bytes());
-                    ret.extend(b"\r\n");
-                }
-                Object::BulkString(Some(value)) => {
-                    ret.push(b'$');
-                    ret.extend(value.len().to_string().as_bytes());
-                    ret.extend(b"\r\n");
-                    ret.extend(value.as_bytes());
-                    ret.extend(b"\r\n");
-                }
-                Object::BulkString(None) => ret.extend(b"$-1\r\n"),
+impl Object {
+    pub fn to_ref(&self) -> ObjectRef<'_> {
+        fn pairs(pairs: &[(Object, Object)]) -> Vec<(ObjectRef<'_>, ObjectRef<'_>)> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_ref(), v.to_ref()))
+                .collect()
+        }
+        match self {
+            Object::Array(inner) => ObjectRef::Array(inner.iter().map(Object::to_ref).collect()),
+            Object::SimpleString(s) => ObjectRef::SimpleString(s),
+            Object::Error(s) => ObjectRef::Error(s),
+            Object::Integer(int) => ObjectRef::Integer(*int),
+            Object::BulkString(value) => ObjectRef::BulkString(value.as_deref()),
+            Object::NullArray | Object::Null => ObjectRef::Null,
+            Object::Boolean(b) => ObjectRef::Boolean(*b),
+            Object::Double(d) => ObjectRef::Double(*d),
+            Object::BigNumber(s) => ObjectRef::BigNumber(s),
+            Object::Map(inner) => ObjectRef::Map(pairs(inner)),
+            Object::Set(inner) => ObjectRef::Set(inner.iter().map(Object::to_ref).collect()),
+            Object::VerbatimString(format, value) => ObjectRef::VerbatimString(format, value),
+            Object::Attribute(attributes, inner) => {
+                ObjectRef::Attribute(pairs(attributes), Box::new(inner.to_ref()))
             }
+            Object::Push(inner) => ObjectRef::Push(inner.iter().map(Object::to_ref).collect()),
+        }
+    }
+}
+
+impl<'a> From<ObjectRef<'a>> for Object {
+    fn from(obj: ObjectRef<'a>) -> Self {
+        let pairs = |pairs: Vec<(ObjectRef<'a>, ObjectRef<'a>)>| {
+            pairs
+                .into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect()
+        };
+        match obj {
+            ObjectRef::Array(inner) => Object::Array(inner.into_iter().map(Object::from).collect()),
+            ObjectRef::SimpleString(s) => Object::SimpleString(s.to_string()),
+            ObjectRef::Error(s) => Object::Error(s.to_string()),
+            ObjectRef::Integer(int) => Object::Integer(int),
+            ObjectRef::BulkString(value) => Object::BulkString(value.map(<[u8]>::to_vec)),
+            ObjectRef::Null => Object::Null,
+            ObjectRef::Boolean(b) => Object::Boolean(b),
+            ObjectRef::Double(d) => Object::Double(d),
+            ObjectRef::BigNumber(s) => Object::BigNumber(s.to_string()),
+            ObjectRef::Map(inner) => Object::Map(pairs(inner)),
+            ObjectRef::Set(inner) => Object::Set(inner.into_iter().map(Object::from).collect()),
+            ObjectRef::VerbatimString(format, value) => {
+                Object::VerbatimString(format.to_string(), value.to_vec())
+            }
+            ObjectRef::Attribute(attributes, inner) => {
+                Object::Attribute(pairs(attributes), Box::new((*inner).into()))
+            }
+            ObjectRef::Push(inner) => Object::Push(inner.into_iter().map(Object::from).collect()),
         }
-        ret
     }
 }
 
 pub fn parse(input: &mut Cursor<&[u8]>) -> Result<Object> {
-    if input.get_ref().is_empty() {
+    parse_ref(input).map(Object::from)
+}
+
+const READ_SIZE: usize = 4096;
+
+/// Reads from `reader` into `buf` until it holds a complete object, which is
+/// then parsed and drained from the front of `buf`. Bytes read past the end of
+/// the object are left in `buf` for the next call. The buffer grows on demand,
+/// but never beyond `max_size`.
+pub fn read_object<R: Read>(reader: &mut R, buf: &mut Vec<u8>, max_size: usize) -> Result<Object> {
+    loop {
+        let mut cursor = Cursor::new(&buf[..]);
+        match parse(&mut cursor) {
+            Ok(obj) => {
+                let pos = cursor.position() as usize;
+                buf.drain(..pos);
+                return Ok(obj);
+            }
+            Err(Error::Incomplete) if buf.len() >= max_size => return Err(Error::TooLarge),
+            Err(Error::Incomplete) => {}
+            Err(err) => return Err(err),
+        }
+
+        let start = buf.len();
+        buf.resize(start + start.max(READ_SIZE).min(max_size - start), 0);
+        let read = reader.read(&mut buf[start..]);
+        buf.truncate(start + *read.as_ref().unwrap_or(&0));
+        if read? == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+}
+
+/// Parses a client request, which is either a RESP array or an inline command:
+/// a line of space-separated words, the way `telnet` or `nc` would send it.
+/// Inline words are read as bulk strings and blank lines are skipped.
+pub fn parse_request<'a>(input: &mut Cursor<&'a [u8]>) -> Result<ObjectRef<'a>> {
+    loop {
+        if peek(input)? == b'*' {
+            return parse_ref(input);
+        }
+
+        let words = read_inline(input)?;
+        if !words.is_empty() {
+            return Ok(ObjectRef::Array(words));
+        }
+    }
+}
+
+pub fn parse_ref<'a>(input: &mut Cursor<&'a [u8]>) -> Result<ObjectRef<'a>> {
+    if !has_remaining(input) {
         return Err(Error::Incomplete);
     }
 
-    match get_u8(input).unwrap() {
-        b'+' => Ok(Object::SimpleString(read_simple(input)?)),
-        b'-' => Ok(Object::Error(read_simple(input)?)),
-        b':' => Ok(Object::Integer(read_integer(input)?)),
-        b'*' => Ok(Object::Array(read_array(input)?)),
-        b'$' => Ok(Object::BulkString(read_bulk(input)?)),
+    match get_u8(input)? {
+        b'+' => Ok(ObjectRef::SimpleString(read_simple(input)?)),
+        b'-' => Ok(ObjectRef::Error(read_simple(input)?)),
+        b':' => Ok(ObjectRef::Integer(read_integer_line(input)?)),
+        b'*' => read_nullable_array(input),
+        b'$' => Ok(ObjectRef::BulkString(read_bulk(input)?)),
+        b'_' => read_crlf(input).map(|_| ObjectRef::Null),
+        b'#' => Ok(ObjectRef::Boolean(read_boolean(input)?)),
+        b',' => Ok(ObjectRef::Double(read_double(input)?)),
+        b'(' => Ok(ObjectRef::BigNumber(read_big_number(input)?)),
+        b'%' => Ok(ObjectRef::Map(read_map(input)?)),
+        b'~' => Ok(ObjectRef::Set(read_array(input)?)),
+        b'>' => Ok(ObjectRef::Push(read_array(input)?)),
+        b'=' => {
+            let (format, value) = read_verbatim(input)?;
+            Ok(ObjectRef::VerbatimString(format, value))
+        }
+        b'|' => {
+            let attributes = read_map(input)?;
+            let inner = parse_ref(input)?;
+            Ok(ObjectRef::Attribute(attributes, Box::new(inner)))
+        }
         _ => Err(Error::InvalidInput),
     }
 }
 
 fn get_u8(input: &mut Cursor<&[u8]>) -> Result<u8> {
     let mut buf = [0; 1];
-    input.read_exact(&mut buf)?;
-    Ok(buf[0])
+    match input.read_exact(&mut buf) {
+        Ok(()) => Ok(buf[0]),
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => Err(Error::Incomplete),
+        Err(err) => Err(err.into()),
+    }
 }
 
 fn peek(input: &mut Cursor<&[u8]>) -> Result<u8> {
-    let mut buf = [0; 1];
-    input.read_exact(&mut buf)?;
+    let b = get_u8(input)?;
     input.set_position(input.position() - 1);
-    Ok(buf[0])
+    Ok(b)
 }
 
 fn advance(input: &mut Cursor<&[u8]>) {
@@ -131,19 +430,64 @@ fn advance_by(amount: u64, input: &mut Cursor<&[u8]>) {
     input.set_position(input.position() + amount);
 }
 
-fn read_array(input: &mut Cursor<&[u8]>) -> Result<Vec<Object>> {
-    let size = read_integer(input)? as usize;
-    read_crlf(input)?;
+/// Returns the next `size` bytes of the input with the lifetime of the
+/// underlying buffer rather than that of the cursor.
+fn take<'a>(size: usize, input: &mut Cursor<&'a [u8]>) -> &'a [u8] {
+    let buf: &'a [u8] = input.get_ref();
+    let start = input.position() as usize;
+    advance_by(size as u64, input);
+    &buf[start..start + size]
+}
+
+fn read_length(input: &mut Cursor<&[u8]>) -> Result<Option<usize>> {
+    match read_integer_line(input)? {
+        -1 => Ok(None),
+        size if size >= 0 => Ok(Some(size as usize)),
+        _ => Err(Error::InvalidInput),
+    }
+}
+
+// A RESP2 null array is read as `ObjectRef::Null`, which is what RESP3 uses for it.
+fn read_nullable_array<'a>(input: &mut Cursor<&'a [u8]>) -> Result<ObjectRef<'a>> {
+    let orig_pos = input.position();
+    if read_length(input)?.is_none() {
+        return Ok(ObjectRef::Null);
+    }
+    input.set_position(orig_pos);
+    Ok(ObjectRef::Array(read_array(input)?))
+}
 
-    let mut ret = Vec::with_capacity(size);
+fn read_array<'a>(input: &mut Cursor<&'a [u8]>) -> Result<Vec<ObjectRef<'a>>> {
+    let size = read_length(input)?.ok_or(Error::InvalidInput)?;
+
+    let mut ret = Vec::with_capacity(size.min(remaining(input)));
     for _ in 0..size {
-        let cmd = parse(input)?;
+        let cmd = parse_ref(input)?;
         ret.push(cmd);
     }
 
     Ok(ret)
 }
 
+fn read_map<'a>(input: &mut Cursor<&'a [u8]>) -> Result<Vec<(ObjectRef<'a>, ObjectRef<'a>)>> {
+    let size = read_length(input)?.ok_or(Error::InvalidInput)?;
+
+    let mut ret = Vec::with_capacity(size.min(remaining(input)));
+    for _ in 0..size {
+        let key = parse_ref(input)?;
+        let value = parse_ref(input)?;
+        ret.push((key, value));
+    }
+
+    Ok(ret)
+}
+
+fn read_integer_line(input: &mut Cursor<&[u8]>) -> Result<i64> {
+    let int = read_integer(input)?;
+    read_crlf(input)?;
+    Ok(int)
+}
+
 fn read_integer(input: &mut Cursor<&[u8]>) -> Result<i64> {
     let sign = if peek(input)? == b'-' {
         advance(input);
@@ -163,17 +507,18 @@ fn read_integer(input: &mut Cursor<&[u8]>) -> Result<i64> {
     if start == end {
         Err(Error::InvalidInput)
     } else {
-        let int = input.get_ref()[start..end]
+        input.get_ref()[start..end]
             .iter()
-            .map(|b| b & 0xF)
-            .fold(0i64, |acc, b| acc * 10 + (b & 0xF) as i64);
-        Ok(sign * int)
+            .try_fold(0i64, |acc, b| {
+                acc.checked_mul(10)?.checked_add(sign * (b & 0xF) as i64)
+            })
+            .ok_or(Error::InvalidInput)
     }
 }
 
 fn read_crlf(input: &mut Cursor<&[u8]>) -> Result<()> {
     let orig_pos = input.position();
-    if (input.get_ref().len() - input.position() as usize) < 2 {
+    if remaining(input) < 2 {
         return Err(Error::Incomplete);
     }
 
@@ -196,40 +541,77 @@ fn remaining(input: &Cursor<&[u8]>) -> usize {
 }
 
 // TODO: UTF-8
-fn read_simple(input: &mut Cursor<&[u8]>) -> Result<String> {
-    let start = input.position() as usize;
+fn read_simple<'a>(input: &mut Cursor<&'a [u8]>) -> Result<&'a str> {
+    let start = input.position();
     while has_remaining(input) && is_simple_string_char(peek(input)?) {
         advance(input);
     }
-    let end = input.position() as usize;
+    let size = (input.position() - start) as usize;
+    input.set_position(start);
+    let s = take(size, input);
     read_crlf(input)?;
 
-    let s = String::from_utf8(input.get_ref()[start..end].into())?;
-    Ok(s)
+    Ok(std::str::from_utf8(s)?)
+}
+
+fn read_inline<'a>(input: &mut Cursor<&'a [u8]>) -> Result<Vec<ObjectRef<'a>>> {
+    let start = input.position();
+    let len = input.get_ref()[start as usize..]
+        .iter()
+        .position(|&b| b == b'\n')
+        .ok_or(Error::Incomplete)?;
+    let line = take(len, input);
+    advance(input);
+
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    Ok(line
+        .split(u8::is_ascii_whitespace)
+        .filter(|word| !word.is_empty())
+        .map(|word| ObjectRef::BulkString(Some(word)))
+        .collect())
 }
 
-fn read_bulk(input: &mut Cursor<&[u8]>) -> Result<Option<String>> {
-    let size = read_integer(input)?;
+fn read_boolean(input: &mut Cursor<&[u8]>) -> Result<bool> {
+    let b = match get_u8(input)? {
+        b't' => true,
+        b'f' => false,
+        _ => return Err(Error::InvalidInput),
+    };
     read_crlf(input)?;
+    Ok(b)
+}
 
-    if size == -1 {
-        Ok(None)
-    } else if size >= 0 {
-        let size = size as usize;
-        if remaining(input) < size {
-            Err(Error::Incomplete)
-        } else {
-            let start = input.position() as usize;
-            let end = start + size;
+fn read_double(input: &mut Cursor<&[u8]>) -> Result<f64> {
+    read_simple(input)?.parse().map_err(|_| Error::InvalidInput)
+}
 
-            advance_by(size as u64, input);
+fn read_big_number<'a>(input: &mut Cursor<&'a [u8]>) -> Result<&'a str> {
+    let s = read_simple(input)?;
+    let digits = s.strip_prefix('-').unwrap_or(s);
+    if digits.is_empty() || !digits.bytes().all(is_digit) {
+        return Err(Error::InvalidInput);
+    }
+    Ok(s)
+}
+
+fn read_verbatim<'a>(input: &mut Cursor<&'a [u8]>) -> Result<(&'a str, &'a [u8])> {
+    let value = read_bulk(input)?.ok_or(Error::InvalidInput)?;
+    if value.len() < 4 || value[3] != b':' {
+        return Err(Error::InvalidInput);
+    }
+    Ok((std::str::from_utf8(&value[..3])?, &value[4..]))
+}
+
+fn read_bulk<'a>(input: &mut Cursor<&'a [u8]>) -> Result<Option<&'a [u8]>> {
+    match read_length(input)? {
+        None => Ok(None),
+        Some(size) if remaining(input) < size => Err(Error::Incomplete),
+        Some(size) => {
+            let value = take(size, input);
             read_crlf(input)?;
 
-            let s = String::from_utf8(input.get_ref()[start..end].into()).unwrap();
-            Ok(Some(s))
+            Ok(Some(value))
         }
-    } else {
-        Err(Error::InvalidInput)
     }
 }
 
@@ -335,10 +717,18 @@ mod tests {
         let o = parse(&mut cursor).unwrap();
         assert!(matches!(o, Object::BulkString(_)));
         if let Object::BulkString(Some(s)) = o {
-            assert_eq!(s, "Hello world".to_string());
+            assert_eq!(s, b"Hello world".to_vec());
         }
     }
 
+    #[test]
+    fn parse_bulk_binary_ok() {
+        let bytes: &[u8] = b"$4\r\n\x00\xff\r\n\r\n";
+        let mut cursor = Cursor::new(bytes);
+        let o = parse(&mut cursor).unwrap();
+        assert_eq!(o, Object::BulkString(Some(b"\x00\xff\r\n".to_vec())));
+    }
+
     #[test]
     fn parse_bulk_none_ok() {
         let bytes: &[u8] = b"$-1\r\n";
@@ -374,16 +764,17 @@ mod tests {
         let bytes: Vec<u8> = obj.into();
         assert_eq!(String::from_utf8(bytes).unwrap(), "$-1\r\n");
 
-        let obj = Object::BulkString(Some("".to_string()));
+        let obj = Object::BulkString(Some(Vec::new()));
         let bytes: Vec<u8> = obj.into();
         assert_eq!(String::from_utf8(bytes).unwrap(), "$0\r\n\r\n");
 
-        let obj = Object::BulkString(Some("Test".to_string()));
+        let obj = Object::BulkString(Some(b"Test".to_vec()));
         let bytes: Vec<u8> = obj.into();
         assert_eq!(String::from_utf8(bytes).unwrap(), "$4\r\nTest\r\n");
     }
 
     #[test]
+    #[allow(clippy::vec_init_then_push)]
     fn object_into_vec_array() {
         let mut inner = Vec::new();
         inner.push(Object::SimpleString("First".to_string()));
@@ -395,4 +786,344 @@ mod tests {
             "*2\r\n+First\r\n+Second\r\n"
         );
     }
+
+    #[test]
+    fn object_into_vec_null_array() {
+        let obj = Object::NullArray;
+        let bytes: Vec<u8> = obj.into();
+        assert_eq!(String::from_utf8(bytes).unwrap(), "*-1\r\n");
+    }
+
+    #[test]
+    fn parse_null_ok() {
+        let bytes: &[u8] = b"_\r\n";
+        let mut cursor = Cursor::new(bytes);
+        assert_eq!(parse(&mut cursor).unwrap(), Object::Null);
+
+        let bytes: &[u8] = b"*-1\r\n";
+        let mut cursor = Cursor::new(bytes);
+        assert_eq!(parse(&mut cursor).unwrap(), Object::Null);
+    }
+
+    #[test]
+    fn parse_boolean_ok() {
+        let bytes: &[u8] = b"#t\r\n#f\r\n";
+        let mut cursor = Cursor::new(bytes);
+        assert_eq!(parse(&mut cursor).unwrap(), Object::Boolean(true));
+        assert_eq!(parse(&mut cursor).unwrap(), Object::Boolean(false));
+    }
+
+    #[test]
+    fn parse_double_ok() {
+        let bytes: &[u8] = b",1.23\r\n,-inf\r\n";
+        let mut cursor = Cursor::new(bytes);
+        assert_eq!(parse(&mut cursor).unwrap(), Object::Double(1.23));
+        assert_eq!(
+            parse(&mut cursor).unwrap(),
+            Object::Double(f64::NEG_INFINITY)
+        );
+    }
+
+    #[test]
+    fn parse_big_number_ok() {
+        let bytes: &[u8] = b"(3492890328409238509324850943850943825024385\r\n";
+        let mut cursor = Cursor::new(bytes);
+        assert_eq!(
+            parse(&mut cursor).unwrap(),
+            Object::BigNumber("3492890328409238509324850943850943825024385".to_string())
+        );
+
+        let bytes: &[u8] = b"(12a\r\n";
+        let mut cursor = Cursor::new(bytes);
+        assert!(matches!(parse(&mut cursor), Err(Error::InvalidInput)));
+    }
+
+    #[test]
+    fn parse_map_ok() {
+        let bytes: &[u8] = b"%2\r\n+first\r\n:1\r\n+second\r\n:2\r\n";
+        let mut cursor = Cursor::new(bytes);
+        assert_eq!(
+            parse(&mut cursor).unwrap(),
+            Object::Map(vec![
+                (
+                    Object::SimpleString("first".to_string()),
+                    Object::Integer(1)
+                ),
+                (
+                    Object::SimpleString("second".to_string()),
+                    Object::Integer(2)
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_set_and_push_ok() {
+        let bytes: &[u8] = b"~2\r\n+a\r\n+b\r\n>1\r\n+c\r\n";
+        let mut cursor = Cursor::new(bytes);
+        assert_eq!(
+            parse(&mut cursor).unwrap(),
+            Object::Set(vec![
+                Object::SimpleString("a".to_string()),
+                Object::SimpleString("b".to_string()),
+            ])
+        );
+        assert_eq!(
+            parse(&mut cursor).unwrap(),
+            Object::Push(vec![Object::SimpleString("c".to_string())])
+        );
+    }
+
+    #[test]
+    fn parse_verbatim_string_ok() {
+        let bytes: &[u8] = b"=15\r\ntxt:Some string\r\n";
+        let mut cursor = Cursor::new(bytes);
+        assert_eq!(
+            parse(&mut cursor).unwrap(),
+            Object::VerbatimString("txt".to_string(), b"Some string".to_vec())
+        );
+    }
+
+    #[test]
+    fn parse_attribute_ok() {
+        let bytes: &[u8] = b"|1\r\n+ttl\r\n:3600\r\n:42\r\n";
+        let mut cursor = Cursor::new(bytes);
+        assert_eq!(
+            parse(&mut cursor).unwrap(),
+            Object::Attribute(
+                vec![(
+                    Object::SimpleString("ttl".to_string()),
+                    Object::Integer(3600)
+                )],
+                Box::new(Object::Integer(42))
+            )
+        );
+    }
+
+    #[test]
+    fn object_into_vec_resp3() {
+        let bytes: Vec<u8> = Object::Null.into();
+        assert_eq!(bytes, b"_\r\n");
+
+        let bytes: Vec<u8> = Object::Boolean(true).into();
+        assert_eq!(bytes, b"#t\r\n");
+
+        let bytes: Vec<u8> = Object::Double(f64::INFINITY).into();
+        assert_eq!(bytes, b",inf\r\n");
+
+        let bytes: Vec<u8> = Object::Double(-0.5).into();
+        assert_eq!(bytes, b",-0.5\r\n");
+
+        let bytes: Vec<u8> = Object::VerbatimString("txt".to_string(), b"hi".to_vec()).into();
+        assert_eq!(bytes, b"=6\r\ntxt:hi\r\n");
+
+        let obj = Object::Map(vec![(
+            Object::SimpleString("proto".to_string()),
+            Object::Integer(3),
+        )]);
+        let bytes: Vec<u8> = obj.into();
+        assert_eq!(bytes, b"%1\r\n+proto\r\n:3\r\n");
+    }
+
+    #[test]
+    fn object_into_resp2() {
+        let obj = Object::Map(vec![
+            (Object::SimpleString("null".to_string()), Object::Null),
+            (
+                Object::SimpleString("bool".to_string()),
+                Object::Boolean(true),
+            ),
+            (
+                Object::SimpleString("double".to_string()),
+                Object::Double(1.5),
+            ),
+        ]);
+        assert_eq!(
+            obj.into_resp2(),
+            Object::Array(vec![
+                Object::SimpleString("null".to_string()),
+                Object::BulkString(None),
+                Object::SimpleString("bool".to_string()),
+                Object::Integer(1),
+                Object::SimpleString("double".to_string()),
+                Object::BulkString(Some(b"1.5".to_vec())),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_ref_borrows_input() {
+        let bytes: &[u8] = b"*2\r\n+get\r\n$5\r\ndrink\r\n";
+        let mut cursor = Cursor::new(bytes);
+        let o = parse_ref(&mut cursor).unwrap();
+        if let ObjectRef::Array(a) = &o {
+            assert_eq!(a[0], ObjectRef::SimpleString("get"));
+            if let ObjectRef::BulkString(Some(key)) = a[1] {
+                assert_eq!(key, b"drink");
+                assert!(std::ptr::eq(key.as_ptr(), bytes[14..].as_ptr()));
+            } else {
+                panic!("Expected a bulk string");
+            }
+        } else {
+            panic!("Expected an array");
+        }
+        assert_eq!(cursor.position() as usize, bytes.len());
+    }
+
+    #[test]
+    fn object_ref_round_trip() {
+        let obj = Object::Attribute(
+            vec![(Object::SimpleString("key".to_string()), Object::Double(0.5))],
+            Box::new(Object::Map(vec![(
+                Object::BulkString(Some(b"a".to_vec())),
+                Object::Set(vec![Object::Null, Object::Boolean(false)]),
+            )])),
+        );
+        assert_eq!(Object::from(obj.to_ref()), obj);
+    }
+
+    #[test]
+    fn read_object_grows_buffer() {
+        let value = vec![b'x'; 3 * READ_SIZE];
+        let mut bytes: Vec<u8> = Object::BulkString(Some(value.clone())).into();
+        bytes.extend(b":1\r\n");
+
+        let mut reader = &bytes[..];
+        let mut buf = Vec::new();
+        let o = read_object(&mut reader, &mut buf, 4 * READ_SIZE).unwrap();
+        assert_eq!(o, Object::BulkString(Some(value)));
+        let o = read_object(&mut reader, &mut buf, 4 * READ_SIZE).unwrap();
+        assert_eq!(o, Object::Integer(1));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn read_object_too_large() {
+        let bytes: Vec<u8> = Object::BulkString(Some(vec![b'x'; 2 * READ_SIZE])).into();
+        let mut reader = &bytes[..];
+        let mut buf = Vec::new();
+        let o = read_object(&mut reader, &mut buf, READ_SIZE);
+        assert!(matches!(o, Err(Error::TooLarge)));
+    }
+
+    #[test]
+    fn parse_request_inline_ok() {
+        let bytes: &[u8] = b"\r\nset  drink water\r\nget drink\n";
+        let mut cursor = Cursor::new(bytes);
+        assert_eq!(
+            parse_request(&mut cursor).unwrap(),
+            ObjectRef::Array(vec![
+                ObjectRef::BulkString(Some(b"set")),
+                ObjectRef::BulkString(Some(b"drink")),
+                ObjectRef::BulkString(Some(b"water")),
+            ])
+        );
+        assert_eq!(
+            parse_request(&mut cursor).unwrap(),
+            ObjectRef::Array(vec![
+                ObjectRef::BulkString(Some(b"get")),
+                ObjectRef::BulkString(Some(b"drink")),
+            ])
+        );
+        assert!(matches!(parse_request(&mut cursor), Err(Error::Incomplete)));
+    }
+
+    #[test]
+    fn parse_request_inline_incomplete() {
+        let bytes: &[u8] = b"get drink";
+        let mut cursor = Cursor::new(bytes);
+        assert!(matches!(parse_request(&mut cursor), Err(Error::Incomplete)));
+    }
+
+    #[test]
+    fn parse_request_array_ok() {
+        let bytes: &[u8] = b"*1\r\n+get\r\n";
+        let mut cursor = Cursor::new(bytes);
+        assert_eq!(
+            parse_request(&mut cursor).unwrap(),
+            ObjectRef::Array(vec![ObjectRef::SimpleString("get")])
+        );
+    }
+
+    #[test]
+    fn object_into_vec_nested_array() {
+        let obj = Object::Array(vec![
+            Object::Array(vec![Object::Integer(1), Object::Integer(2)]),
+            Object::Integer(3),
+        ]);
+        let bytes: Vec<u8> = obj.into();
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            "*2\r\n*2\r\n:1\r\n:2\r\n:3\r\n"
+        );
+    }
+
+    /// A small xorshift generator, so that the round trip tests are reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> usize {
+            (self.next() % n) as usize
+        }
+
+        fn bytes(&mut self) -> Vec<u8> {
+            (0..self.below(8)).map(|_| self.next() as u8).collect()
+        }
+
+        fn line(&mut self) -> String {
+            (0..self.below(8))
+                .map(|_| (b' ' + self.below(95) as u8) as char)
+                .collect()
+        }
+
+        fn object(&mut self, depth: usize) -> Object {
+            let kinds = if depth == 0 { 10 } else { 15 };
+            match self.below(kinds) {
+                0 => Object::SimpleString(self.line()),
+                1 => Object::Error(self.line()),
+                2 => Object::Integer(self.next() as i64),
+                3 => Object::BulkString(Some(self.bytes())),
+                4 => Object::BulkString(None),
+                5 => Object::Null,
+                6 => Object::Boolean(self.below(2) == 0),
+                7 => Object::Double(self.next() as i64 as f64 / 1000.0),
+                8 => Object::BigNumber(format!("-{}{}", self.next(), self.next())),
+                9 => Object::VerbatimString("txt".to_string(), self.bytes()),
+                10 => Object::Array(self.objects(depth - 1)),
+                11 => Object::Set(self.objects(depth - 1)),
+                12 => Object::Push(self.objects(depth - 1)),
+                13 => Object::Map(self.pairs(depth - 1)),
+                _ => Object::Attribute(self.pairs(depth - 1), Box::new(self.object(depth - 1))),
+            }
+        }
+
+        fn objects(&mut self, depth: usize) -> Vec<Object> {
+            (0..self.below(4)).map(|_| self.object(depth)).collect()
+        }
+
+        fn pairs(&mut self, depth: usize) -> Vec<(Object, Object)> {
+            (0..self.below(4))
+                .map(|_| (self.object(depth), self.object(depth)))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn object_round_trip_nested() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        for _ in 0..1000 {
+            let obj = rng.object(4);
+            let bytes: Vec<u8> = obj.clone().into();
+            let mut cursor = Cursor::new(&bytes[..]);
+            assert_eq!(parse(&mut cursor).unwrap(), obj);
+            assert_eq!(cursor.position() as usize, bytes.len());
+        }
+    }
 }
@@ -0,0 +1,429 @@
+//! Deserializes Rust values from `Object`s, following the mapping described
+//! in the `ser` module.
+//!
+//! Deserialization is lenient in the same ways RESP itself is: integers and
+//! doubles can also be read from strings, booleans from `0` and `1`, structs
+//! from positional arrays, and the RESP2 and RESP3 nulls are interchangeable.
+//! An error reply is returned as `Error::Reply` instead of being deserialized.
+
+use super::{parse_ref, Error, Object, ObjectRef, Result};
+use serde::de::{self, DeserializeSeed, Deserializer as _, Visitor};
+use serde::Deserialize;
+use std::io::Cursor;
+
+/// Deserializes a `T` from a borrowed frame, borrowing strings from it where
+/// `T` allows.
+pub fn from_object_ref<'de, T: Deserialize<'de>>(obj: &ObjectRef<'de>) -> Result<T> {
+    T::deserialize(Deserializer(obj))
+}
+
+/// Deserializes a `T` from an `Object`.
+pub fn from_object<'de, T: Deserialize<'de>>(obj: &'de Object) -> Result<T> {
+    from_object_ref(&obj.to_ref())
+}
+
+/// Deserializes a `T` from the RESP encoding of exactly one object.
+pub fn from_bytes<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T> {
+    let mut cursor = Cursor::new(bytes);
+    let obj = parse_ref(&mut cursor)?;
+    if cursor.position() as usize != bytes.len() {
+        return Err(Error::InvalidInput);
+    }
+    from_object_ref(&obj)
+}
+
+pub struct Deserializer<'a, 'de>(pub &'a ObjectRef<'de>);
+
+impl<'a, 'de> Deserializer<'a, 'de> {
+    /// Returns the text of string-like objects, if they are valid UTF-8.
+    fn text(&self) -> Option<&'de str> {
+        match *self.0 {
+            ObjectRef::SimpleString(s) | ObjectRef::BigNumber(s) => Some(s),
+            ObjectRef::BulkString(Some(b)) | ObjectRef::VerbatimString(_, b) => {
+                std::str::from_utf8(b).ok()
+            }
+            _ => None,
+        }
+    }
+
+    fn deserialize_integer<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        if let ObjectRef::Integer(int) = *self.0 {
+            return visitor.visit_i64(int);
+        }
+        match self.text() {
+            Some(s) => match (s.parse::<i64>(), s.parse::<u64>()) {
+                (Ok(int), _) => visitor.visit_i64(int),
+                (_, Ok(int)) => visitor.visit_u64(int),
+                _ => self.deserialize_any(visitor),
+            },
+            None => self.deserialize_any(visitor),
+        }
+    }
+}
+
+macro_rules! deserialize_integers {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+                self.deserialize_integer(visitor)
+            }
+        )*
+    };
+}
+
+impl<'a, 'de> de::Deserializer<'de> for Deserializer<'a, 'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.0 {
+            ObjectRef::Array(inner) | ObjectRef::Set(inner) | ObjectRef::Push(inner) => {
+                visitor.visit_seq(SeqDeserializer(inner.iter()))
+            }
+            ObjectRef::SimpleString(s) | ObjectRef::BigNumber(s) => visitor.visit_borrowed_str(s),
+            ObjectRef::Error(s) => Err(Error::Reply(s.to_string())),
+            ObjectRef::Integer(int) => visitor.visit_i64(*int),
+            ObjectRef::BulkString(Some(b)) | ObjectRef::VerbatimString(_, b) => {
+                visitor.visit_borrowed_bytes(b)
+            }
+            ObjectRef::BulkString(None) | ObjectRef::Null => visitor.visit_unit(),
+            ObjectRef::Boolean(b) => visitor.visit_bool(*b),
+            ObjectRef::Double(d) => visitor.visit_f64(*d),
+            ObjectRef::Map(pairs) => visitor.visit_map(MapDeserializer {
+                iter: pairs.iter(),
+                value: None,
+            }),
+            ObjectRef::Attribute(_, inner) => Deserializer(inner).deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match *self.0 {
+            ObjectRef::Integer(0) => visitor.visit_bool(false),
+            ObjectRef::Integer(1) => visitor.visit_bool(true),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    deserialize_integers! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_f64(visitor)
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.text().and_then(|s| s.parse().ok()) {
+            Some(d) => visitor.visit_f64(d),
+            None => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.text() {
+            Some(s) => visitor.visit_borrowed_str(s),
+            None => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match *self.0 {
+            ObjectRef::SimpleString(s) => visitor.visit_borrowed_bytes(s.as_bytes()),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.0 {
+            ObjectRef::BulkString(None) | ObjectRef::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_any(visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.0 {
+            ObjectRef::Array(inner) if !inner.is_empty() => visitor.visit_enum(EnumDeserializer {
+                variant: &inner[0],
+                fields: &inner[1..],
+            }),
+            ObjectRef::Error(_) => self.deserialize_any(visitor),
+            obj => visitor.visit_enum(EnumDeserializer {
+                variant: obj,
+                fields: &[],
+            }),
+        }
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+}
+
+struct SeqDeserializer<'a, 'de>(std::slice::Iter<'a, ObjectRef<'de>>);
+
+impl<'a, 'de> de::SeqAccess<'de> for SeqDeserializer<'a, 'de> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        self.0
+            .next()
+            .map(|obj| seed.deserialize(Deserializer(obj)))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct MapDeserializer<'a, 'de> {
+    iter: std::slice::Iter<'a, (ObjectRef<'de>, ObjectRef<'de>)>,
+    value: Option<&'a ObjectRef<'de>>,
+}
+
+impl<'a, 'de> de::MapAccess<'de> for MapDeserializer<'a, 'de> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.iter.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                seed.deserialize(Deserializer(key)).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        let value = self
+            .value
+            .take()
+            .expect("next_value_seed called before next_key_seed");
+        seed.deserialize(Deserializer(value))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct EnumDeserializer<'a, 'de> {
+    variant: &'a ObjectRef<'de>,
+    fields: &'a [ObjectRef<'de>],
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for EnumDeserializer<'a, 'de> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let variant = seed.deserialize(Deserializer(self.variant))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'a, 'de> de::VariantAccess<'de> for EnumDeserializer<'a, 'de> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        if self.fields.is_empty() {
+            Ok(())
+        } else {
+            Err(de::Error::invalid_length(self.fields.len(), &"no fields"))
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        match self.fields {
+            [field] => seed.deserialize(Deserializer(field)),
+            _ => Err(de::Error::invalid_length(self.fields.len(), &"one field")),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value> {
+        if self.fields.len() != len {
+            return Err(de::Error::invalid_length(self.fields.len(), &visitor));
+        }
+        visitor.visit_seq(SeqDeserializer(self.fields.iter()))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.tuple_variant(fields.len(), visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{to_bytes, to_object};
+    use serde::Serialize;
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Profile {
+        name: String,
+        age: u8,
+        score: f64,
+        nickname: Option<String>,
+        tags: Vec<String>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    enum Message {
+        Ping,
+        Get(String),
+        Set(String, i64),
+        Move { from: String, to: String },
+    }
+
+    #[test]
+    fn struct_round_trip() {
+        let profile = Profile {
+            name: "Ada".to_string(),
+            age: 36,
+            score: 99.5,
+            nickname: None,
+            tags: vec!["admin".to_string(), "ops".to_string()],
+        };
+        let bytes = to_bytes(&profile).unwrap();
+        assert_eq!(from_bytes::<Profile>(&bytes).unwrap(), profile);
+    }
+
+    #[test]
+    fn enum_is_encoded_like_a_command() {
+        let obj = to_object(&Message::Set("drink".to_string(), 3)).unwrap();
+        assert_eq!(
+            obj,
+            Object::Array(vec![
+                Object::SimpleString("set".to_string()),
+                Object::BulkString(Some(b"drink".to_vec())),
+                Object::Integer(3),
+            ])
+        );
+
+        for msg in [
+            Message::Ping,
+            Message::Get("drink".to_string()),
+            Message::Set("drink".to_string(), -1),
+            Message::Move {
+                from: "a".to_string(),
+                to: "b".to_string(),
+            },
+        ] {
+            let obj = to_object(&msg).unwrap();
+            assert_eq!(from_object::<Message>(&obj).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn enum_unknown_variant() {
+        let bytes = b"*2\r\n+remove\r\n$5\r\ndrink\r\n";
+        let err = from_bytes::<Message>(bytes).unwrap_err();
+        assert!(matches!(err, Error::UnknownVariant(v) if v == "remove"));
+    }
+
+    #[test]
+    fn map_round_trip() {
+        let mut map = BTreeMap::new();
+        map.insert("a".to_string(), vec![1u64, u64::MAX]);
+        map.insert("b".to_string(), Vec::new());
+        let obj = to_object(&map).unwrap();
+        assert_eq!(
+            from_object::<BTreeMap<String, Vec<u64>>>(&obj).unwrap(),
+            map
+        );
+    }
+
+    #[test]
+    fn lenient_scalars() {
+        assert_eq!(from_bytes::<i64>(b"$2\r\n42\r\n").unwrap(), 42);
+        assert_eq!(from_bytes::<f64>(b"$3\r\n1.5\r\n").unwrap(), 1.5);
+        assert!(from_bytes::<bool>(b":1\r\n").unwrap());
+        assert_eq!(from_bytes::<Option<String>>(b"$-1\r\n").unwrap(), None);
+        assert_eq!(from_bytes::<Option<String>>(b"_\r\n").unwrap(), None);
+        assert_eq!(from_bytes::<&str>(b"+OK\r\n").unwrap(), "OK");
+    }
+
+    #[test]
+    fn error_reply() {
+        let err = from_bytes::<String>(b"-ERR wrong\r\n").unwrap_err();
+        assert!(matches!(err, Error::Reply(msg) if msg == "ERR wrong"));
+    }
+}
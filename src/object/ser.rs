@@ -0,0 +1,306 @@
+//! Serializes Rust values into `Object`s.
+//!
+//! Strings and byte arrays become bulk strings, integers become integers (or
+//! big numbers when they do not fit in an `i64`), sequences become arrays,
+//! maps and structs become maps, and `None` and `()` become null. Enum
+//! variants are written like commands: unit variants are a simple string with
+//! the variant name, and all other variants are an array starting with the
+//! variant name, followed by the fields in order.
+
+use super::{Error, Object, Result};
+use serde::ser::{self, Serialize};
+
+/// Serializes `value` into an `Object`.
+pub fn to_object<T: Serialize + ?Sized>(value: &T) -> Result<Object> {
+    value.serialize(Serializer)
+}
+
+/// Serializes `value` into its RESP encoding.
+pub fn to_bytes<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    to_object(value).map(Into::into)
+}
+
+pub struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Object;
+    type Error = Error;
+
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeVec;
+    type SerializeTupleStruct = SerializeVec;
+    type SerializeTupleVariant = SerializeVec;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeVec;
+
+    fn serialize_bool(self, v: bool) -> Result<Object> {
+        Ok(Object::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Object> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Object> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Object> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Object> {
+        Ok(Object::Integer(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Object> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Object> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Object> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Object> {
+        if v > i64::MAX as u64 {
+            Ok(Object::BigNumber(v.to_string()))
+        } else {
+            self.serialize_i64(v as i64)
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Object> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Object> {
+        Ok(Object::Double(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Object> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Object> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Object> {
+        Ok(Object::BulkString(Some(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Object> {
+        Ok(Object::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Object> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Object> {
+        Ok(Object::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Object> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Object> {
+        Ok(Object::SimpleString(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Object> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Object> {
+        Ok(Object::Array(vec![
+            Object::SimpleString(variant.to_string()),
+            to_object(value)?,
+        ]))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeVec> {
+        Ok(SerializeVec(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SerializeVec> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVec> {
+        let mut vec = Vec::with_capacity(len + 1);
+        vec.push(Object::SimpleString(variant.to_string()));
+        Ok(SerializeVec(vec))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap> {
+        Ok(SerializeMap {
+            pairs: Vec::with_capacity(len.unwrap_or(0)),
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeMap> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVec> {
+        self.serialize_tuple_variant(name, variant_index, variant, len)
+    }
+}
+
+pub struct SerializeVec(Vec<Object>);
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = Object;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.0.push(to_object(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Object> {
+        Ok(Object::Array(self.0))
+    }
+}
+
+impl ser::SerializeTuple for SerializeVec {
+    type Ok = Object;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Object> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeVec {
+    type Ok = Object;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Object> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeVec {
+    type Ok = Object;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Object> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVec {
+    type Ok = Object;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Object> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+pub struct SerializeMap {
+    pairs: Vec<(Object, Object)>,
+    key: Option<Object>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Object;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(to_object(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        self.pairs.push((key, to_object(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Object> {
+        Ok(Object::Map(self.pairs))
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = Object;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.pairs
+            .push((Object::SimpleString(key.to_string()), to_object(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Object> {
+        Ok(Object::Map(self.pairs))
+    }
+}
@@ -0,0 +1,109 @@
+//! Channel and pattern subscriptions. Messages published to them wait here
+//! until the server delivers them to the subscribed connections.
+
+use crate::glob;
+use crate::object::Object;
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct PubSub {
+    /// The ids of the connections subscribed to each channel.
+    channels: HashMap<Vec<u8>, Vec<u64>>,
+    /// The ids of the connections subscribed to each glob pattern.
+    patterns: HashMap<Vec<u8>, Vec<u64>>,
+    /// Published messages and the ids of the connections to deliver them to.
+    outbox: Vec<(u64, Object)>,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&mut self, channel: &[u8], id: u64) {
+        self.channels.entry(channel.to_vec()).or_default().push(id);
+    }
+
+    pub fn unsubscribe(&mut self, channel: &[u8], id: u64) {
+        remove(&mut self.channels, channel, id);
+    }
+
+    pub fn psubscribe(&mut self, pattern: &[u8], id: u64) {
+        self.patterns.entry(pattern.to_vec()).or_default().push(id);
+    }
+
+    pub fn punsubscribe(&mut self, pattern: &[u8], id: u64) {
+        remove(&mut self.patterns, pattern, id);
+    }
+
+    /// Queues a message for the subscribers of the channel and of every
+    /// pattern that matches it, and returns how many messages were queued.
+    pub fn publish(&mut self, channel: &[u8], message: &[u8]) -> usize {
+        let bulk = |bytes: &[u8]| Object::BulkString(Some(bytes.to_vec()));
+        let before = self.outbox.len();
+        for &id in self.channels.get(channel).into_iter().flatten() {
+            let push = vec![bulk(b"message"), bulk(channel), bulk(message)];
+            self.outbox.push((id, Object::Push(push)));
+        }
+        for (pattern, ids) in &self.patterns {
+            if glob::matches(pattern, channel) {
+                for &id in ids {
+                    let push = vec![
+                        bulk(b"pmessage"),
+                        bulk(pattern),
+                        bulk(channel),
+                        bulk(message),
+                    ];
+                    self.outbox.push((id, Object::Push(push)));
+                }
+            }
+        }
+        self.outbox.len() - before
+    }
+
+    /// Takes the messages published since the last call, in order.
+    pub fn take_messages(&mut self) -> Vec<(u64, Object)> {
+        std::mem::take(&mut self.outbox)
+    }
+}
+
+fn remove(subscribers: &mut HashMap<Vec<u8>, Vec<u64>>, name: &[u8], id: u64) {
+    if let Some(ids) = subscribers.get_mut(name) {
+        ids.retain(|&other| other != id);
+        if ids.is_empty() {
+            subscribers.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_reach_channel_and_pattern_subscribers() {
+        let mut pubsub = PubSub::new();
+        pubsub.subscribe(b"news", 1);
+        pubsub.subscribe(b"news", 2);
+        pubsub.psubscribe(b"n*", 2);
+        pubsub.psubscribe(b"weather", 3);
+        assert_eq!(pubsub.publish(b"news", b"hi"), 3);
+        let ids: Vec<u64> = pubsub.take_messages().iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, [1, 2, 2]);
+
+        pubsub.unsubscribe(b"news", 1);
+        pubsub.punsubscribe(b"n*", 2);
+        assert_eq!(pubsub.publish(b"news", b"bye"), 1);
+        let messages = pubsub.take_messages();
+        let bulk = |bytes: &[u8]| Object::BulkString(Some(bytes.to_vec()));
+        assert_eq!(
+            messages,
+            [(
+                2,
+                Object::Push(vec![bulk(b"message"), bulk(b"news"), bulk(b"bye")])
+            )]
+        );
+        assert_eq!(pubsub.publish(b"sports", b"goal"), 0);
+        assert!(pubsub.channels.len() == 1 && pubsub.patterns.len() == 1);
+    }
+}
@@ -1,23 +1,37 @@
 use crate::cluster::Cluster;
+use crate::command::Command;
 use crate::connection::Connection;
 use crate::db::{Database, HashMapDatabase};
+use crate::object::Object;
+use crate::pubsub::PubSub;
 use crate::wal::Wal;
 use log::trace;
 use nix::poll::{poll, PollFd, PollFlags};
 use socket2::Socket;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
 use std::io;
 use std::os::unix::io::AsRawFd;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
-pub const MESSAGE_MAX_SIZE: usize = 512;
+pub const PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// How often the leader looks for expired keys.
+const EXPIRE_INTERVAL_MS: i32 = 100;
+/// How many expired keys are removed at a time.
+const EXPIRE_BATCH_SIZE: usize = 200;
+/// How long a single round of active expiry may take.
+const EXPIRE_TIME_LIMIT: Duration = Duration::from_millis(25);
 
 #[derive(Clone)]
 pub struct ServerOptions {
     pub backlog: i32,
     pub port: u32,
     pub read_only: bool,
+    pub proto_max_bulk_len: usize,
+    /// How much output a subscriber may have waiting before it is disconnected.
+    pub pubsub_max_output_len: usize,
 
     // Socket options
     pub only_v6: bool,
@@ -29,6 +43,9 @@ pub struct ServerOptions {
     pub cluster_password: String,
     pub cluster_nodes: Vec<String>,
     pub cluster_connect_timeout: u64,
+
+    /// The number of databases, numbered from 0, that clients can `SELECT`.
+    pub databases: usize,
 }
 
 impl ServerOptions {
@@ -51,6 +68,7 @@ enum ServerEvent {
     IncomingConnection,
     CloseConnection,
     IncomingCommand,
+    Writable,
 }
 
 impl ServerEvent {
@@ -63,6 +81,8 @@ impl ServerEvent {
                 Some(ServerEvent::CloseConnection)
             } else if revents.intersects(PollFlags::POLLIN) {
                 Some(ServerEvent::IncomingCommand)
+            } else if revents.intersects(PollFlags::POLLOUT) {
+                Some(ServerEvent::Writable)
             } else {
                 None
             }
@@ -72,32 +92,79 @@ impl ServerEvent {
 
 pub struct Server {
     opt: ServerOptions,
-    db: Arc<dyn Database>,
+    dbs: Vec<Arc<dyn Database>>,
     wal: Arc<Wal>,
     cluster: Option<Cluster>,
 
     // Each Connection has a corresponding PollFd on the same index.
     connections: Vec<Connection>,
     pollfds: Vec<PollFd>,
+
+    /// Ids of the blocked connections waiting on each key of each database, in
+    /// arrival order.
+    waiters: HashMap<(usize, Vec<u8>), VecDeque<u64>>,
+    pubsub: PubSub,
 }
 
 impl Server {
-    pub fn new(options: ServerOptions, wal: Arc<Wal>) -> Self {
+    /// Creates a server with the state in the WAL, which fails if the WAL uses
+    /// more databases than there are.
+    pub fn new(options: ServerOptions, wal: Arc<Wal>) -> Result<Self, Box<dyn Error>> {
         let time = Instant::now();
-        let db: Arc<dyn Database> = Arc::new(HashMapDatabase::new());
-        while let Some(cmd) = wal.read() {
+        let dbs: Vec<Arc<dyn Database>> = (0..options.databases.max(1))
+            .map(|_| Arc::new(HashMapDatabase::new()) as Arc<dyn Database>)
+            .collect();
+        set_replaying(&dbs, true);
+        let mut selected = 0;
+        let mut transaction: Option<Vec<Command>> = None;
+        let mut transaction_start = 0;
+        loop {
+            let position = wal.position();
+            let cmd = match wal.read() {
+                Some(cmd) => cmd,
+                None => break,
+            };
             trace!("Replaying cmd = {:?}", cmd);
-            let _response = db.execute(cmd);
+            match (cmd, &mut transaction) {
+                (Command::Multi, _) => {
+                    transaction = Some(Vec::new());
+                    transaction_start = position;
+                }
+                (Command::Exec, Some(cmds)) => {
+                    // Nothing else runs meanwhile, so the commands can be
+                    // applied one by one
+                    for cmd in std::mem::take(cmds) {
+                        replay(&dbs, &mut selected, cmd)?;
+                    }
+                    transaction = None;
+                }
+                (cmd, Some(cmds)) => cmds.push(cmd),
+                (cmd, None) => replay(&dbs, &mut selected, cmd)?,
+            }
+        }
+        // A transaction without its EXEC was cut short by a crash, before it
+        // was acknowledged, so it is dropped, and cut off so that new records
+        // do not end up in it.
+        if transaction.is_some() {
+            wal.truncate(transaction_start)?;
+        }
+        // Keys whose time passed while the server was down are removed, and
+        // logged, once it runs. Nobody waits for the ready keys yet.
+        set_replaying(&dbs, false);
+        for db in &dbs {
+            let _ready = db.take_ready().unwrap();
         }
         trace!("Server init took {} ms", time.elapsed().as_millis());
-        Self {
+        Ok(Self {
             opt: options,
-            db,
+            dbs,
             wal,
             cluster: None,
             pollfds: Vec::new(),
             connections: Vec::new(),
-        }
+            waiters: HashMap::new(),
+            pubsub: PubSub::new(),
+        })
     }
 
     fn listen(&mut self) -> Result<(), Box<dyn Error>> {
@@ -134,6 +201,9 @@ impl Server {
         trace!("Cleaning up!");
         for i in (0..self.connections.len()).rev() {
             if self.connections[i].closed {
+                self.dequeue(i);
+                self.connections[i].unsubscribe_all(&mut self.pubsub);
+                self.connections[i].unwatch(&self.dbs);
                 self.pollfds.remove(i);
                 self.connections.remove(i);
             }
@@ -142,18 +212,227 @@ impl Server {
 
     fn respond_to_command(&mut self, i: usize) -> Result<(), Box<dyn Error>> {
         self.connections[i].handle_incoming_command(
-            self.db.clone(),
+            &self.dbs,
             self.wal.clone(),
             &mut self.cluster,
-        )
+            &mut self.pubsub,
+        )?;
+        self.enqueue(i);
+        Ok(())
+    }
+
+    /// Queues a connection that has just blocked on each of its keys.
+    fn enqueue(&mut self, i: usize) {
+        let connection = &mut self.connections[i];
+        if let Some(blocked) = connection
+            .blocked
+            .as_mut()
+            .filter(|blocked| !blocked.queued)
+        {
+            blocked.queued = true;
+            for key in &blocked.keys {
+                let queue = self
+                    .waiters
+                    .entry((connection.selected, key.clone()))
+                    .or_default();
+                queue.push_back(connection.id);
+            }
+        }
+    }
+
+    /// Removes a connection from the queues of all the keys it blocks on.
+    fn dequeue(&mut self, i: usize) {
+        let connection = &self.connections[i];
+        if let Some(blocked) = connection.blocked.as_ref().filter(|blocked| blocked.queued) {
+            remove_waiter(
+                &mut self.waiters,
+                connection.id,
+                connection.selected,
+                &blocked.keys,
+            );
+        }
+    }
+
+    /// Serves the clients blocked on keys that received values, in the order
+    /// they blocked. Serving a client can make further keys ready, e.g. the
+    /// destination of a `BLMOVE`, so this repeats until no key is ready.
+    fn serve_ready_keys(&mut self) {
+        loop {
+            let mut ready = Vec::new();
+            for (index, db) in self.dbs.iter().enumerate() {
+                ready.extend(db.take_ready().unwrap().into_iter().map(|key| (index, key)));
+            }
+            if ready.is_empty() {
+                return;
+            }
+            for waiting in ready {
+                // Clients that cannot be served keep their place in every queue
+                let mut skipped = 0;
+                while let Some(&id) = self
+                    .waiters
+                    .get(&waiting)
+                    .and_then(|queue| queue.get(skipped))
+                {
+                    let i = self
+                        .connections
+                        .iter()
+                        .position(|connection| connection.id == id)
+                        .expect("Waiting connections are never removed without dequeueing");
+                    if self.connections[i].closed {
+                        self.dequeue(i);
+                        self.connections[i].blocked = None;
+                        continue;
+                    }
+                    let connection = &mut self.connections[i];
+                    let served = connection.serve_blocked(
+                        &waiting.1,
+                        &self.dbs,
+                        &self.wal,
+                        &mut self.cluster,
+                    );
+                    let blocked = match served {
+                        Some(blocked) => blocked,
+                        None if connection.blocked.as_ref().unwrap().is_exclusive() => {
+                            // The key is empty again, so everyone else keeps waiting
+                            break;
+                        }
+                        None => {
+                            skipped += 1;
+                            continue;
+                        }
+                    };
+                    remove_waiter(&mut self.waiters, id, connection.selected, &blocked.keys);
+                    // The client may have more requests that arrived while it was blocked
+                    connection.process_requests(
+                        &self.dbs,
+                        &self.wal,
+                        &mut self.cluster,
+                        &mut self.pubsub,
+                    );
+                    self.enqueue(i);
+                }
+            }
+        }
+    }
+
+    /// Replies with null to the blocked clients whose timeout has elapsed.
+    fn time_out_blocked(&mut self) {
+        let now = Instant::now();
+        for i in 0..self.connections.len() {
+            let timed_out = self.connections[i]
+                .blocked
+                .as_ref()
+                .and_then(|blocked| blocked.deadline)
+                .is_some_and(|deadline| deadline <= now);
+            if timed_out {
+                self.dequeue(i);
+                let connection = &mut self.connections[i];
+                connection.time_out();
+                connection.process_requests(
+                    &self.dbs,
+                    &self.wal,
+                    &mut self.cluster,
+                    &mut self.pubsub,
+                );
+                self.enqueue(i);
+            }
+        }
+    }
+
+    /// Delivers the messages published since the last call to their
+    /// subscribers.
+    fn deliver_messages(&mut self) {
+        let messages = self.pubsub.take_messages();
+        if messages.is_empty() {
+            return;
+        }
+        let indexes: HashMap<u64, usize> = self
+            .connections
+            .iter()
+            .enumerate()
+            .map(|(i, connection)| (connection.id, i))
+            .collect();
+        let mut receivers = HashSet::new();
+        for (id, message) in messages {
+            let i = indexes[&id];
+            let connection = &mut self.connections[i];
+            if !connection.closed {
+                connection.deliver(message);
+                receivers.insert(i);
+            }
+        }
+        for i in receivers {
+            self.connections[i].flush();
+        }
+    }
+
+    /// Polls for input on every connection, and for the sockets of the ones
+    /// with output left over to become writable.
+    fn update_pollfds(&mut self) {
+        for (pollfd, connection) in self.pollfds.iter_mut().zip(&self.connections) {
+            let events = match connection.has_output() {
+                true => PollFlags::POLLIN | PollFlags::POLLOUT,
+                false => PollFlags::POLLIN,
+            };
+            *pollfd = PollFd::new(connection.as_raw_fd(), events);
+        }
+    }
+
+    /// How long to poll for, so that active expiry runs on time and blocked
+    /// clients time out on time.
+    fn poll_timeout(&self) -> i32 {
+        let mut timeout = if self.opt.read_only {
+            -1
+        } else {
+            EXPIRE_INTERVAL_MS
+        };
+        let now = Instant::now();
+        let deadlines = self
+            .connections
+            .iter()
+            .filter_map(|connection| connection.blocked.as_ref()?.deadline);
+        for deadline in deadlines {
+            // Round up, so that the deadline has passed when poll returns
+            let wait = deadline
+                .saturating_duration_since(now)
+                .as_micros()
+                .div_ceil(1000);
+            let wait = wait.min(i32::MAX as u128) as i32;
+            if timeout < 0 || wait < timeout {
+                timeout = wait;
+            }
+        }
+        timeout
+    }
+
+    /// Removes expired keys until there are none left or the time limit is
+    /// reached. Followers never do this, they wait for the leader's deletes.
+    fn expire_keys(&mut self) {
+        for (index, db) in self.dbs.iter().enumerate() {
+            let start = Instant::now();
+            while db.expire_keys(EXPIRE_BATCH_SIZE).unwrap() == EXPIRE_BATCH_SIZE
+                && start.elapsed() < EXPIRE_TIME_LIMIT
+            {}
+            propagate_expired(index, &**db, &self.wal, &mut self.cluster);
+        }
     }
 
     pub fn run(&mut self) -> Result<(), Box<dyn Error>> {
         self.listen()?;
         self.start_cluster()?;
 
+        let mut last_expire = Instant::now();
         loop {
-            let mut poll_count = poll(&mut self.pollfds, -1)?;
+            if !self.opt.read_only
+                && last_expire.elapsed() >= Duration::from_millis(EXPIRE_INTERVAL_MS as u64)
+            {
+                self.expire_keys();
+                last_expire = Instant::now();
+            }
+
+            self.update_pollfds();
+            let timeout = self.poll_timeout();
+            let mut poll_count = poll(&mut self.pollfds, timeout)?;
             for i in 0..self.pollfds.len() {
                 if poll_count == 0 {
                     break;
@@ -165,10 +444,704 @@ impl Server {
                         ServerEvent::IncomingConnection => self.accept_connection(i)?,
                         ServerEvent::CloseConnection => self.close_connection(i),
                         ServerEvent::IncomingCommand => self.respond_to_command(i)?,
+                        ServerEvent::Writable => self.connections[i].flush(),
                     }
                 }
             }
+            self.serve_ready_keys();
+            self.time_out_blocked();
+            self.deliver_messages();
             self.cleanup_closed();
         }
     }
 }
+
+/// Applies a command from the WAL to the selected database, or changes which
+/// one that is.
+fn replay(dbs: &[Arc<dyn Database>], selected: &mut usize, cmd: Command) -> Result<(), String> {
+    let check = |index: usize| match index < dbs.len() {
+        true => Ok(index),
+        false => Err(format!(
+            "The WAL uses database {}, but there are only {} databases",
+            index,
+            dbs.len()
+        )),
+    };
+    match cmd {
+        Command::Select(index) => *selected = check(index)?,
+        Command::SwapDb(first, second) => {
+            let (first, second) = (check(first)?, check(second)?);
+            if first != second {
+                dbs[first]
+                    .swap(&*dbs[second])
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+        Command::FlushAll => {
+            for db in dbs {
+                let _response = db.execute(Command::FlushAll);
+            }
+        }
+        cmd => {
+            let _response = dbs[*selected].execute(cmd);
+        }
+    }
+    Ok(())
+}
+
+/// Appends an encoded command to the WAL and relays it to the cluster, after a
+/// `SELECT` if the last one propagated was for another database.
+pub fn propagate(index: usize, record: &[u8], wal: &Wal, cluster: &mut Option<Cluster>) {
+    select(index, wal, cluster);
+    wal.append(record).unwrap();
+    if let Some(cluster) = cluster {
+        cluster.relay(record, 1);
+    }
+}
+
+/// Propagates the records of a transaction, each with the index of its
+/// database. More than one is wrapped in `MULTI` and `EXEC` and written in one
+/// go, so that neither a replay after a crash nor a follower can apply only
+/// some of them.
+pub fn propagate_transaction(
+    records: Vec<(usize, Object)>,
+    wal: &Wal,
+    cluster: &mut Option<Cluster>,
+) {
+    if records.len() < 2 {
+        for (index, record) in records {
+            let record: Vec<u8> = record.into();
+            propagate(index, &record, wal, cluster);
+        }
+        return;
+    }
+    let mut replies = 2;
+    let mut buf: Vec<u8> = Command::Multi.to_object().into();
+    for (index, record) in records {
+        if wal.select(index) {
+            let select = Command::Select(index).to_object();
+            select.write_to(&mut buf).unwrap();
+            replies += 1;
+        }
+        record.write_to(&mut buf).unwrap();
+        replies += 1;
+    }
+    Command::Exec.to_object().write_to(&mut buf).unwrap();
+    wal.append(&buf).unwrap();
+    if let Some(cluster) = cluster {
+        cluster.relay(&buf, replies);
+    }
+}
+
+/// Propagates the keys that expired since the last call as a single delete.
+pub fn propagate_expired(
+    index: usize,
+    db: &dyn Database,
+    wal: &Wal,
+    cluster: &mut Option<Cluster>,
+) {
+    let keys = db.take_expired().unwrap();
+    if !keys.is_empty() {
+        let record: Vec<u8> = Command::Del(keys).to_object().into();
+        propagate(index, &record, wal, cluster);
+    }
+}
+
+/// Removes a connection from the queues of its keys in a database.
+fn remove_waiter(
+    waiters: &mut HashMap<(usize, Vec<u8>), VecDeque<u64>>,
+    id: u64,
+    index: usize,
+    keys: &[Vec<u8>],
+) {
+    for key in keys {
+        let waiting = (index, key.clone());
+        if let Some(queue) = waiters.get_mut(&waiting) {
+            queue.retain(|&waiter| waiter != id);
+            if queue.is_empty() {
+                waiters.remove(&waiting);
+            }
+        }
+    }
+}
+
+/// Sets whether the databases apply logged commands, see
+/// `Database::set_replaying`.
+pub fn set_replaying(dbs: &[Arc<dyn Database>], replaying: bool) {
+    for db in dbs {
+        db.set_replaying(replaying).unwrap();
+    }
+}
+
+/// Propagates a `SELECT` if the records that follow are for another database
+/// than the last ones.
+fn select(index: usize, wal: &Wal, cluster: &mut Option<Cluster>) {
+    if wal.select(index) {
+        let record: Vec<u8> = Command::Select(index).to_object().into();
+        wal.append(&record).unwrap();
+        if let Some(cluster) = cluster {
+            cluster.relay(&record, 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    fn encode(obj: Object) -> Vec<u8> {
+        obj.into()
+    }
+
+    /// A server without a listener, with an empty log of its own.
+    fn server(name: &str) -> Server {
+        Server::new(options(2), wal(name)).unwrap()
+    }
+
+    /// An empty log of a test's own.
+    fn wal(name: &str) -> Arc<Wal> {
+        let path = log_path(name);
+        let _ = std::fs::remove_file(&path);
+        open(&path)
+    }
+
+    fn log_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("passage-{}-{}.wal", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn open(path: &str) -> Arc<Wal> {
+        Arc::new(Wal::new(path, false, 1 << 20).unwrap())
+    }
+
+    fn options(databases: usize) -> ServerOptions {
+        ServerOptions {
+            backlog: 1,
+            port: 0,
+            read_only: false,
+            proto_max_bulk_len: 1 << 20,
+            pubsub_max_output_len: 1 << 20,
+            only_v6: false,
+            reuse_address: false,
+            reuse_port: false,
+            nodelay: false,
+            cluster_password: String::new(),
+            cluster_nodes: Vec::new(),
+            cluster_connect_timeout: 0,
+            databases,
+        }
+    }
+
+    /// Connects a client, which is the connection with the returned index.
+    fn connect(server: &mut Server) -> (usize, UnixStream) {
+        let (client, socket) = UnixStream::pair().unwrap();
+        client.set_nonblocking(true).unwrap();
+        let connection = Connection::new(socket.into(), false, &server.opt);
+        let fd = connection.as_raw_fd();
+        server.connections.push(connection);
+        server.pollfds.push(PollFd::new(fd, PollFlags::POLLIN));
+        (server.connections.len() - 1, client)
+    }
+
+    /// Sends a request and handles it like the event loop does, and returns
+    /// the replies sent to the client so far.
+    fn request(server: &mut Server, client: &mut (usize, UnixStream), args: &[&str]) -> Vec<u8> {
+        let args = args
+            .iter()
+            .map(|arg| Object::BulkString(Some(arg.as_bytes().to_vec())))
+            .collect();
+        client.1.write_all(&encode(Object::Array(args))).unwrap();
+        server.respond_to_command(client.0).unwrap();
+        server.serve_ready_keys();
+        replies(client)
+    }
+
+    fn replies(client: &mut (usize, UnixStream)) -> Vec<u8> {
+        let mut buf = vec![0; 1024];
+        match client.1.read(&mut buf) {
+            Ok(len) => buf[..len].to_vec(),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Vec::new(),
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    fn popped(key: &str, value: &str) -> Vec<u8> {
+        Object::Array(vec![
+            Object::BulkString(Some(key.as_bytes().to_vec())),
+            Object::BulkString(Some(value.as_bytes().to_vec())),
+        ])
+        .into()
+    }
+
+    #[test]
+    fn blocked_clients_keep_their_place_on_every_key() {
+        let mut server = server("fairness");
+        let mut first = connect(&mut server);
+        let mut second = connect(&mut server);
+        let mut writer = connect(&mut server);
+        assert!(request(&mut server, &mut first, &["BLPOP", "x", "y", "0"]).is_empty());
+        assert!(request(&mut server, &mut second, &["BLPOP", "y", "x", "0"]).is_empty());
+
+        // x receives a value that is gone before anyone can be served
+        request(&mut server, &mut writer, &["MULTI"]);
+        request(&mut server, &mut writer, &["RPUSH", "x", "a"]);
+        request(&mut server, &mut writer, &["LPOP", "x"]);
+        request(&mut server, &mut writer, &["EXEC"]);
+        assert!(replies(&mut first).is_empty());
+
+        // The first client still blocked first on y as well
+        request(&mut server, &mut writer, &["RPUSH", "y", "b"]);
+        assert_eq!(replies(&mut first), popped("y", "b"));
+        assert!(replies(&mut second).is_empty());
+        request(&mut server, &mut writer, &["RPUSH", "x", "c"]);
+        assert_eq!(replies(&mut second), popped("x", "c"));
+    }
+
+    #[test]
+    fn blocked_moves_stay_exclusive() {
+        let mut server = server("exclusive");
+        let mut first = connect(&mut server);
+        let mut second = connect(&mut server);
+        let mut writer = connect(&mut server);
+        let blmove = ["BLMOVE", "src", "dst", "LEFT", "RIGHT", "0"];
+        assert!(request(&mut server, &mut first, &blmove).is_empty());
+        assert!(request(&mut server, &mut second, &blmove).is_empty());
+        for client in [&first, &second] {
+            let blocked = server.connections[client.0].blocked.as_ref().unwrap();
+            assert!(blocked.is_exclusive());
+        }
+
+        request(&mut server, &mut writer, &["RPUSH", "src", "a"]);
+        assert_eq!(
+            replies(&mut first),
+            encode(Object::BulkString(Some(b"a".to_vec())))
+        );
+        assert!(replies(&mut second).is_empty());
+        assert_eq!(server.waiters.len(), 1);
+    }
+
+    #[test]
+    fn blocked_moves_time_out_and_fail_on_other_types() {
+        let mut server = server("timeout");
+        let mut timed = connect(&mut server);
+        let mut mover = connect(&mut server);
+        let mut popper = connect(&mut server);
+        let mut writer = connect(&mut server);
+        request(&mut server, &mut writer, &["HSET", "h", "f", "v"]);
+        let blmove =
+            |destination, timeout| ["BLMOVE", "src", destination, "LEFT", "RIGHT", timeout];
+        assert!(request(&mut server, &mut timed, &blmove("dst", "0.01")).is_empty());
+        assert!(request(&mut server, &mut mover, &blmove("h", "0")).is_empty());
+        assert!(request(&mut server, &mut popper, &["BLPOP", "src", "0"]).is_empty());
+
+        std::thread::sleep(Duration::from_millis(20));
+        server.time_out_blocked();
+        assert_eq!(replies(&mut timed), encode(Object::NullArray));
+        let ids: Vec<_> = [&mover, &popper]
+            .iter()
+            .map(|client| server.connections[client.0].id)
+            .collect();
+        let queue = &server.waiters[&(0, b"src".to_vec())];
+        assert_eq!(queue.iter().copied().collect::<Vec<_>>(), ids);
+
+        // The move fails on its destination and leaves the value to the next
+        // client, without logging anything
+        request(&mut server, &mut writer, &["RPUSH", "src", "a"]);
+        let error = "WRONGTYPE Operation against a key holding the wrong kind of value";
+        assert_eq!(
+            replies(&mut mover),
+            encode(Object::Error(error.to_string()))
+        );
+        assert_eq!(replies(&mut popper), popped("src", "a"));
+        assert!(server.waiters.is_empty());
+        let reply = request(&mut server, &mut timed, &["LLEN", "dst"]);
+        assert_eq!(reply, encode(Object::Integer(0)));
+
+        let server = Server::new(options(2), open(&log_path("timeout"))).unwrap();
+        let llen = server.dbs[0]
+            .execute(Command::LLen(b"src".to_vec()))
+            .unwrap();
+        assert_eq!(llen.object, Object::Integer(0));
+    }
+
+    #[test]
+    fn transactions_abort_on_queueing_errors_and_changed_watched_keys() {
+        let mut server = server("transactions");
+        let mut client = connect(&mut server);
+        let mut other = connect(&mut server);
+        let ok = || encode(Object::SimpleString("OK".to_string()));
+        let queued = || encode(Object::SimpleString("QUEUED".to_string()));
+        let error = |message: &str| encode(Object::Error(message.to_string()));
+        let bulk = |value: &str| Object::BulkString(Some(value.as_bytes().to_vec()));
+
+        let reply = request(&mut server, &mut client, &["EXEC"]);
+        assert_eq!(reply, error("ERR EXEC without MULTI"));
+        let reply = request(&mut server, &mut client, &["DISCARD"]);
+        assert_eq!(reply, error("ERR DISCARD without MULTI"));
+
+        // Misplaced MULTI and WATCH are refused, but only queueing errors
+        // abort the transaction
+        request(&mut server, &mut client, &["MULTI"]);
+        let reply = request(&mut server, &mut client, &["MULTI"]);
+        assert_eq!(reply, error("ERR MULTI calls can not be nested"));
+        let reply = request(&mut server, &mut client, &["WATCH", "k"]);
+        assert_eq!(reply, error("ERR WATCH inside MULTI is not allowed"));
+        assert_eq!(
+            request(&mut server, &mut client, &["SET", "k", "1"]),
+            queued()
+        );
+        let reply = request(&mut server, &mut client, &["SUBSCRIBE", "news"]);
+        assert_eq!(reply, error("ERR Command not allowed inside a transaction"));
+        let reply = request(&mut server, &mut client, &["EXEC"]);
+        assert_eq!(
+            reply,
+            error("EXECABORT Transaction discarded because of previous errors.")
+        );
+        let reply = request(&mut server, &mut client, &["EXISTS", "k"]);
+        assert_eq!(reply, encode(Object::Integer(0)));
+
+        request(&mut server, &mut client, &["WATCH", "k"]);
+        request(&mut server, &mut other, &["SET", "k", "2"]);
+        request(&mut server, &mut client, &["MULTI"]);
+        request(&mut server, &mut client, &["SET", "k", "3"]);
+        let reply = request(&mut server, &mut client, &["EXEC"]);
+        assert_eq!(reply, encode(Object::NullArray));
+        // EXEC unwatches the keys, whether it ran or not
+        request(&mut server, &mut client, &["MULTI"]);
+        request(&mut server, &mut client, &["SET", "k", "3"]);
+        assert_eq!(request(&mut server, &mut other, &["SET", "k", "4"]), ok());
+        let reply = request(&mut server, &mut client, &["EXEC"]);
+        assert_eq!(
+            reply,
+            encode(Object::Array(vec![Object::SimpleString("OK".to_string())]))
+        );
+
+        // Commands that fail when run do not stop the others, and nothing blocks
+        request(&mut server, &mut client, &["MULTI"]);
+        request(&mut server, &mut client, &["HSET", "k", "f", "v"]);
+        request(&mut server, &mut client, &["INCR", "n"]);
+        request(&mut server, &mut client, &["BLPOP", "list", "0"]);
+        let replies = Object::Array(vec![
+            Object::Error(
+                "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
+            ),
+            Object::Integer(1),
+            Object::Null,
+        ]);
+        let reply = request(&mut server, &mut client, &["EXEC"]);
+        assert_eq!(reply, encode(replies.into_resp2()));
+        assert!(server.connections[client.0].blocked.is_none());
+        let reply = request(&mut server, &mut client, &["GET", "k"]);
+        assert_eq!(reply, encode(bulk("3")));
+    }
+
+    #[test]
+    fn transactions_cut_short_by_a_crash_are_dropped() {
+        let wal = wal("crash");
+        let set = |key: &[u8]| Command::Set(key.to_vec(), b"v".to_vec(), Default::default());
+        for cmd in [set(b"before"), Command::Multi, set(b"during")] {
+            wal.append(&encode(cmd.to_object())).unwrap();
+        }
+        let path = log_path("crash");
+        let mut server = Server::new(options(1), open(&path)).unwrap();
+        let mut client = connect(&mut server);
+        request(&mut server, &mut client, &["SET", "after", "v"]);
+
+        let server = Server::new(options(1), open(&path)).unwrap();
+        let exists = Command::Exists(vec![b"before".to_vec(), b"after".to_vec()]);
+        assert_eq!(
+            server.dbs[0].execute(exists).unwrap().object,
+            Object::Integer(2)
+        );
+        let exists = Command::Exists(vec![b"during".to_vec()]);
+        assert_eq!(
+            server.dbs[0].execute(exists).unwrap().object,
+            Object::Integer(0)
+        );
+    }
+
+    #[test]
+    fn records_cut_short_by_a_crash_are_dropped() {
+        let wal = wal("torn");
+        let set = |key: &[u8]| Command::Set(key.to_vec(), b"v".to_vec(), Default::default());
+        wal.append(&encode(set(b"before").to_object())).unwrap();
+        let torn = encode(set(b"torn").to_object());
+        wal.append(&torn[..torn.len() - 3]).unwrap();
+        let path = log_path("torn");
+        let mut server = Server::new(options(1), open(&path)).unwrap();
+        let mut client = connect(&mut server);
+        request(&mut server, &mut client, &["SET", "after", "v"]);
+
+        let server = Server::new(options(1), open(&path)).unwrap();
+        let exists = Command::Exists(vec![
+            b"before".to_vec(),
+            b"torn".to_vec(),
+            b"after".to_vec(),
+        ]);
+        assert_eq!(
+            server.dbs[0].execute(exists).unwrap().object,
+            Object::Integer(2)
+        );
+    }
+
+    #[test]
+    fn replay_needs_the_databases_the_log_uses() {
+        let wal = wal("databases");
+        for cmd in [
+            Command::Select(1),
+            Command::Set(b"k".to_vec(), b"v".to_vec(), Default::default()),
+            Command::SwapDb(1, 3),
+        ] {
+            wal.append(&encode(cmd.to_object())).unwrap();
+        }
+        let path = log_path("databases");
+        let err = Server::new(options(2), open(&path)).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "The WAL uses database 3, but there are only 2 databases"
+        );
+        let err = Server::new(options(1), open(&path)).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "The WAL uses database 1, but there are only 1 databases"
+        );
+
+        let server = Server::new(options(4), open(&path)).unwrap();
+        let get = server.dbs[3].execute(Command::Get(b"k".to_vec())).unwrap();
+        assert_eq!(get.object, Object::BulkString(Some(b"v".to_vec())));
+    }
+
+    #[test]
+    fn transactions_can_select_databases() {
+        let mut server = server("select");
+        let mut client = connect(&mut server);
+        let ok = || Object::SimpleString("OK".to_string());
+        request(&mut server, &mut client, &["MULTI"]);
+        for args in [
+            &["SET", "k", "0"][..],
+            &["SELECT", "1"],
+            &["SET", "k", "1"],
+            &["SELECT", "2"],
+            &["INCR", "k"],
+        ] {
+            let reply = request(&mut server, &mut client, args);
+            assert_eq!(reply, encode(Object::SimpleString("QUEUED".to_string())));
+        }
+        let replies = Object::Array(vec![
+            ok(),
+            ok(),
+            ok(),
+            Object::Error("ERR DB index is out of range".to_string()),
+            Object::Integer(2),
+        ]);
+        assert_eq!(
+            request(&mut server, &mut client, &["EXEC"]),
+            encode(replies)
+        );
+        // The selection outlasts the transaction
+        let reply = request(&mut server, &mut client, &["GET", "k"]);
+        assert_eq!(reply, encode(Object::BulkString(Some(b"2".to_vec()))));
+
+        let server = Server::new(options(2), open(&log_path("select"))).unwrap();
+        for (db, value) in server.dbs.iter().zip(["0", "2"]) {
+            let get = db.execute(Command::Get(b"k".to_vec())).unwrap();
+            assert_eq!(get.object, Object::BulkString(Some(value.into())));
+        }
+    }
+
+    #[test]
+    fn transactions_swap_and_flush_databases_between_their_commands() {
+        let mut server = server("across");
+        let mut client = connect(&mut server);
+        let ok = || Object::SimpleString("OK".to_string());
+        let mut transaction = |server: &mut Server, cmds: &[&[&str]]| {
+            request(server, &mut client, &["MULTI"]);
+            for args in cmds {
+                request(server, &mut client, args);
+            }
+            request(server, &mut client, &["EXEC"])
+        };
+        let reply = transaction(
+            &mut server,
+            &[
+                &["SET", "a", "0"],
+                &["SWAPDB", "0", "1"],
+                &["SET", "b", "1"],
+                &["SWAPDB", "0", "2"],
+            ],
+        );
+        let error = Object::Error("ERR DB index is out of range".to_string());
+        assert_eq!(reply, encode(Object::Array(vec![ok(), ok(), ok(), error])));
+        let keys = |server: &Server| -> Vec<Object> {
+            let keys = || Command::Keys(b"*".to_vec());
+            server
+                .dbs
+                .iter()
+                .map(|db| db.execute(keys()).unwrap().object)
+                .collect()
+        };
+        let key = |key: &str| Object::Array(vec![Object::BulkString(Some(key.into()))]);
+        assert_eq!(keys(&server), [key("b"), key("a")]);
+        let restarted = Server::new(options(2), open(&log_path("across"))).unwrap();
+        assert_eq!(keys(&restarted), [key("b"), key("a")]);
+
+        let reply = transaction(
+            &mut server,
+            &[&["SET", "c", "2"], &["FLUSHALL"], &["SET", "d", "3"]],
+        );
+        assert_eq!(reply, encode(Object::Array(vec![ok(), ok(), ok()])));
+        let restarted = Server::new(options(2), open(&log_path("across"))).unwrap();
+        assert_eq!(keys(&restarted), [key("d"), Object::Array(Vec::new())]);
+    }
+
+    #[test]
+    fn swapping_databases_wakes_blocked_clients() {
+        let mut server = server("swap");
+        let mut blocked = connect(&mut server);
+        let mut writer = connect(&mut server);
+        assert!(request(&mut server, &mut blocked, &["BLPOP", "list", "0"]).is_empty());
+        request(&mut server, &mut writer, &["SELECT", "1"]);
+        request(&mut server, &mut writer, &["RPUSH", "list", "a", "b"]);
+        assert!(replies(&mut blocked).is_empty());
+
+        // The client stays on its database, which now holds the list
+        request(&mut server, &mut writer, &["SWAPDB", "0", "1"]);
+        assert_eq!(replies(&mut blocked), popped("list", "a"));
+        assert!(server.waiters.is_empty());
+        let reply = request(&mut server, &mut writer, &["LLEN", "list"]);
+        assert_eq!(reply, encode(Object::Integer(0)));
+
+        let server = Server::new(options(2), open(&log_path("swap"))).unwrap();
+        let llen = server.dbs[0]
+            .execute(Command::LLen(b"list".to_vec()))
+            .unwrap();
+        assert_eq!(llen.object, Object::Integer(1));
+    }
+
+    #[test]
+    fn flushing_a_database_fails_the_transactions_watching_it() {
+        let mut server = server("flush");
+        let mut client = connect(&mut server);
+        let mut other = connect(&mut server);
+        let transaction = |server: &mut Server, client: &mut (usize, UnixStream)| {
+            request(server, client, &["WATCH", "k"]);
+            request(server, client, &["MULTI"]);
+            request(server, client, &["SET", "k", "v"]);
+        };
+        request(&mut server, &mut other, &["SET", "k", "v"]);
+
+        transaction(&mut server, &mut client);
+        request(&mut server, &mut other, &["SELECT", "1"]);
+        request(&mut server, &mut other, &["FLUSHDB"]);
+        let reply = request(&mut server, &mut client, &["EXEC"]);
+        let ok = Object::SimpleString("OK".to_string());
+        assert_eq!(reply, encode(Object::Array(vec![ok])));
+
+        transaction(&mut server, &mut client);
+        request(&mut server, &mut other, &["SELECT", "0"]);
+        request(&mut server, &mut other, &["FLUSHDB"]);
+        let reply = request(&mut server, &mut client, &["EXEC"]);
+        assert_eq!(reply, encode(Object::NullArray));
+
+        transaction(&mut server, &mut client);
+        request(&mut server, &mut other, &["FLUSHALL"]);
+        let reply = request(&mut server, &mut client, &["EXEC"]);
+        assert_eq!(reply, encode(Object::NullArray));
+        let reply = request(&mut server, &mut client, &["DBSIZE"]);
+        assert_eq!(reply, encode(Object::Integer(0)));
+    }
+
+    #[test]
+    fn subscribers_get_each_message_once_and_only_manage_subscriptions() {
+        let mut server = server("subscriptions");
+        let mut subscriber = connect(&mut server);
+        let mut publisher = connect(&mut server);
+        let bulk = |bytes: &str| Object::BulkString(Some(bytes.as_bytes().to_vec()));
+        let pushed = |items: &[&str], count: Option<i64>| {
+            let mut items: Vec<_> = items.iter().map(|item| bulk(item)).collect();
+            items.extend(count.map(Object::Integer));
+            encode(Object::Array(items))
+        };
+
+        let reply = request(&mut server, &mut subscriber, &["SUBSCRIBE", "news", "news"]);
+        let confirmation = pushed(&["subscribe", "news"], Some(1));
+        assert_eq!(reply, [&confirmation[..], &confirmation[..]].concat());
+        let reply = request(&mut server, &mut subscriber, &["PSUBSCRIBE", "n*"]);
+        assert_eq!(reply, pushed(&["psubscribe", "n*"], Some(2)));
+        let reply = request(&mut server, &mut subscriber, &["GET", "k"]);
+        let error = "ERR Can't execute 'get': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING are allowed in this context";
+        assert_eq!(reply, encode(Object::Error(error.to_string())));
+        let reply = request(&mut server, &mut subscriber, &["PING"]);
+        assert_eq!(reply, pushed(&["pong", ""], None));
+
+        let reply = request(&mut server, &mut publisher, &["PUBLISH", "news", "hi"]);
+        assert_eq!(reply, encode(Object::Integer(2)));
+        server.deliver_messages();
+        let messages = [
+            pushed(&["message", "news", "hi"], None),
+            pushed(&["pmessage", "n*", "news", "hi"], None),
+        ];
+        assert_eq!(replies(&mut subscriber), messages.concat());
+
+        // Unsubscribing from everything leaves the patterns
+        let reply = request(&mut server, &mut subscriber, &["UNSUBSCRIBE"]);
+        assert_eq!(reply, pushed(&["unsubscribe", "news"], Some(1)));
+        let reply = request(&mut server, &mut subscriber, &["PUNSUBSCRIBE", "x*", "n*"]);
+        let confirmations = [
+            pushed(&["punsubscribe", "x*"], Some(1)),
+            pushed(&["punsubscribe", "n*"], Some(0)),
+        ];
+        assert_eq!(reply, confirmations.concat());
+        let reply = request(&mut server, &mut subscriber, &["GET", "k"]);
+        assert_eq!(reply, encode(Object::Null.into_resp2()));
+        let reply = request(&mut server, &mut publisher, &["PUBLISH", "news", "hi"]);
+        assert_eq!(reply, encode(Object::Integer(0)));
+    }
+
+    #[test]
+    fn slow_subscribers_are_disconnected() {
+        let options = ServerOptions {
+            pubsub_max_output_len: 1024,
+            ..options(1)
+        };
+        let mut server = Server::new(options, wal("slow")).unwrap();
+        let mut subscriber = connect(&mut server);
+        let mut publisher = connect(&mut server);
+        request(&mut server, &mut subscriber, &["SUBSCRIBE", "news"]);
+
+        // The subscriber reads nothing, until the socket takes no more
+        let message = "x".repeat(32 * 1024);
+        for _ in 0..100 {
+            request(&mut server, &mut publisher, &["PUBLISH", "news", &message]);
+            server.deliver_messages();
+            if server.connections[subscriber.0].closed {
+                break;
+            }
+        }
+        assert!(server.connections[subscriber.0].closed);
+        server.cleanup_closed();
+        publisher.0 -= 1;
+        let reply = request(&mut server, &mut publisher, &["PUBLISH", "news", "hi"]);
+        assert_eq!(reply, encode(Object::Integer(0)));
+    }
+
+    #[test]
+    fn subscribers_that_cannot_be_written_to_are_closed() {
+        let mut server = server("subscriber");
+        let mut subscriber = connect(&mut server);
+        let mut publisher = connect(&mut server);
+        request(&mut server, &mut subscriber, &["SUBSCRIBE", "news"]);
+        drop(subscriber.1);
+
+        request(&mut server, &mut publisher, &["PUBLISH", "news", "hello"]);
+        server.deliver_messages();
+        assert!(server.connections[subscriber.0].closed);
+        server.cleanup_closed();
+        publisher.0 -= 1;
+        let reply = request(&mut server, &mut publisher, &["PUBLISH", "news", "hello"]);
+        assert_eq!(reply, encode(Object::Integer(0)));
+    }
+}
@@ -0,0 +1,153 @@
+//! A stream: an append-only log of field-value entries under increasing IDs,
+//! and the consumer groups that read it.
+//!
+//! A group remembers the last entry it delivered and, until they are
+//! acknowledged, which consumer each delivered entry went to. Entries are
+//! never removed, so every pending entry can be delivered again.
+
+use crate::command::KeyValue;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Bound;
+
+/// The ID of a stream entry: a unix time in milliseconds and a sequence
+/// number for entries added in the same millisecond, written as `ms-seq`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> Self {
+        Self { ms, seq }
+    }
+
+    /// Parses `ms-seq`, or `ms` alone with `default_seq` as the sequence.
+    pub fn parse(bytes: &[u8], default_seq: u64) -> Option<Self> {
+        let number = |bytes: &[u8]| -> Option<u64> {
+            // Unlike `str::parse`, do not accept a sign
+            if bytes.first().is_none_or(|b| !b.is_ascii_digit()) {
+                return None;
+            }
+            std::str::from_utf8(bytes).ok()?.parse().ok()
+        };
+        match bytes.iter().position(|&b| b == b'-') {
+            Some(i) => Some(Self::new(number(&bytes[..i])?, number(&bytes[i + 1..])?)),
+            None => Some(Self::new(number(bytes)?, default_seq)),
+        }
+    }
+
+    /// The smallest ID greater than this one.
+    pub fn next(self) -> Option<Self> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(Self::new(self.ms, seq)),
+            None => Some(Self::new(self.ms.checked_add(1)?, 0)),
+        }
+    }
+
+    /// The greatest ID smaller than this one.
+    pub fn prev(self) -> Option<Self> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(Self::new(self.ms, seq)),
+            None => Some(Self::new(self.ms.checked_sub(1)?, u64::MAX)),
+        }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Vec<KeyValue>>,
+    /// The ID of the last entry ever added, which new IDs must be greater than.
+    pub last_id: StreamId,
+    pub groups: BTreeMap<Vec<u8>, Group>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Group {
+    /// The ID of the last entry delivered to a consumer of the group; `>`
+    /// reads the entries after it.
+    pub last_delivered: StreamId,
+    /// The delivered entries that were not acknowledged yet.
+    pub pending: BTreeMap<StreamId, Pending>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pending {
+    pub consumer: Vec<u8>,
+    /// When the entry was last delivered, as a unix time in milliseconds.
+    pub delivered_at: i64,
+    pub deliveries: u64,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The ID `XADD *` gives the next entry: the current time, or the time of
+    /// the last entry with the next sequence number if the clock went back.
+    pub fn next_id(&self, now: i64) -> Option<StreamId> {
+        match u64::try_from(now).unwrap_or(0) {
+            ms if ms > self.last_id.ms => Some(StreamId::new(ms, 0)),
+            _ => self.last_id.next(),
+        }
+    }
+
+    /// Iterates over the entries after `id`.
+    pub fn after(&self, id: StreamId) -> impl Iterator<Item = (&StreamId, &Vec<KeyValue>)> {
+        self.entries.range((Bound::Excluded(id), Bound::Unbounded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_parse_and_order() {
+        assert_eq!(StreamId::parse(b"5-3", 0), Some(StreamId::new(5, 3)));
+        assert_eq!(StreamId::parse(b"5", 0), Some(StreamId::new(5, 0)));
+        assert_eq!(
+            StreamId::parse(b"5", u64::MAX),
+            Some(StreamId::new(5, u64::MAX))
+        );
+        for invalid in [&b""[..], b"-", b"5-", b"-3", b"+5", b"5-+3", b"5-3-1", b"x"] {
+            assert_eq!(StreamId::parse(invalid, 0), None);
+        }
+        assert!(StreamId::new(1, u64::MAX) < StreamId::new(2, 0));
+        assert_eq!(StreamId::new(1, u64::MAX).next(), Some(StreamId::new(2, 0)));
+        assert_eq!(StreamId::new(2, 0).prev(), Some(StreamId::new(1, u64::MAX)));
+        assert_eq!(StreamId::MAX.next(), None);
+        assert_eq!(StreamId::MIN.prev(), None);
+        assert_eq!(StreamId::new(7, 1).to_string(), "7-1");
+    }
+
+    #[test]
+    fn next_id_never_goes_back() {
+        let mut stream = Stream::new();
+        assert_eq!(stream.next_id(10), Some(StreamId::new(10, 0)));
+        stream.last_id = StreamId::new(20, 4);
+        assert_eq!(stream.next_id(10), Some(StreamId::new(20, 5)));
+        assert_eq!(stream.next_id(20), Some(StreamId::new(20, 5)));
+        stream.last_id = StreamId::MAX;
+        assert_eq!(stream.next_id(10), None);
+    }
+}
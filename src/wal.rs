@@ -1,21 +1,23 @@
 use crate::command::Command;
-use crate::object::parse;
-use crate::server::MESSAGE_MAX_SIZE;
+use crate::object::{self, read_object};
 use nix::unistd::fsync;
 use std::convert::TryFrom;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::io::{Cursor, Result};
+use std::io::{Result, SeekFrom};
 use std::os::unix::io::AsRawFd;
 use std::sync::Mutex;
 
 pub struct Wal {
     fsync: bool,
+    proto_max_bulk_len: usize,
     file: Mutex<File>,
+    /// The database of the last record appended, if known.
+    selected: Mutex<Option<usize>>,
 }
 
 impl Wal {
-    pub fn new(path: &str, fsync: bool) -> Result<Self> {
+    pub fn new(path: &str, fsync: bool, proto_max_bulk_len: usize) -> Result<Self> {
         let file = Mutex::new(
             OpenOptions::new()
                 .create(true)
@@ -23,44 +25,60 @@ impl Wal {
                 .append(true)
                 .open(path)?,
         );
-        Ok(Self { fsync, file })
+        Ok(Self {
+            fsync,
+            proto_max_bulk_len,
+            file,
+            selected: Mutex::new(None),
+        })
     }
 
     pub fn read(&self) -> Option<Command> {
-        let mut buf: [u8; MESSAGE_MAX_SIZE] = [0; MESSAGE_MAX_SIZE];
+        let mut buf = Vec::new();
 
         let mut file = self.file.lock().unwrap();
-        let len = file.read(&mut buf).unwrap() as i64;
-
-        let mut cursor = Cursor::new(&buf[..]);
-        let ret = parse(&mut cursor)
-            .ok()
-            .and_then(|o| Command::try_from(o).ok());
+        let (ret, torn) = match read_object(&mut *file, &mut buf, self.proto_max_bulk_len) {
+            Ok(o) => (Command::try_from(o).ok(), false),
+            Err(object::Error::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                (None, !buf.is_empty())
+            }
+            Err(err) => panic!("Corrupt WAL: {}", err),
+        };
 
-        let pos = cursor.position() as i64;
-        file.seek(std::io::SeekFrom::Current(pos - len)).unwrap();
+        let position = file.seek(SeekFrom::Current(-(buf.len() as i64))).unwrap();
+        // A record cut short by a crash is dropped, or the next one would be appended to it
+        if torn {
+            file.set_len(position).unwrap();
+        }
 
         ret
     }
 
-    pub fn append(&self, cmd: &Command) -> Result<()> {
-        if let Some(buf) = match cmd {
-            Command::Set(key, value) => Some(format!(
-                "*3\r\n+set\r\n+{}\r\n${}\r\n{}\r\n",
-                key,
-                value.len(),
-                value
-            )),
-            Command::Remove(key) => Some(format!("*2\r\n+remove\r\n+{}\r\n", key)),
-            _ => None,
-        } {
-            let mut f = self.file.lock().unwrap();
-            f.write_all(buf.as_bytes())?;
-            if self.fsync {
-                f.flush()?;
-                fsync(f.as_raw_fd())?;
-            }
-        };
+    /// How far `read` has got, in bytes.
+    pub fn position(&self) -> u64 {
+        self.file.lock().unwrap().stream_position().unwrap()
+    }
+
+    /// Cuts off the log after `len` bytes.
+    pub fn truncate(&self, len: u64) -> Result<()> {
+        self.file.lock().unwrap().set_len(len)
+    }
+
+    /// Remembers the database of the records appended next, and returns
+    /// whether they need a `SELECT` because it changed. Which database the
+    /// log was left at is not known after a restart, so the first one does.
+    pub fn select(&self, index: usize) -> bool {
+        self.selected.lock().unwrap().replace(index) != Some(index)
+    }
+
+    /// Appends an encoded command to the log.
+    pub fn append(&self, record: &[u8]) -> Result<()> {
+        let mut f = self.file.lock().unwrap();
+        f.write_all(record)?;
+        if self.fsync {
+            f.flush()?;
+            fsync(f.as_raw_fd())?;
+        }
         Ok(())
     }
 }
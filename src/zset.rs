@@ -0,0 +1,441 @@
+//! A sorted set: members ordered by score, then lexicographically.
+//!
+//! A hash index maps each member to its score, and a skiplist keeps the order.
+//! As in Redis, every link of the skiplist records how many nodes it skips
+//! (its span), so finding a node by score, member or rank takes O(log n) and
+//! walking a range of m members from there O(log n + m). Nodes live in a `Vec`
+//! and refer to each other by index, with the head at index 0.
+
+use crate::dict::Dict;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+const MAX_LEVEL: usize = 32;
+const HEAD: usize = 0;
+
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    next: Option<usize>,
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    score: f64,
+    member: Vec<u8>,
+    links: Vec<Link>,
+    prev: Option<usize>,
+}
+
+impl Node {
+    fn is_before(&self, score: f64, member: &[u8]) -> bool {
+        self.score < score || (self.score == score && self.member.as_slice() < member)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SkipList {
+    nodes: Vec<Node>,
+    /// Indexes of removed nodes, reused by later inserts.
+    free: Vec<usize>,
+    level: usize,
+    len: usize,
+    /// State of the xorshift generator for node levels.
+    seed: u64,
+}
+
+impl SkipList {
+    fn new() -> Self {
+        let head = Node {
+            score: 0.0,
+            member: Vec::new(),
+            links: vec![
+                Link {
+                    next: None,
+                    span: 0
+                };
+                MAX_LEVEL
+            ],
+            prev: None,
+        };
+        let seed = RandomState::new().build_hasher().finish() | 1;
+        Self {
+            nodes: vec![head],
+            free: Vec::new(),
+            level: 1,
+            len: 0,
+            seed,
+        }
+    }
+
+    /// Picks a level with probability 1/4 of going up a level, like Redis.
+    fn random_level(&mut self) -> usize {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        let level = 1 + (self.seed.trailing_zeros() / 2) as usize;
+        level.min(MAX_LEVEL)
+    }
+
+    fn next(&self, node: usize) -> Option<usize> {
+        self.nodes[node].links[0].next
+    }
+
+    fn first(&self) -> Option<usize> {
+        self.next(HEAD)
+    }
+
+    /// Follows the links for as long as `advance` holds for the next node, and
+    /// returns the last node reached (possibly the head) with its 1-based rank.
+    fn seek(&self, advance: impl Fn(&Node) -> bool) -> (usize, usize) {
+        let (mut x, mut rank) = (HEAD, 0);
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].links[i].next {
+                if !advance(&self.nodes[next]) {
+                    break;
+                }
+                rank += self.nodes[x].links[i].span;
+                x = next;
+            }
+        }
+        (x, rank)
+    }
+
+    /// Finds the node at a 0-based rank.
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        let target = rank + 1;
+        let (mut x, mut traversed) = (HEAD, 0);
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].links[i].next {
+                if traversed + self.nodes[x].links[i].span > target {
+                    break;
+                }
+                traversed += self.nodes[x].links[i].span;
+                x = next;
+            }
+            if traversed == target {
+                return Some(x);
+            }
+        }
+        None
+    }
+
+    /// Inserts a member, which must not be in the list already.
+    fn insert(&mut self, score: f64, member: Vec<u8>) {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[x].links[i].next {
+                if !self.nodes[next].is_before(score, &member) {
+                    break;
+                }
+                rank[i] += self.nodes[x].links[i].span;
+                x = next;
+            }
+            update[i] = x;
+        }
+
+        let level = self.random_level();
+        if level > self.level {
+            for i in self.level..level {
+                self.nodes[HEAD].links[i].span = self.len;
+            }
+            self.level = level;
+        }
+
+        let node = Node {
+            score,
+            member,
+            links: vec![
+                Link {
+                    next: None,
+                    span: 0
+                };
+                level
+            ],
+            prev: (update[0] != HEAD).then_some(update[0]),
+        };
+        let node = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+
+        for i in 0..level {
+            let link = self.nodes[update[i]].links[i];
+            self.nodes[node].links[i] = Link {
+                next: link.next,
+                span: link.span - (rank[0] - rank[i]),
+            };
+            self.nodes[update[i]].links[i] = Link {
+                next: Some(node),
+                span: rank[0] - rank[i] + 1,
+            };
+        }
+        for (i, &x) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[x].links[i].span += 1;
+        }
+        if let Some(next) = self.next(node) {
+            self.nodes[next].prev = Some(node);
+        }
+        self.len += 1;
+    }
+
+    /// Removes a member with the given score, returning whether it was found.
+    fn remove(&mut self, score: f64, member: &[u8]) -> bool {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut x = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[x].links[i].next {
+                if !self.nodes[next].is_before(score, member) {
+                    break;
+                }
+                x = next;
+            }
+            update[i] = x;
+        }
+        let node = match self.next(x) {
+            Some(node) if self.nodes[node].score == score && self.nodes[node].member == member => {
+                node
+            }
+            _ => return false,
+        };
+
+        for (i, &x) in update.iter().enumerate().take(self.level) {
+            let link = self.nodes[x].links[i];
+            if link.next == Some(node) {
+                let removed = self.nodes[node].links[i];
+                self.nodes[x].links[i] = Link {
+                    next: removed.next,
+                    span: link.span + removed.span - 1,
+                };
+            } else {
+                self.nodes[x].links[i].span -= 1;
+            }
+        }
+        let prev = self.nodes[node].prev;
+        if let Some(next) = self.next(node) {
+            self.nodes[next].prev = prev;
+        }
+        while self.level > 1 && self.nodes[HEAD].links[self.level - 1].next.is_none() {
+            self.level -= 1;
+        }
+        self.nodes[node].member = Vec::new();
+        self.nodes[node].links = Vec::new();
+        self.free.push(node);
+        self.len -= 1;
+        true
+    }
+}
+
+/// Iterates over a sorted set in either direction from a node.
+pub struct Iter<'a> {
+    list: &'a SkipList,
+    node: Option<usize>,
+    rev: bool,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a [u8], f64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = &self.list.nodes[self.node?];
+        self.node = match self.rev {
+            false => node.links[0].next,
+            true => node.prev,
+        };
+        Some((&node.member, node.score))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ZSet {
+    scores: Dict<f64>,
+    list: SkipList,
+}
+
+impl Default for ZSet {
+    fn default() -> Self {
+        Self {
+            scores: Dict::new(),
+            list: SkipList::new(),
+        }
+    }
+}
+
+impl PartialEq for ZSet {
+    fn eq(&self, other: &Self) -> bool {
+        self.scores == other.scores
+    }
+}
+
+impl ZSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Sets the score of a member, returning its previous score.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> Option<f64> {
+        let old = self.scores.insert(member.clone(), score);
+        match old {
+            Some(old) if old == score => {}
+            Some(old) => {
+                self.list.remove(old, &member);
+                self.list.insert(score, member);
+            }
+            None => self.list.insert(score, member),
+        }
+        old
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        self.list.remove(score, member);
+        Some(score)
+    }
+
+    /// The 0-based position of a member in the order.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        let (_, rank) = self.list.seek(|node| {
+            node.is_before(score, member) || (node.score == score && node.member == member)
+        });
+        Some(rank - 1)
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            list: &self.list,
+            node: self.list.first(),
+            rev: false,
+        }
+    }
+
+    /// Iterates from the member at a 0-based rank, towards the lowest ranks if
+    /// `rev` is set.
+    pub fn iter_from_rank(&self, rank: usize, rev: bool) -> Iter<'_> {
+        Iter {
+            list: &self.list,
+            node: self.list.node_at(rank),
+            rev,
+        }
+    }
+
+    /// Splits the order where `in_prefix` stops holding, which it must do only
+    /// once, and iterates forward from the first member after the prefix, or
+    /// backward from the last member in it if `rev` is set.
+    pub fn iter_from(&self, in_prefix: impl Fn(f64, &[u8]) -> bool, rev: bool) -> Iter<'_> {
+        let (node, _) = self.list.seek(|node| in_prefix(node.score, &node.member));
+        let node = match rev {
+            false => self.list.next(node),
+            true => (node != HEAD).then_some(node),
+        };
+        Iter {
+            list: &self.list,
+            node,
+            rev,
+        }
+    }
+
+    /// Removes and returns the member with the lowest score.
+    pub fn pop_min(&mut self) -> Option<(Vec<u8>, f64)> {
+        let node = &self.list.nodes[self.list.first()?];
+        let (member, score) = (node.member.clone(), node.score);
+        self.remove(&member);
+        Some((member, score))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(i: usize) -> Vec<u8> {
+        format!("member:{:03}", i).into_bytes()
+    }
+
+    /// Checks the order, the ranks and the backward links against a sort.
+    fn check(zset: &ZSet) {
+        let mut expected: Vec<_> = zset
+            .scores
+            .iter()
+            .map(|(member, &score)| (member.to_vec(), score))
+            .collect();
+        expected.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then(a.0.cmp(&b.0)));
+        let forward: Vec<_> = zset.iter().map(|(m, s)| (m.to_vec(), s)).collect();
+        assert_eq!(forward, expected);
+        for (rank, (member, _)) in expected.iter().enumerate() {
+            assert_eq!(zset.rank(member), Some(rank));
+            assert_eq!(
+                zset.iter_from_rank(rank, false).next().unwrap().0,
+                &member[..]
+            );
+        }
+        let mut backward: Vec<_> = zset
+            .iter_from(|_, _| true, true)
+            .map(|(m, s)| (m.to_vec(), s))
+            .collect();
+        backward.reverse();
+        assert_eq!(backward, expected);
+    }
+
+    #[test]
+    fn order_and_ranks_follow_updates() {
+        let mut zset = ZSet::new();
+        for i in 0..200 {
+            assert_eq!(zset.insert(member(i), ((i * 37) % 101) as f64), None);
+        }
+        check(&zset);
+        for i in (0..200).step_by(3) {
+            assert!(zset.insert(member(i), -(i as f64)).is_some());
+        }
+        for i in (0..200).step_by(7) {
+            zset.remove(&member(i));
+        }
+        check(&zset);
+        while zset.len() > 10 {
+            let first = zset.iter().next().map(|(m, s)| (m.to_vec(), s));
+            assert_eq!(zset.pop_min(), first);
+        }
+        check(&zset);
+        assert_eq!(zset.rank(b"missing"), None);
+        assert!(zset.iter_from_rank(10, false).next().is_none());
+    }
+
+    #[test]
+    fn ranges_start_where_the_prefix_ends() {
+        let mut zset = ZSet::new();
+        for i in 0..10 {
+            zset.insert(member(i), i as f64);
+        }
+        let scores = |iter: Iter| iter.map(|(_, score)| score).collect::<Vec<_>>();
+        assert_eq!(
+            scores(zset.iter_from(|score, _| score < 7.0, false)),
+            [7.0, 8.0, 9.0]
+        );
+        assert_eq!(
+            scores(zset.iter_from(|score, _| score <= 2.0, true)),
+            [2.0, 1.0, 0.0]
+        );
+        assert!(zset.iter_from(|_, _| false, true).next().is_none());
+    }
+}